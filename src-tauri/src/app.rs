@@ -1,35 +1,84 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
-use p2panda_core::{Hash, PrivateKey, PublicKey};
+use p2panda_core::{Body, Hash, Header, PrivateKey, PruneFlag, PublicKey, Signature};
 use p2panda_net::{SystemEvent, TopicId};
 use p2panda_node::extensions::LogId;
 use p2panda_node::node::Node;
 use p2panda_node::operation::create_operation;
-use p2panda_node::stream::StreamEvent;
-use p2panda_node::topic::{Topic, TopicMap};
-use p2panda_store::MemoryStore;
+use p2panda_node::stream::{EventData, StreamEvent};
+use p2panda_node::topic::Topic;
+use p2panda_store::{LogStore, MemoryStore, OperationStore};
 use p2panda_sync::log_sync::TopicLogMap;
 use serde::Serialize;
 #[cfg(not(test))]
 use tauri::{AppHandle, Manager};
 use thiserror::Error;
-use tokio::sync::{broadcast, mpsc, RwLock};
-use tracing::debug;
-
-use crate::extensions::{to_log_id, Extensions, LogPath, Stream, StreamOwner, StreamRootHash};
-use crate::keystore::KeyStore;
-use crate::messages::{ChannelEvent, NetworkEvent, StreamArgs};
+use tokio::sync::{broadcast, mpsc, watch, RwLock};
+use tracing::{debug, error, warn};
+
+use crate::backup::{
+    decode_private_key, write_blobs, Backup, BackupError, STAGED_BACKUP_FILE_NAME,
+};
+use crate::bundle::{BundleError, StreamBundle};
+use crate::capabilities::{WriteAccess, WriteCapability};
+use crate::delegation::{DelegationError, DelegationRequest, DeviceDelegation};
+use crate::dependencies::{DependencyBuffer, DEPENDENCY_TIMEOUT};
+use crate::extensions::{
+    from_log_id, is_identity_record, to_log_id, unix_time, Dependencies, ExpiresAt, Extensions,
+    LogIdError, LogPath, LogPathError, SchemaVersion, Stream, StreamOwner, StreamRootHash,
+};
+use crate::gc::{self, GcReport, StorageQuota};
+use crate::inventory::{self, StreamInventory};
+use crate::keystore::{KeyStore, KeyStoreBackend, KeyStoreError, MemoryKeyStore};
+use crate::messages::{
+    ChannelEvent, NetworkEvent, PublishOptions, PublishStatus, StreamArgs, ToolkittyStreamEvent,
+};
+use crate::migrations;
+use crate::mnemonic::{from_mnemonic, to_mnemonic, MnemonicError};
+use crate::payload::{Payload, PayloadError};
+#[cfg(not(test))]
+use crate::profiles::Profiles;
+use crate::schema::{SchemaError, SchemaVersions};
+use crate::signing::{self, SigningError};
+use crate::store::{OutboxEntry, SqliteStore, StoreError};
+use crate::succession::KeySuccession;
+use crate::topic_map::TopicMap;
+use crate::unlock::Unlocker;
 
 const NETWORK_ID: &str = "toolkitty";
 
+/// Identity record which was added to the outbox and still needs to be delivered on its topics.
+type IdentityRecord = (Header<Extensions>, Option<Body>, Vec<String>);
+
+/// Interval in which the garbage collector enforces the storage quota.
+#[cfg(not(test))]
+const GC_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10 * 60);
+
+/// Interval in which expired operations are purged from the store.
+#[cfg(not(test))]
+const EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+/// Interval in which operations with missing dependencies are checked for timeouts.
+const DEPENDENCY_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 /// Shared application context which can be accessed from within the main application runtime loop
 /// as well as any tauri command.
 pub struct Context {
     /// Local p2panda node.
     pub node: Node<Topic, LogId, Extensions>,
 
+    /// Persistent store where all operations are written to so they survive restarts.
+    pub store: SqliteStore,
+
+    /// Directory where all data of the active profile is stored.
+    pub app_data_dir: PathBuf,
+
+    /// Backend the private key of the node is stored in.
+    pub key_store: Box<dyn KeyStore>,
+
     /// All topics we have subscribed to.
     pub subscriptions: HashMap<[u8; 32], Topic>,
 
@@ -48,30 +97,22 @@ pub struct Context {
     /// Flag indicating that we already received a channel from the frontend (init was already
     /// called). There is a bug if `init` is called twice.
     pub channel_set: bool,
-}
 
-impl Context {
-    pub fn new(
-        node: Node<Topic, LogId, Extensions>,
-        to_app_tx: broadcast::Sender<ChannelEvent>,
-        topic_map: TopicMap,
-        channel_tx: mpsc::Sender<broadcast::Sender<ChannelEvent>>,
-    ) -> Self {
-        Self {
-            node,
-            subscriptions: HashMap::new(),
-            to_app_tx,
-            topic_map: topic_map.clone(),
-            channel_tx,
-            channel_set: false,
-        }
-    }
+    /// Schema versions the frontend supports, shared with the service so it does not need to
+    /// read them from the database for every operation.
+    pub schema_versions_tx: watch::Sender<SchemaVersions>,
 }
 
 pub struct Service {
     /// Handle onto the tauri application. The shared Context can be accessed and modified here.
     context: Arc<RwLock<Context>>,
 
+    /// Persistent store where operations arriving on the stream are written to.
+    store: SqliteStore,
+
+    /// In-memory operation store of the node, shared with the node itself.
+    node_store: MemoryStore<LogId, Extensions>,
+
     /// Stream where we receive all topic events from the p2panda node.
     stream_rx: mpsc::Receiver<StreamEvent<Extensions>>,
 
@@ -83,53 +124,157 @@ pub struct Service {
 
     /// Channel where we receive the actual backend->frontend event channel.
     channel_rx: mpsc::Receiver<broadcast::Sender<ChannelEvent>>,
+
+    /// Persisted topics we automatically re-subscribed to on startup. The frontend is informed
+    /// about them as soon as we received the channel.
+    resubscribed: Vec<Topic>,
+
+    /// Operations which are held back until their dependencies were delivered to the frontend.
+    dependencies: DependencyBuffer,
+
+    /// Schema versions the frontend supports, updated whenever they are configured.
+    schema_versions_rx: watch::Receiver<SchemaVersions>,
+}
+
+/// Side effects of persisting an operation arriving on the stream.
+#[derive(Default)]
+struct Persisted {
+    /// Operations which were rejected earlier and are authorized by a write capability in the
+    /// persisted operation.
+    readmitted: Vec<StreamEvent<Extensions>>,
+
+    /// Operations which were deleted by a prune point in the persisted operation.
+    pruned: Vec<Hash>,
 }
 
 impl Service {
     /// Construct node, context and channels required for running the app service. Already
-    /// subscribe to the invite codes topic.
+    /// subscribe to all persisted topics we were subscribed to before the app was closed.
     ///
     /// The node and several channel senders are added to the shared app context while channel
     /// receivers are stored on the Service struct for use during the runtime loop.
-    pub async fn build(app_data_dir: PathBuf) -> anyhow::Result<Self> {
-        // Use an ephemeral private key if we're running with `tauri dev`.
-        let private_key = if cfg!(dev) {
-            PrivateKey::new()
-        } else {
-            let private_key_path = app_data_dir.join("private_key.txt");
-            PrivateKey::load_or_create_new(&private_key_path)?
-        };
-        let store = MemoryStore::new();
-        let topic_map = TopicMap::new();
-
-        let (node, stream_rx, network_events_rx) = Node::new(
+    pub async fn build(
+        app_data_dir: PathBuf,
+        key_store: Box<dyn KeyStore>,
+    ) -> anyhow::Result<Self> {
+        let private_key = key_store.load_or_create_new()?;
+
+        // Load all operations we persisted during earlier runs into the node's store so we can
+        // serve them to other peers and replay them to the frontend.
+        let sqlite_store = SqliteStore::open(&app_data_dir)?;
+        let mut store = MemoryStore::new();
+        let count = sqlite_store.hydrate(&mut store).await?;
+        debug!("loaded {count} persisted operations");
+
+        let topic_map = TopicMap::load(sqlite_store.clone())?;
+
+        let (mut node, stream_rx, network_events_rx) = Node::new(
             NETWORK_ID.to_string(),
             private_key,
             None,
             None,
             store.clone(),
-            app_data_dir,
+            app_data_dir.clone(),
             topic_map.clone(),
         )
         .await?;
 
+        // Restore the acknowledgement state of the stream controller so replays after a restart
+        // only redeliver operations the frontend did not process yet.
+        for operation_id in sqlite_store.acks()? {
+            if let Err(err) = node.ack(operation_id).await {
+                warn!("failed to restore ack for operation {operation_id}: {err}");
+            }
+        }
+
+        let mut subscriptions = HashMap::new();
+        let mut resubscribed = Vec::new();
+        for topic in sqlite_store.subscriptions()? {
+            let topic = Topic::Persisted(topic);
+            node.subscribe_persisted(&topic).await?;
+            subscriptions.insert(topic.id(), topic.clone());
+            resubscribed.push(topic);
+        }
+
         let (to_app_tx, to_app_rx) = broadcast::channel(32);
         let (channel_tx, channel_rx) = mpsc::channel(32);
+        let (schema_versions_tx, schema_versions_rx) =
+            watch::channel(SchemaVersions::load(&sqlite_store)?);
 
-        let context = Context::new(node, to_app_tx, topic_map, channel_tx);
+        let context = Context {
+            node,
+            store: sqlite_store.clone(),
+            app_data_dir,
+            key_store,
+            subscriptions,
+            to_app_tx,
+            topic_map,
+            channel_tx,
+            channel_set: false,
+            schema_versions_tx,
+        };
 
         Ok(Self {
             context: Arc::new(RwLock::new(context)),
+            store: sqlite_store,
+            node_store: store,
             stream_rx,
             network_events_rx,
             to_app_rx,
             channel_rx,
+            resubscribed,
+            dependencies: DependencyBuffer::default(),
+            schema_versions_rx,
         })
     }
 
+    /// Upgrade the data of a profile and build a service for it.
+    async fn open_profile(profile_dir: PathBuf, unlocker: &Unlocker) -> anyhow::Result<Self> {
+        // Upgrade data written by earlier versions of the app before touching anything else.
+        migrations::migrate(&profile_dir)?;
+
+        let key_store = Self::open_key_store(&profile_dir, unlocker).await?;
+        Self::build(profile_dir, key_store).await
+    }
+
+    /// Shut down the node of a service which was stopped, so the service of the next profile can
+    /// take over the network.
+    async fn shutdown(context: &Arc<RwLock<Context>>) {
+        if let Err(err) = context.read().await.node.shutdown().await {
+            error!("failed to shut down node: {err}");
+        }
+    }
+
+    /// Open the configured key store. If the private key is encrypted we wait until the frontend
+    /// unlocked it with the correct passphrase.
+    async fn open_key_store(
+        app_data_dir: &Path,
+        unlocker: &Unlocker,
+    ) -> anyhow::Result<Box<dyn KeyStore>> {
+        // Use an ephemeral private key if we're running with `tauri dev`.
+        if cfg!(dev) {
+            unlocker.unlocked();
+            return Ok(Box::new(MemoryKeyStore::default()));
+        }
+
+        let backend = KeyStoreBackend::load(&SqliteStore::open(app_data_dir)?, app_data_dir)?;
+        if backend == KeyStoreBackend::EncryptedFile {
+            return unlocker.wait_for_passphrase(app_data_dir).await;
+        }
+
+        unlocker.unlocked();
+        Ok(backend.open(app_data_dir, None)?)
+    }
+
     /// Spawn the service task.
+    ///
+    /// The service runs with the data of the active profile. Whenever another profile is chosen
+    /// the running service is stopped and a new one is started for that profile. Commands and
+    /// the frontend channel stay connected, only the contents of the shared context are replaced.
     #[cfg(not(test))]
     pub fn run(app_handle: AppHandle) {
+        app_handle.manage(Unlocker::default());
+
         tauri::async_runtime::spawn(async move {
             let app_data_dir = if cfg!(dev) {
                 tempfile::tempdir().expect("temp dir").into_path()
@@ -139,13 +284,105 @@ impl Service {
                     .app_data_dir()
                     .expect("app data directory")
             };
-            let mut app = Self::build(app_data_dir).await.expect("build stream");
-            let rpc = Rpc {
-                context: app.context.clone(),
-            };
-            app_handle.manage(rpc);
-            let channel = app.recv_channel().await.expect("receive on channel rx");
-            app.inner_run(channel).await.expect("run stream task");
+
+            let profiles = Profiles::open(app_data_dir).expect("open profiles");
+            let mut active_profile_rx = profiles.subscribe();
+            app_handle.manage(profiles);
+
+            let unlocker = app_handle.state::<Unlocker>();
+            let profiles = app_handle.state::<Profiles>();
+            let mut shared_context: Option<Arc<RwLock<Context>>> = None;
+            let mut channel = None;
+
+            loop {
+                let profile = active_profile_rx.borrow_and_update().clone();
+                let mut app = Self::open_profile(profiles.dir(&profile.id), &unlocker)
+                    .await
+                    .expect("open profile");
+                match &shared_context {
+                    Some(context) => app.share_context(context).await,
+                    None => {
+                        app_handle.manage(Rpc {
+                            context: app.context.clone(),
+                        });
+
+                        let rpc = Rpc {
+                            context: app.context.clone(),
+                        };
+                        tauri::async_runtime::spawn(async move {
+                            let mut interval = tokio::time::interval(GC_INTERVAL);
+                            loop {
+                                interval.tick().await;
+                                if let Err(err) = rpc.collect_garbage().await {
+                                    error!("garbage collection failed: {err}");
+                                }
+                            }
+                        });
+
+                        let rpc = Rpc {
+                            context: app.context.clone(),
+                        };
+                        tauri::async_runtime::spawn(async move {
+                            let mut interval = tokio::time::interval(EXPIRY_INTERVAL);
+                            loop {
+                                interval.tick().await;
+                                match rpc.purge_expired().await {
+                                    Ok(expired) if !expired.is_empty() => {
+                                        debug!("purged {} expired operations", expired.len())
+                                    }
+                                    Ok(_) => (),
+                                    Err(err) => error!("purging expired operations failed: {err}"),
+                                }
+                            }
+                        });
+
+                        shared_context = Some(app.context.clone());
+                    }
+                }
+                unlocker.ready();
+
+                let app_channel = match channel.take() {
+                    Some(channel) => {
+                        app.context.write().await.channel_set = true;
+                        let _ = channel.send(ChannelEvent::ProfileSwitched(profile));
+                        channel
+                    }
+                    None => app.recv_channel().await.expect("receive on channel rx"),
+                };
+
+                // Import a backup which was staged before the app was restarted with its identity.
+                let rpc = Rpc {
+                    context: app.context.clone(),
+                };
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = rpc.restore_staged_backup().await {
+                        error!("failed to restore staged backup: {err}");
+                    }
+                });
+
+                // Publish operations which were left in the outbox when the app was closed.
+                let rpc = Rpc {
+                    context: app.context.clone(),
+                };
+                tauri::async_runtime::spawn(async move {
+                    if let Err(err) = rpc.flush_outbox().await {
+                        error!("failed to flush outbox: {err}");
+                    }
+                });
+
+                let profile_changed = async {
+                    let _ = active_profile_rx.changed().await;
+                };
+                channel = Some(
+                    app.inner_run(app_channel, profile_changed)
+                        .await
+                        .expect("run stream task"),
+                );
+                if let Some(context) = &shared_context {
+                    Self::shutdown(context).await;
+                }
+                unlocker.reset();
+            }
         });
     }
 
@@ -153,7 +390,21 @@ impl Service {
     #[cfg(test)]
     pub async fn run() -> Arc<RwLock<Context>> {
         let temp_blobs_root_dir = tempfile::tempdir().expect("temp dir");
-        let mut app = Self::build(temp_blobs_root_dir.into_path())
+        Self::run_in(
+            temp_blobs_root_dir.into_path(),
+            Box::new(MemoryKeyStore::default()),
+        )
+        .await
+    }
+
+    /// Spawn the service task with the data in the given directory.
+    #[cfg(test)]
+    pub async fn run_in(
+        app_data_dir: PathBuf,
+        key_store: Box<dyn KeyStore>,
+    ) -> Arc<RwLock<Context>> {
+        migrations::migrate(&app_data_dir).expect("migrate app data");
+        let mut app = Self::build(app_data_dir, key_store)
             .await
             .expect("build stream");
         let context = app.context.clone();
@@ -161,7 +412,9 @@ impl Service {
 
         rt.spawn(async move {
             let channel = app.recv_channel().await.expect("receive on channel rx");
-            app.inner_run(channel).await.expect("run stream task");
+            app.inner_run(channel, std::future::pending())
+                .await
+                .expect("run stream task");
         });
 
         context
@@ -169,20 +422,46 @@ impl Service {
 
     /// Run the inner service loop which awaits events arriving on the app, network, stream and
     /// invite codes channels.
+    ///
+    /// Before entering the loop all topics we re-subscribed to on startup are announced to the
+    /// frontend. The loop ends when `shutdown` resolves, the frontend channel is returned so it
+    /// can be handed to the next service.
     pub(crate) async fn inner_run(
         mut self,
         mut channel: broadcast::Sender<ChannelEvent>,
-    ) -> anyhow::Result<()> {
+        shutdown: impl Future<Output = ()>,
+    ) -> anyhow::Result<broadcast::Sender<ChannelEvent>> {
+        for topic in self.resubscribed.drain(..) {
+            channel.send(ChannelEvent::SubscribedToTopic(topic))?;
+        }
+        self.restore_pending(&channel).await?;
+
+        let mut dependency_timer = tokio::time::interval(DEPENDENCY_CHECK_INTERVAL);
+
+        tokio::pin!(shutdown);
         loop {
             tokio::select! {
+                _ = &mut shutdown => {
+                    return Ok(channel);
+                }
                 Ok(event) = self.to_app_rx.recv() => {
                     channel.send(event)?;
                 }
                 Ok(event) = self.network_events_rx.recv() => {
+                    if let SystemEvent::GossipNeighborUp { .. } = event {
+                        self.retry_outbox();
+                    }
                     channel.send(ChannelEvent::NetworkEvent(NetworkEvent(event)))?;
                 },
                 Some(event) = self.stream_rx.recv() => {
-                    channel.send(ChannelEvent::Stream(event.into()))?;
+                    self.process(event, &channel).await?;
+                },
+                _ = dependency_timer.tick() => {
+                    for (header, missing) in self.dependencies.expired(DEPENDENCY_TIMEOUT) {
+                        warn!("operation {} is missing dependencies", header.hash());
+                        let event = ToolkittyStreamEvent::missing_dependencies(header, missing);
+                        channel.send(ChannelEvent::Stream(self.resolve_identity(event)))?;
+                    }
                 },
                 Some(new_channel) = self.channel_rx.recv() => {
                     channel = new_channel;
@@ -191,6 +470,417 @@ impl Service {
         }
     }
 
+    /// Authorize, persist and deliver an operation arriving on the stream.
+    ///
+    /// Capabilities in the operation can authorize operations which were rejected earlier, these
+    /// are processed right after it. Operations with dependencies are kept in the store until
+    /// they were delivered, so they are not lost if they are held back when the app stops.
+    async fn process(
+        &mut self,
+        event: StreamEvent<Extensions>,
+        channel: &broadcast::Sender<ChannelEvent>,
+    ) -> anyhow::Result<()> {
+        let mut events = VecDeque::from([event]);
+        while let Some(event) = events.pop_front() {
+            if self.is_acked(&event) || self.drop_expired(&event).await {
+                continue;
+            }
+            if let Err(rejected) = self.authorize(&event) {
+                channel.send(ChannelEvent::Stream(self.resolve_identity(rejected)))?;
+                if let Some(header) = &event.header {
+                    if let Err(err) = self.store.delete_pending(&header.hash()) {
+                        error!("failed to release operation {}: {err}", header.hash());
+                    }
+                    let ready = self.dependencies.resolve(header.hash());
+                    self.send_ready(ready, channel).await?;
+                }
+                continue;
+            }
+            self.insert_pending(&event);
+            let persisted = self.persist(&event).await;
+            events.extend(persisted.readmitted);
+
+            let mut ready = Vec::new();
+            for hash in persisted.pruned {
+                ready.extend(self.dependencies.resolve(hash));
+            }
+
+            // Identity records are only meant for the node, they are not application data.
+            if !event.header.as_ref().is_some_and(is_identity_record) {
+                ready.extend(
+                    self.dependencies
+                        .release(event, |hash| Self::is_resolved(&self.store, hash)),
+                );
+            }
+            self.send_ready(ready, channel).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Buffer the operations which were held back when the app stopped again. Operations which
+    /// were not persisted yet are processed like new ones.
+    async fn restore_pending(
+        &mut self,
+        channel: &broadcast::Sender<ChannelEvent>,
+    ) -> anyhow::Result<()> {
+        let pending = match self.store.pending_operations() {
+            Ok(pending) => pending,
+            Err(err) => {
+                error!("failed to read held back operations: {err}");
+                return Ok(());
+            }
+        };
+
+        for (header, body) in pending {
+            let event = StreamEvent {
+                data: EventData::Application(body.map(|body| body.to_bytes()).unwrap_or_default()),
+                header: Some(header),
+            };
+            let header = event.header.as_ref().expect("pending event has header");
+            match self.store.has_operation(&header.hash()) {
+                Ok(true) => {
+                    let ready = self
+                        .dependencies
+                        .release(event, |hash| Self::is_resolved(&self.store, hash));
+                    self.send_ready(ready, channel).await?;
+                }
+                Ok(false) => self.process(event, channel).await?,
+                Err(err) => error!("failed to restore operation {}: {err}", header.hash()),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Deliver operations which are not held back anymore to the frontend.
+    async fn send_ready(
+        &mut self,
+        ready: Vec<StreamEvent<Extensions>>,
+        channel: &broadcast::Sender<ChannelEvent>,
+    ) -> anyhow::Result<()> {
+        for event in ready {
+            if let Some(header) = &event.header {
+                if let Err(err) = self.store.delete_pending(&header.hash()) {
+                    error!("failed to release operation {}: {err}", header.hash());
+                }
+            }
+            if self.drop_expired(&event).await {
+                continue;
+            }
+            let event = self.check_schema_version(event);
+            channel.send(ChannelEvent::Stream(self.resolve_identity(event)))?;
+        }
+
+        Ok(())
+    }
+
+    /// Check that the author of an operation arriving on the stream may write to its log.
+    ///
+    /// Rejected operations are not persisted, instead an error event is returned which is
+    /// forwarded to the frontend. Operations without write access, or whose write access could
+    /// not be checked, are kept aside and accepted later if a capability granting access arrives.
+    fn authorize(&self, event: &StreamEvent<Extensions>) -> Result<(), ToolkittyStreamEvent> {
+        let (Some(header), EventData::Application(bytes)) = (&event.header, &event.data) else {
+            return Ok(());
+        };
+
+        let stream: Stream = header.extension().expect("extract stream extension");
+        let log_path: Option<LogPath> = header.extension();
+        if let Some(Err(err)) = log_path.as_ref().map(LogPath::validate) {
+            warn!("rejecting operation {}: {err}", header.hash());
+            return Err(ToolkittyStreamEvent::rejected(
+                header.clone(),
+                err.to_string(),
+            ));
+        }
+
+        let reason =
+            match self
+                .store
+                .check_write_access(&stream, log_path.as_ref(), &header.public_key)
+            {
+                Ok(()) => return Ok(()),
+                Err(StoreError::Capability(err)) => {
+                    warn!("rejecting operation {}: {err}", header.hash());
+                    err.to_string()
+                }
+                Err(err) => {
+                    error!("failed to check write access of {}: {err}", header.hash());
+                    "write access could not be checked".to_string()
+                }
+            };
+
+        let body = header.payload_hash.map(|_| Body::new(bytes));
+        if let Err(err) = self.store.insert_rejected(header, body.as_ref()) {
+            error!("failed to keep rejected operation {}: {err}", header.hash());
+        }
+        Err(ToolkittyStreamEvent::rejected(header.clone(), reason))
+    }
+
+    /// Returns `true` if the operation in this event is expired. The payloads of expired
+    /// operations are deleted right away so they are not synced to other peers. Their headers are
+    /// kept, logs stay verifiable and our own log continues after them.
+    async fn drop_expired(&mut self, event: &StreamEvent<Extensions>) -> bool {
+        let Some(header) = &event.header else {
+            return false;
+        };
+        let expires_at: Option<ExpiresAt> = header.extension();
+        if !expires_at.is_some_and(|expires_at| expires_at.is_expired()) {
+            return false;
+        }
+
+        debug!("dropping expired operation {}", header.hash());
+        let log_id: LogId = header.extension().expect("extract log id extension");
+        let result = self
+            .store
+            .insert_operation(header, None, &log_id)
+            .and_then(|_| self.store.delete_payload(&header.hash()));
+        if let Err(err) = result {
+            error!(
+                "failed to delete payload of expired operation {}: {err}",
+                header.hash()
+            );
+        }
+        if let Err(err) = self.node_store.delete_payload(header.hash()).await {
+            error!(
+                "failed to delete payload of expired operation {}: {err}",
+                header.hash()
+            );
+        }
+        true
+    }
+
+    /// Keep an operation with dependencies in the store until it was delivered to the frontend.
+    fn insert_pending(&self, event: &StreamEvent<Extensions>) {
+        let (Some(header), EventData::Application(bytes)) = (&event.header, &event.data) else {
+            return;
+        };
+        let dependencies: Dependencies = header.extension().unwrap_or_default();
+        if dependencies.0.is_empty() || is_identity_record(header) {
+            return;
+        }
+
+        let body = header.payload_hash.map(|_| Body::new(bytes));
+        if let Err(err) = self.store.insert_pending(header, body.as_ref()) {
+            error!("failed to keep operation {}: {err}", header.hash());
+        }
+    }
+
+    /// Returns `true` if the operation was already delivered to the frontend, during this or an
+    /// earlier run, or if it will never be delivered because it was rejected or pruned.
+    fn is_resolved(store: &SqliteStore, operation_id: &Hash) -> bool {
+        let is_resolved = |operation_id: &Hash| -> Result<bool, StoreError> {
+            if store.is_pending(operation_id)? {
+                return Ok(false);
+            }
+            Ok(store.has_operation(operation_id)?
+                || store.is_acked(operation_id)?
+                || store.is_rejected(operation_id)?
+                || store.is_pruned(operation_id)?)
+        };
+        is_resolved(operation_id).unwrap_or_else(|err| {
+            error!("failed to read delivery state of {operation_id}: {err}");
+            false
+        })
+    }
+
+    /// Withhold payloads of operations with a schema version the frontend does not support.
+    fn check_schema_version(&self, event: StreamEvent<Extensions>) -> ToolkittyStreamEvent {
+        let (Some(header), EventData::Application(_)) = (&event.header, &event.data) else {
+            return event.into();
+        };
+
+        let versions = self.schema_versions_rx.borrow().clone();
+        let version: SchemaVersion = header.extension().unwrap_or_default();
+        if versions.supports(version) {
+            return event.into();
+        }
+
+        debug!(
+            "withholding operation {} with unsupported schema version {version}",
+            header.hash()
+        );
+        ToolkittyStreamEvent::unsupported_schema(header.clone(), versions)
+    }
+
+    /// Write operations arriving on the stream to the persistent store.
+    ///
+    /// Operations which are marked as prune points cause all earlier operations in the same log
+    /// to be deleted.
+    async fn persist(&mut self, event: &StreamEvent<Extensions>) -> Persisted {
+        let mut persisted = Persisted::default();
+        let (Some(header), EventData::Application(bytes)) = (&event.header, &event.data) else {
+            return persisted;
+        };
+
+        let log_id: LogId = header.extension().expect("extract log id extension");
+        let body = header.payload_hash.map(|_| Body::new(bytes));
+        if let Err(err) = self.store.insert_operation(header, body.as_ref(), &log_id) {
+            error!("failed to persist operation {}: {err}", header.hash());
+        }
+
+        if let Some(capability) = body.as_ref().and_then(WriteCapability::from_body) {
+            match self.store.insert_write_capability(header, &capability) {
+                Ok(()) => persisted.readmitted = self.readmit_rejected(header),
+                Err(err) => warn!("ignoring write capability {}: {err}", header.hash()),
+            }
+        }
+
+        match DeviceDelegation::from_operation(header, body.as_ref()) {
+            Ok(Some(delegation)) => {
+                if let Err(err) = self.store.insert_delegation(&delegation) {
+                    warn!("ignoring device delegation {}: {err}", header.hash());
+                }
+            }
+            Ok(None) => (),
+            Err(err) => warn!("ignoring device delegation {}: {err}", header.hash()),
+        }
+
+        match KeySuccession::from_operation(header, body.as_ref()) {
+            Ok(Some(succession)) => {
+                if let Err(err) = self.store.insert_succession(&succession) {
+                    warn!("ignoring key succession {}: {err}", header.hash());
+                }
+            }
+            Ok(None) => (),
+            Err(err) => warn!("ignoring key succession {}: {err}", header.hash()),
+        }
+
+        let prune_flag: Option<PruneFlag> = header.extension();
+        if prune_flag.is_some_and(|flag| flag.is_set()) && header.seq_num > 0 {
+            if let Err(err) = self
+                .node_store
+                .delete_operations(&header.public_key, &log_id, header.seq_num)
+                .await
+            {
+                error!("failed to prune log {}: {err}", log_id.0);
+            }
+
+            match self
+                .store
+                .prune_log(&header.public_key, &log_id, header.seq_num)
+            {
+                Ok(pruned) => {
+                    debug!("pruned {} operations from log {}", pruned.len(), log_id.0);
+                    persisted.pruned = pruned;
+                }
+                Err(err) => error!("failed to prune log {}: {err}", log_id.0),
+            }
+        }
+
+        persisted
+    }
+
+    /// Take the operations in the stream of a new write capability which were rejected earlier
+    /// and have write access now.
+    fn readmit_rejected(&self, header: &Header<Extensions>) -> Vec<StreamEvent<Extensions>> {
+        let stream: Stream = header.extension().expect("extract stream extension");
+        let rejected = match self.store.rejected_operations(&stream) {
+            Ok(rejected) => rejected,
+            Err(err) => {
+                error!("failed to read rejected operations: {err}");
+                return Vec::new();
+            }
+        };
+
+        let mut events = Vec::new();
+        for (header, body) in rejected {
+            let log_path: Option<LogPath> = header.extension();
+            if self
+                .store
+                .check_write_access(&stream, log_path.as_ref(), &header.public_key)
+                .is_err()
+            {
+                continue;
+            }
+            if let Err(err) = self.store.delete_rejected(&header.hash()) {
+                error!(
+                    "failed to accept rejected operation {}: {err}",
+                    header.hash()
+                );
+                continue;
+            }
+
+            debug!("accepting operation {} rejected earlier", header.hash());
+            events.push(StreamEvent {
+                header: Some(header),
+                data: EventData::Application(body.map(|body| body.to_bytes()).unwrap_or_default()),
+            });
+        }
+        events
+    }
+
+    /// Resolve the identities of the author and the stream owner of an event, so rotated keys
+    /// and delegated devices are treated like the identity they belong to.
+    fn resolve_identity(&self, mut event: ToolkittyStreamEvent) -> ToolkittyStreamEvent {
+        let Some(meta) = event.meta.as_mut() else {
+            return event;
+        };
+
+        let result = self.store.identity_of(&meta.author).and_then(|identity| {
+            let owner_identity = self.store.identity_of(&meta.stream.owner.into())?;
+            Ok((identity, owner_identity))
+        });
+        match result {
+            Ok((identity, owner_identity)) => {
+                meta.identity = identity;
+                meta.owns_stream = identity == owner_identity;
+            }
+            Err(err) => error!("failed to resolve identity of {}: {err}", meta.author),
+        }
+
+        event
+    }
+
+    /// Retry publishing pending operations in the outbox when we connected to a new peer.
+    ///
+    /// Publishing requires the context lock, the outbox is therefore flushed in a separate task to
+    /// not block the service loop.
+    fn retry_outbox(&self) {
+        match self.store.outbox() {
+            Ok(entries) if entries.is_empty() => return,
+            Ok(_) => (),
+            Err(err) => {
+                error!("failed to read outbox: {err}");
+                return;
+            }
+        }
+
+        let rpc = Rpc {
+            context: self.context.clone(),
+        };
+        tokio::spawn(async move {
+            if let Err(err) = rpc.flush_outbox().await {
+                error!("failed to flush outbox: {err}");
+            }
+        });
+    }
+
+    /// Returns `true` if the frontend already acknowledged the operation in this event during this
+    /// or an earlier run. These are never delivered again.
+    fn is_acked(&self, event: &StreamEvent<Extensions>) -> bool {
+        let (Some(header), EventData::Application(_)) = (&event.header, &event.data) else {
+            return false;
+        };
+
+        self.store.is_acked(&header.hash()).unwrap_or_else(|err| {
+            error!("failed to read ack state of {}: {err}", header.hash());
+            false
+        })
+    }
+
+    /// Move the context of this service into the context which is shared with all commands,
+    /// replacing the context of the previously running service.
+    #[cfg(not(test))]
+    async fn share_context(&mut self, shared: &Arc<RwLock<Context>>) {
+        let context = std::mem::replace(&mut self.context, shared.clone());
+        let context = Arc::into_inner(context)
+            .expect("context is not shared yet")
+            .into_inner();
+        *shared.write().await = context;
+    }
+
     async fn recv_channel(&mut self) -> anyhow::Result<broadcast::Sender<ChannelEvent>> {
         let Some(channel) = self.channel_rx.recv().await else {
             return Err(anyhow::anyhow!("channel tx closed"));
@@ -227,9 +917,13 @@ impl Rpc {
     }
 
     /// Acknowledge operations to mark them as successfully processed in the stream controller.
+    ///
+    /// Acknowledgements are persisted, acknowledged operations are not replayed again after a
+    /// restart.
     pub async fn ack(&self, operation_id: Hash) -> Result<(), RpcError> {
         let mut context = self.context.write().await;
         context.node.ack(operation_id).await?;
+        context.store.insert_ack(&operation_id)?;
         Ok(())
     }
 
@@ -250,9 +944,25 @@ impl Rpc {
         topic: &str,
         log_id: &LogId,
     ) -> Result<(), RpcError> {
+        from_log_id(log_id)?;
         let context = self.context.write().await;
-        let topic = Topic::Persisted(topic.to_string());
-        context.topic_map.add_log(&topic, public_key, log_id).await;
+        context.topic_map.add_log(topic, public_key, log_id).await?;
+        Ok(())
+    }
+
+    /// Remove a log from a persisted topic in the topic log map.
+    pub async fn remove_topic_log(
+        &self,
+        public_key: &PublicKey,
+        topic: &str,
+        log_id: &LogId,
+    ) -> Result<(), RpcError> {
+        from_log_id(log_id)?;
+        let context = self.context.write().await;
+        context
+            .topic_map
+            .remove_log(topic, public_key, log_id)
+            .await?;
         Ok(())
     }
 
@@ -271,11 +981,7 @@ impl Rpc {
     async fn subscribe(&self, topic: &Topic) -> Result<(), RpcError> {
         let mut context = self.context.write().await;
 
-        if context
-            .subscriptions
-            .insert(topic.id(), topic.clone())
-            .is_some()
-        {
+        if context.subscriptions.contains_key(&topic.id()) {
             return Ok(());
         };
 
@@ -287,7 +993,10 @@ impl Rpc {
                     .await
                     .expect("can subscribe to topic");
             }
-            Topic::Persisted(_) => {
+            Topic::Persisted(name) => {
+                // Persist the subscription first, a failed write leaves us unsubscribed in memory
+                // and in the database.
+                context.store.insert_subscription(name)?;
                 context
                     .node
                     .subscribe_persisted(topic)
@@ -295,6 +1004,7 @@ impl Rpc {
                     .expect("can subscribe to topic");
             }
         }
+        context.subscriptions.insert(topic.id(), topic.clone());
 
         context
             .to_app_tx
@@ -303,24 +1013,33 @@ impl Rpc {
         Ok(())
     }
 
-    /// Publish to a persisted topic.
+    /// Publish to a persisted topic. Returns the ids of the operation and its stream, and whether
+    /// the operation was published or queued for another attempt.
     pub async fn publish_persisted(
         &self,
         payload: &[u8],
         stream_args: &StreamArgs,
         log_path: Option<&str>,
         topic: Option<&str>,
-    ) -> Result<(Hash, Hash), RpcError> {
+        options: &PublishOptions,
+    ) -> Result<(Hash, Hash, PublishStatus), RpcError> {
         let mut context = self.context.write().await;
         let private_key = context.node.private_key.clone();
 
         let stream_root_hash: Option<StreamRootHash> = stream_args.root_hash.map(Into::into);
         let stream_owner: Option<StreamOwner> = stream_args.owner.map(Into::into);
 
-        let log_path = log_path.map(|log_path| LogPath(log_path.to_string()));
+        let log_path = log_path
+            .map(|log_path| LogPath::try_from(log_path.to_string()))
+            .transpose()?;
         let log_id = match (stream_root_hash, stream_owner) {
             (Some(root_hash), Some(owner)) => {
                 let stream = Stream { root_hash, owner };
+                context.store.check_write_access(
+                    &stream,
+                    log_path.as_ref(),
+                    &private_key.public_key(),
+                )?;
                 Some(to_log_id(stream, log_path.clone()))
             }
             _ => None,
@@ -330,7 +1049,12 @@ impl Rpc {
             stream_root_hash: stream_args.root_hash.map(Into::into),
             stream_owner: stream_args.owner.map(Into::into),
             log_path,
-            ..Default::default()
+            prune_flag: PruneFlag::new(options.prune),
+            schema_version: options.schema_version,
+            content_type: options.content_type,
+            dependencies: options.dependencies.clone(),
+            expires_at: options.expires_at,
+            blobs: options.blobs.clone(),
         };
 
         let (header, body) = create_operation(
@@ -342,40 +1066,556 @@ impl Rpc {
         )
         .await;
 
-        match topic {
-            Some(topic) => {
-                let topic = Topic::Persisted(topic.to_string());
-                context
-                    .node
-                    .publish_persisted(&topic, &header, body.as_ref())
-                    .await?;
-            }
-            None => {
-                context.node.ingest(&header, body.as_ref()).await?;
-            }
-        }
+        // The operation is part of our log from now on, it is persisted together with its outbox
+        // entry right away. The log survives a restart even before the operation was published
+        // or arrived on the stream, and it is not lost if publishing fails.
+        let log_id: LogId = header.extension().expect("extract log id extension");
+        context
+            .store
+            .insert_outbox(&header, body.as_ref(), &log_id, topic)?;
+        let status = Self::deliver(&mut context, &header, body.as_ref(), topic).await;
 
         debug!("publish operation: {}", header.hash());
 
         let stream: Stream = header.extension().expect("extract stream extension");
 
-        Ok((header.hash(), stream.id()))
+        Ok((header.hash(), stream.id(), status))
     }
 
-    /// Publish to an ephemeral topic.
-    pub async fn publish_ephemeral(&self, topic: &str, payload: &[u8]) -> Result<(), RpcError> {
-        let mut context = self.context.write().await;
-        let topic = Topic::Ephemeral(topic.to_string());
-        context.node.publish_ephemeral(&topic, payload).await?;
-        Ok(())
+    /// Grant or revoke write access to a log path of a stream we own.
+    pub async fn set_write_access(
+        &self,
+        stream_args: &StreamArgs,
+        log_path: Option<&str>,
+        subject: PublicKey,
+        access: WriteAccess,
+        topic: Option<&str>,
+    ) -> Result<Hash, RpcError> {
+        let capability = WriteCapability {
+            subject,
+            log_path: log_path
+                .map(|log_path| LogPath::try_from(log_path.to_string()))
+                .transpose()?,
+            access,
+        };
+        let (operation_id, _, _) = self
+            .publish_persisted(
+                &capability.to_bytes(),
+                stream_args,
+                log_path,
+                topic,
+                &PublishOptions::default(),
+            )
+            .await?;
+        Ok(operation_id)
     }
 
-    /// Upload a file.
+    /// Replace the private key of the local node with a new one.
+    ///
+    /// A key succession record signed by the previous and the new key is published on all
+    /// persisted topics we are subscribed to, so peers treat the new key as the same identity.
+    /// The new key is written to the key store and used after the app was restarted. Returns the
+    /// new public key.
+    pub async fn rotate_key(&self) -> Result<PublicKey, RpcError> {
+        let mut context = self.context.write().await;
+        let private_key = context.node.private_key.clone();
+        let next_private_key = PrivateKey::new();
+        let succession = KeySuccession::new(&private_key, &next_private_key);
+        let payload = succession.to_bytes();
+
+        let record = Self::prepare_identity_record(&mut context, &payload).await?;
+        context.store.insert_succession(&succession)?;
+
+        if let Err(err) = context.key_store.replace(&next_private_key) {
+            // The operation was never published, removing it from the end of our identity log
+            // again is safe.
+            let operation_id = record.0.hash();
+            context.store.delete_outbox(&operation_id)?;
+            context.store.delete_operation(&operation_id)?;
+            if let Err(err) = context.node.store.delete_operation(operation_id).await {
+                error!("failed to delete operation {operation_id}: {err}");
+            }
+            context.store.delete_succession(&succession.previous)?;
+            return Err(err.into());
+        }
+
+        Self::deliver_identity_record(&mut context, record).await;
+
+        Ok(next_private_key.public_key())
+    }
+
+    /// Request to act as a device of the given identity. The request is signed with the key of
+    /// this device and needs to be completed with `delegate_device` by the identity.
+    pub async fn request_delegation(
+        &self,
+        identity: PublicKey,
+    ) -> Result<DelegationRequest, RpcError> {
+        let context = self.context.read().await;
+        Ok(DelegationRequest::new(identity, &context.node.private_key))
+    }
+
+    /// Delegate the key of another device to the local identity.
+    ///
+    /// The delegation certificate is published on all persisted topics we are subscribed to, so
+    /// peers treat operations of the device as authored by our identity.
+    pub async fn delegate_device(&self, request: DelegationRequest) -> Result<(), RpcError> {
+        let mut context = self.context.write().await;
+        let delegation = DeviceDelegation::new(&context.node.private_key, request)?;
+        context.store.insert_delegation(&delegation)?;
+
+        let record = Self::prepare_identity_record(&mut context, &delegation.to_bytes()).await?;
+        Self::deliver_identity_record(&mut context, record).await;
+
+        Ok(())
+    }
+
+    /// Create an operation containing an identity record like a key succession or a device
+    /// delegation in our identity log and add it to the outbox.
+    ///
+    /// The identity log is added to the topic log map of every persisted topic we are subscribed
+    /// to, so peers which join later still receive the record via sync. Returns the operation
+    /// together with these topics.
+    async fn prepare_identity_record(
+        context: &mut Context,
+        payload: &[u8],
+    ) -> Result<IdentityRecord, RpcError> {
+        let private_key = context.node.private_key.clone();
+
+        let topics: Vec<String> = context
+            .subscriptions
+            .values()
+            .filter_map(|topic| match topic {
+                Topic::Persisted(name) => Some(name.clone()),
+                Topic::Ephemeral(_) => None,
+            })
+            .collect();
+
+        let extensions = Extensions::identity(private_key.public_key());
+        let log_id = to_log_id(
+            Stream::identity(private_key.public_key()),
+            extensions.log_path.clone(),
+        );
+
+        let (header, body) = create_operation(
+            &mut context.node.store,
+            &private_key,
+            Some(&log_id),
+            Some(extensions),
+            Some(payload),
+        )
+        .await;
+        context.store.insert_outbox(
+            &header,
+            body.as_ref(),
+            &log_id,
+            topics.first().map(String::as_str),
+        )?;
+        for topic in &topics {
+            context
+                .topic_map
+                .add_log(topic, &private_key.public_key(), &log_id)
+                .await?;
+        }
+
+        Ok((header, body, topics))
+    }
+
+    /// Publish an identity record prepared with `prepare_identity_record` on all its topics.
+    ///
+    /// Only publishing on the first topic is retried from the outbox, peers on the other topics
+    /// receive the record via sync if publishing on them fails.
+    async fn deliver_identity_record(context: &mut Context, record: IdentityRecord) {
+        let (header, body, topics) = record;
+        let Some((first, rest)) = topics.split_first() else {
+            Self::deliver(context, &header, body.as_ref(), None).await;
+            return;
+        };
+
+        Self::deliver(context, &header, body.as_ref(), Some(first)).await;
+        for topic in rest {
+            let topic = Topic::Persisted(topic.clone());
+            if let Err(err) = context
+                .node
+                .publish_persisted(&topic, &header, body.as_ref())
+                .await
+            {
+                warn!(
+                    "publishing identity record {} on {topic:?} failed: {err}",
+                    header.hash()
+                );
+            }
+        }
+    }
+
+    /// Publish an operation from the outbox and remove it from there on success. Failed attempts
+    /// are recorded and retried later.
+    async fn deliver(
+        context: &mut Context,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+        topic: Option<&str>,
+    ) -> PublishStatus {
+        let result: Result<(), RpcError> = match topic {
+            Some(topic) => {
+                let topic = Topic::Persisted(topic.to_string());
+                context
+                    .node
+                    .publish_persisted(&topic, header, body)
+                    .await
+                    .map_err(Into::into)
+            }
+            None => context.node.ingest(header, body).await.map_err(Into::into),
+        };
+
+        let operation_id = header.hash();
+        let (status, result) = match result {
+            Ok(_) => (
+                PublishStatus::Published,
+                context.store.delete_outbox(&operation_id),
+            ),
+            Err(err) => {
+                warn!("publishing operation {operation_id} failed, will retry: {err}");
+                (
+                    PublishStatus::Queued,
+                    context
+                        .store
+                        .record_outbox_failure(&operation_id, &err.to_string()),
+                )
+            }
+        };
+        if let Err(err) = result {
+            error!("failed to update outbox entry of {operation_id}: {err}");
+        }
+        status
+    }
+
+    /// Retry publishing all operations in the outbox. Returns the number of operations which are
+    /// still pending afterwards.
+    pub async fn flush_outbox(&self) -> Result<usize, RpcError> {
+        // Holding the write lock for the whole pass makes sure operations are not published twice
+        // by concurrent flushes.
+        let mut context = self.context.write().await;
+        for entry in context.store.outbox()? {
+            Self::deliver(
+                &mut context,
+                &entry.header,
+                entry.body.as_ref(),
+                entry.topic.as_deref(),
+            )
+            .await;
+        }
+        Ok(context.store.outbox()?.len())
+    }
+
+    /// All operations which were created but not published yet.
+    pub async fn outbox(&self) -> Result<Vec<OutboxEntry>, RpcError> {
+        let context = self.context.read().await;
+        Ok(context.store.outbox()?)
+    }
+
+    /// Publish to an ephemeral topic. The payload is sent together with its content type.
+    pub async fn publish_ephemeral(&self, topic: &str, payload: &Payload) -> Result<(), RpcError> {
+        let mut context = self.context.write().await;
+        let topic = Topic::Ephemeral(topic.to_string());
+        context
+            .node
+            .publish_ephemeral(&topic, &payload.to_ephemeral())
+            .await?;
+        Ok(())
+    }
+
+    /// Export all operations, the topic log map, subscriptions and referenced blobs into a backup
+    /// file. The private key is only included when explicitly requested.
+    pub async fn export_backup(
+        &self,
+        path: &Path,
+        include_private_key: bool,
+    ) -> Result<(), RpcError> {
+        let context = self.context.read().await;
+        let backup = Backup::export(&context, include_private_key).await?;
+        backup.write(path)?;
+        Ok(())
+    }
+
+    /// Sign application data with the private key of the local node. The tag names the purpose
+    /// of the data and needs to be passed again to verify the signature.
+    pub async fn sign(&self, tag: &str, bytes: &[u8]) -> Result<Signature, RpcError> {
+        let context = self.context.read().await;
+        let signature = signing::sign(&context.node.private_key, tag, bytes)?;
+        Ok(signature)
+    }
+
+    /// The private key of the local node as a mnemonic word list.
+    pub async fn export_mnemonic(&self) -> Result<String, RpcError> {
+        let context = self.context.read().await;
+        Ok(to_mnemonic(&context.node.private_key))
+    }
+
+    /// Restore the identity encoded in a mnemonic word list.
+    ///
+    /// Like importing a backup with another identity this is only possible as long as the
+    /// current identity did not author any operations. The private key is written to the key
+    /// store, `true` is returned if it differs from the current one and the app needs to be
+    /// restarted.
+    pub async fn restore_mnemonic(&self, words: &str) -> Result<bool, RpcError> {
+        let private_key = from_mnemonic(words)?;
+
+        let context = self.context.read().await;
+        let public_key = context.node.private_key.public_key();
+        if private_key.public_key() == public_key {
+            return Ok(false);
+        }
+        if context.store.count_operations_by(&public_key)? > 0 {
+            return Err(BackupError::IdentityInUse.into());
+        }
+
+        context.key_store.replace(&private_key)?;
+        Ok(true)
+    }
+
+    /// Import a backup file after verifying all operations in it.
+    ///
+    /// If the backup contains a different identity it can only be imported into a fresh
+    /// installation which did not author any operations yet. The private key is written to the
+    /// key store and the rest of the backup is staged to be imported after a restart, in this
+    /// case `true` is returned and the app needs to be restarted.
+    pub async fn import_backup(&self, path: &Path) -> Result<bool, RpcError> {
+        let mut backup = Backup::read(path)?;
+
+        if let Some(private_key) = backup.private_key.take() {
+            let private_key = decode_private_key(&private_key)?;
+
+            let context = self.context.read().await;
+            if private_key.public_key() != context.node.private_key.public_key() {
+                let public_key = context.node.private_key.public_key();
+                if context.store.count_operations_by(&public_key)? > 0 {
+                    return Err(BackupError::IdentityInUse.into());
+                }
+
+                context
+                    .key_store
+                    .replace(&private_key)
+                    .map_err(|err| BackupError::InvalidPrivateKey(err.to_string()))?;
+                backup.write(&context.app_data_dir.join(STAGED_BACKUP_FILE_NAME))?;
+                return Ok(true);
+            }
+        }
+
+        self.restore_backup(backup).await?;
+        Ok(false)
+    }
+
+    /// Import a backup which was staged by `import_backup` before the app restarted.
+    pub async fn restore_staged_backup(&self) -> Result<(), RpcError> {
+        let path = {
+            let context = self.context.read().await;
+            context.app_data_dir.join(STAGED_BACKUP_FILE_NAME)
+        };
+        if !path.exists() {
+            return Ok(());
+        }
+
+        let backup = Backup::read(&path)?;
+        self.restore_backup(backup).await?;
+        std::fs::remove_file(path).map_err(BackupError::from)?;
+        Ok(())
+    }
+
+    async fn restore_backup(&self, backup: Backup) -> Result<(), RpcError> {
+        {
+            let mut context = self.context.write().await;
+
+            write_blobs(&context.node, &context.store, &backup.blobs).await?;
+
+            // Operations are ingested into the node so they are persisted and forwarded to the
+            // frontend like any other operation we receive.
+            for operation in &backup.operations {
+                let (header, body) = operation.decode_and_verify()?;
+                if context.store.has_operation(&header.hash())? {
+                    continue;
+                }
+                context.node.ingest(&header, body.as_ref()).await?;
+            }
+
+            for topic_log in &backup.topic_logs {
+                context
+                    .topic_map
+                    .add_log(&topic_log.topic, &topic_log.public_key, &topic_log.log_id)
+                    .await?;
+            }
+        }
+
+        for topic in &backup.subscriptions {
+            self.subscribe_persisted(topic).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Export all logs of a stream into a bundle file which can be imported on another device.
+    pub async fn export_stream(
+        &self,
+        stream_id: Hash,
+        path: &Path,
+        include_blobs: bool,
+    ) -> Result<(), RpcError> {
+        let context = self.context.read().await;
+        let bundle = StreamBundle::export(&context, stream_id, include_blobs).await?;
+        bundle.write(path)?;
+        Ok(())
+    }
+
+    /// Import a stream bundle. All operations are ingested into the node and forwarded to the
+    /// frontend as if they were received via sync. Returns the id of the imported stream.
+    pub async fn import_stream(&self, path: &Path) -> Result<Hash, RpcError> {
+        let bundle = StreamBundle::read(path)?;
+        let operations = bundle.verify()?;
+
+        let mut context = self.context.write().await;
+        write_blobs(&context.node, &context.store, &bundle.blobs)
+            .await
+            .map_err(BundleError::from)?;
+        for (header, body) in operations {
+            if context.store.has_operation(&header.hash())? {
+                continue;
+            }
+            context.node.ingest(&header, body.as_ref()).await?;
+        }
+
+        Ok(bundle.stream.id())
+    }
+
+    /// Upload a file.
     pub async fn upload_file(&self, path: PathBuf) -> Result<Hash, RpcError> {
         let context = self.context.read().await;
+        let size = std::fs::metadata(&path)?.len();
         let file_hash = context.node.upload_file(path).await?;
+        context.store.record_blob(&file_hash, size, true)?;
         Ok(file_hash)
     }
+
+    /// Move the private key into another key store backend. A passphrase is required for the
+    /// encrypted file backend. The key is removed from the previous backend afterwards, this is
+    /// also how existing plain text key files are migrated.
+    pub async fn set_key_store(
+        &self,
+        backend: KeyStoreBackend,
+        passphrase: Option<&str>,
+    ) -> Result<(), RpcError> {
+        // Moving the key into memory would delete it from disk and lose the identity on the
+        // next start.
+        if backend == KeyStoreBackend::Memory {
+            return Err(KeyStoreError::NotPersistent.into());
+        }
+
+        let mut context = self.context.write().await;
+        let key_store = backend.open(&context.app_data_dir, passphrase)?;
+
+        // The previous key store shares its location with the new one if only the passphrase
+        // changed, the key is replaced in that case. Otherwise we refuse to overwrite any key
+        // which might already be stored in the new backend.
+        let same_backend = context.key_store.backend() == backend;
+        if same_backend {
+            key_store.replace(&context.node.private_key)?;
+        } else {
+            key_store.save(&context.node.private_key)?;
+        }
+        backend.save(&context.store)?;
+
+        let previous_key_store = std::mem::replace(&mut context.key_store, key_store);
+        if !same_backend {
+            previous_key_store.delete()?;
+        }
+
+        Ok(())
+    }
+
+    /// Get the configured storage quota.
+    pub async fn storage_quota(&self) -> Result<StorageQuota, RpcError> {
+        let context = self.context.read().await;
+        Ok(StorageQuota::load(&context.store)?)
+    }
+
+    /// Configure the storage quota, it is enforced during the next garbage collection.
+    pub async fn set_storage_quota(&self, quota: &StorageQuota) -> Result<(), RpcError> {
+        let context = self.context.read().await;
+        quota.save(&context.store)?;
+        Ok(())
+    }
+
+    /// Get the range of payload schema versions the frontend supports.
+    pub async fn schema_versions(&self) -> Result<SchemaVersions, RpcError> {
+        let context = self.context.read().await;
+        Ok(context.schema_versions_tx.borrow().clone())
+    }
+
+    /// Configure the range of payload schema versions the frontend supports. Operations outside
+    /// of it are forwarded as `unsupported_schema` events.
+    pub async fn set_schema_versions(&self, versions: &SchemaVersions) -> Result<(), RpcError> {
+        versions.validate()?;
+        let context = self.context.read().await;
+        versions.save(&context.store)?;
+        context.schema_versions_tx.send_replace(versions.clone());
+        Ok(())
+    }
+
+    /// Delete the payloads of all expired operations from the persistent and the node store, so
+    /// they are not delivered or synced anymore. Returns the ids of the affected operations.
+    pub async fn purge_expired(&self) -> Result<Vec<Hash>, RpcError> {
+        let context = self.context.read().await;
+        let expired = context.store.purge_expired(unix_time())?;
+        let mut node_store = context.node.store.clone();
+        for operation_id in &expired {
+            if let Err(err) = node_store.delete_payload(*operation_id).await {
+                error!("failed to delete payload of expired operation {operation_id}: {err}");
+            }
+        }
+        Ok(expired)
+    }
+
+    /// List all streams we persisted operations of with their logs, authors, operation counts and
+    /// sizes.
+    pub async fn streams(&self) -> Result<Vec<StreamInventory>, RpcError> {
+        let context = self.context.read().await;
+        Ok(inventory::streams(&context.store)?)
+    }
+
+    /// Get the inventory of a single stream, `None` if we don't know any operations of it.
+    pub async fn stream(&self, stream_id: Hash) -> Result<Option<StreamInventory>, RpcError> {
+        let context = self.context.read().await;
+        Ok(inventory::stream(&context.store, &stream_id)?)
+    }
+
+    /// Evict blobs until the storage quota is met. Reclaimed space is reported to the frontend if
+    /// anything was evicted.
+    pub async fn collect_garbage(&self) -> Result<GcReport, RpcError> {
+        let context = self.context.read().await;
+        let quota = StorageQuota::load(&context.store)?;
+        let report = gc::collect_garbage(
+            &context.store,
+            &context.node,
+            &quota,
+            &context.node.private_key.public_key(),
+        )
+        .await?;
+
+        if !report.evicted_blobs.is_empty() {
+            context
+                .to_app_tx
+                .send(ChannelEvent::GarbageCollected(report.clone()))?;
+        }
+
+        Ok(report)
+    }
+
+    /// Pin a blob so it is never evicted by the garbage collector, or unpin it again.
+    pub async fn pin_blob(&self, hash: Hash, pinned: bool) -> Result<(), RpcError> {
+        let context = self.context.read().await;
+        if !context.store.pin_blob(&hash, pinned)? {
+            return Err(RpcError::UnknownBlob(hash));
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Error)]
@@ -394,6 +1634,45 @@ pub enum RpcError {
 
     #[error("sending message on channel failed")]
     ChannelSender(#[from] tokio::sync::broadcast::error::SendError<ChannelEvent>),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+
+    #[error(transparent)]
+    Mnemonic(#[from] MnemonicError),
+
+    #[error(transparent)]
+    Delegation(#[from] DelegationError),
+
+    #[error(transparent)]
+    Signing(#[from] SigningError),
+
+    #[error(transparent)]
+    Payload(#[from] PayloadError),
+
+    #[error(transparent)]
+    LogPath(#[from] LogPathError),
+
+    #[error(transparent)]
+    LogId(#[from] LogIdError),
+
+    #[error(transparent)]
+    Schema(#[from] SchemaError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    Backup(#[from] BackupError),
+
+    #[error(transparent)]
+    Bundle(#[from] BundleError),
+
+    #[error("blob {0} is not stored locally")]
+    UnknownBlob(Hash),
 }
 
 impl Serialize for RpcError {
@@ -407,20 +1686,75 @@ impl Serialize for RpcError {
 
 #[cfg(test)]
 mod tests {
+    use std::path::Path;
     use std::time::Duration;
 
-    use p2panda_node::{extensions::LogId, topic::Topic};
+    use iroh_io::AsyncSliceReaderExt;
+    use p2panda_core::{Hash, PrivateKey};
+    use p2panda_node::{extensions::LogId, operation::create_operation, topic::Topic};
+    use p2panda_store::OperationStore;
+    use p2panda_sync::log_sync::TopicLogMap;
     use serde_json::json;
-    use tokio::sync::broadcast;
+    use tokio::sync::{broadcast, watch};
 
     use crate::{
-        extensions::{LogPath, Stream, StreamOwner, StreamRootHash},
+        capabilities::{CapabilityError, WriteAccess},
+        delegation::DelegationError,
+        extensions::{
+            is_identity_record, to_log_id, ContentType, Dependencies, Extensions, LogPath, Stream,
+            StreamOwner, StreamRootHash, IDENTITY_LOG_PATH,
+        },
+        keystore::{EncryptedFileKeyStore, FileKeyStore, KeyStore, KeyStoreBackend},
         messages::{
-            ChannelEvent, StreamArgs, ToolkittyEventData, ToolkittyEventMeta, ToolkittyStreamEvent,
+            ChannelEvent, PublishOptions, PublishStatus, StreamArgs, ToolkittyEventData,
+            ToolkittyEventMeta, ToolkittyStreamEvent,
         },
+        migrations::{ENCRYPTED_PRIVATE_KEY_FILE_NAME, PRIVATE_KEY_FILE_NAME},
+        mnemonic::to_mnemonic,
+        payload::Payload,
+        profiles::{Profile, Profiles, DEFAULT_PROFILE_ID},
+        schema::{SchemaError, SchemaVersions},
+        store::StoreError,
+        unlock::Unlocker,
     };
 
-    use super::{Rpc, Service};
+    use super::{Rpc, RpcError, Service};
+
+    /// Start a service with the data in the given directory. The private key is stored in a file
+    /// so the identity survives restarts.
+    async fn start(app_data_dir: &Path) -> Rpc {
+        let key_store = FileKeyStore::new(app_data_dir.join(PRIVATE_KEY_FILE_NAME));
+        Rpc {
+            context: Service::run_in(app_data_dir.to_path_buf(), Box::new(key_store)).await,
+        }
+    }
+
+    /// Shut the node of a service down and start a new one with the same data, like after the
+    /// app was closed and opened again.
+    async fn restart(rpc: Rpc) -> Rpc {
+        let app_data_dir = {
+            let context = rpc.context.read().await;
+            context.node.shutdown().await.unwrap();
+            context.app_data_dir.clone()
+        };
+        start(&app_data_dir).await
+    }
+
+    /// Ids of the operations delivered on the channel until no event arrived for a second.
+    async fn delivered_operations(channel_rx: &mut broadcast::Receiver<ChannelEvent>) -> Vec<Hash> {
+        let mut operation_ids = Vec::new();
+        while let Ok(Ok(event)) =
+            tokio::time::timeout(Duration::from_secs(1), channel_rx.recv()).await
+        {
+            if let ChannelEvent::Stream(ToolkittyStreamEvent {
+                meta: Some(meta), ..
+            }) = event
+            {
+                operation_ids.push(meta.operation_id);
+            }
+        }
+        operation_ids
+    }
 
     #[tokio::test]
     async fn public_key() {
@@ -482,6 +1816,31 @@ mod tests {
         }
     }
 
+    #[tokio::test]
+    async fn rejoin_subscriptions() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let rpc = start(tmp_dir.path()).await;
+        rpc.subscribe_persisted("calendar").await.unwrap();
+        rpc.subscribe_ephemeral("presence").await.unwrap();
+
+        // Persisted topics are joined again after a restart and announced to the frontend,
+        // ephemeral ones are forgotten.
+        let rpc = restart(rpc).await;
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+
+        let event = channel_rx.recv().await.unwrap();
+        match event {
+            ChannelEvent::SubscribedToTopic(topic) => {
+                assert_eq!(topic, Topic::Persisted("calendar".to_string()));
+            }
+            _ => panic!(),
+        }
+
+        let context = rpc.context.read().await;
+        assert_eq!(context.subscriptions.len(), 1);
+    }
+
     #[tokio::test]
     async fn publish() {
         let context = Service::run().await;
@@ -512,11 +1871,12 @@ mod tests {
                 &stream_args,
                 Some(&log_path),
                 Some(&topic),
+                &PublishOptions::default(),
             )
             .await;
 
         assert!(result.is_ok());
-        let (operation_hash, stream_id) = result.unwrap();
+        let (operation_hash, stream_id, _) = result.unwrap();
 
         let expected_log_path = log_path;
         let event = channel_rx.recv().await.unwrap();
@@ -535,9 +1895,13 @@ mod tests {
                 assert_eq!(stream.id, stream_id);
                 assert_eq!(stream.root_hash, StreamRootHash::from(operation_hash));
                 assert_eq!(stream.owner, StreamOwner::from(private_key.public_key()));
-                assert_eq!(log_path, Some(LogPath::from(expected_log_path.to_string())));
+                assert_eq!(
+                    log_path,
+                    Some(LogPath::try_from(expected_log_path.to_string()).unwrap())
+                );
 
-                let ToolkittyEventData::Application(value) = stream_event.data else {
+                let ToolkittyEventData::Application(Payload::Json(value)) = stream_event.data
+                else {
                     panic!();
                 };
 
@@ -548,19 +1912,111 @@ mod tests {
     }
 
     #[tokio::test]
-    async fn two_peers_subscribe() {
-        let peer_a = Rpc {
+    async fn persist_published_operations() {
+        let rpc = Rpc {
             context: Service::run().await,
         };
-        let peer_b = Rpc {
-            context: Service::run().await,
-        };
-
-        let (peer_a_tx, _peer_a_rx) = broadcast::channel(100);
-        let (peer_b_tx, mut peer_b_rx) = broadcast::channel(100);
 
-        let result = peer_a.init(peer_a_tx).await;
-        assert!(result.is_ok());
+        // Operations are persisted when they are created, also before the frontend called
+        // `init` and the service started processing the stream.
+        let (operation_id, _, _) = rpc
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "message": "organize!" })).unwrap(),
+                &StreamArgs::default(),
+                Some("messages"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let context = rpc.context.read().await;
+        assert!(context.store.has_operation(&operation_id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn replay_unacked_operations_after_restart() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let rpc = start(tmp_dir.path()).await;
+        let public_key = rpc.public_key().await.unwrap();
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+
+        let payload = serde_json::to_vec(&json!({ "message": "organize!" })).unwrap();
+        let (root_hash, stream_id, _) = rpc
+            .publish_persisted(
+                &payload,
+                &StreamArgs::default(),
+                Some("messages"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        let stream_args = StreamArgs {
+            id: Some(stream_id),
+            root_hash: Some(root_hash),
+            owner: Some(public_key),
+        };
+        let (operation_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &stream_args,
+                Some("messages"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            delivered_operations(&mut channel_rx).await,
+            vec![root_hash, operation_id]
+        );
+
+        // Only the first operation is acknowledged before the app is closed.
+        rpc.ack(root_hash).await.unwrap();
+        let log_id = to_log_id(
+            Stream {
+                root_hash: root_hash.into(),
+                owner: public_key.into(),
+            },
+            Some(LogPath::try_from("messages".to_string()).unwrap()),
+        );
+        rpc.add_topic_log(&public_key, "messages", &log_id)
+            .await
+            .unwrap();
+
+        // Log ids which were not created with `to_log_id` are refused.
+        let result = rpc
+            .add_topic_log(&public_key, "messages", &LogId("messages".to_string()))
+            .await;
+        assert!(matches!(result, Err(RpcError::LogId(_))));
+
+        let rpc = restart(rpc).await;
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+        rpc.replay("messages").await.unwrap();
+
+        assert_eq!(
+            delivered_operations(&mut channel_rx).await,
+            vec![operation_id]
+        );
+    }
+
+    #[tokio::test]
+    async fn two_peers_subscribe() {
+        let peer_a = Rpc {
+            context: Service::run().await,
+        };
+        let peer_b = Rpc {
+            context: Service::run().await,
+        };
+
+        let (peer_a_tx, _peer_a_rx) = broadcast::channel(100);
+        let (peer_b_tx, mut peer_b_rx) = broadcast::channel(100);
+
+        let result = peer_a.init(peer_a_tx).await;
+        assert!(result.is_ok());
 
         let result = peer_b.init(peer_b_tx).await;
         assert!(result.is_ok());
@@ -582,7 +2038,7 @@ mod tests {
                 loop {
                     tokio::time::sleep(Duration::from_secs(1)).await;
                     let result = peer_a
-                        .publish_ephemeral(&topic, &serde_json::to_vec(&send_payload).unwrap())
+                        .publish_ephemeral(&topic, &Payload::Json(send_payload.clone()))
                         .await;
                     assert!(result.is_ok());
                 }
@@ -591,12 +2047,21 @@ mod tests {
 
         let mut message_received = false;
         while let Ok(event) = peer_b_rx.recv().await {
-            if let ChannelEvent::Stream(ToolkittyStreamEvent {
-                data: ToolkittyEventData::Ephemeral(payload),
-                ..
-            }) = event
+            if let ChannelEvent::Stream(
+                event @ ToolkittyStreamEvent {
+                    data: ToolkittyEventData::Ephemeral(Payload::Json(_)),
+                    ..
+                },
+            ) = event
             {
-                assert_eq!(send_payload, payload);
+                assert_eq!(
+                    event.data,
+                    ToolkittyEventData::Ephemeral(Payload::Json(send_payload.clone()))
+                );
+                assert_eq!(
+                    serde_json::to_value(&event).unwrap()["meta"]["contentType"],
+                    json!("json")
+                );
                 message_received = true;
                 break;
             }
@@ -641,12 +2106,13 @@ mod tests {
                 &stream_args,
                 Some(&log_path),
                 Some(&topic),
+                &PublishOptions::default(),
             )
             .await;
         assert!(result.is_ok());
 
         // We need these values so Peer B can subscribe and publish to the correct stream.
-        let (operation_id, stream_id) = result.unwrap();
+        let (operation_id, stream_id, _) = result.unwrap();
 
         let stream_args = StreamArgs {
             id: Some(stream_id),
@@ -665,6 +2131,7 @@ mod tests {
                 &stream_args,
                 Some(&log_path),
                 Some(&topic),
+                &PublishOptions::default(),
             )
             .await;
         assert!(result.is_ok());
@@ -674,7 +2141,10 @@ mod tests {
             root_hash: operation_id.into(),
             owner: peer_a_public_key.into(),
         };
-        let log_id = LogId(format!("{}/{}", stream.id(), log_path.to_string()));
+        let log_id = to_log_id(
+            stream,
+            Some(LogPath::try_from(log_path.to_string()).unwrap()),
+        );
 
         peer_a
             .add_topic_log(&peer_a_public_key, &topic, &log_id)
@@ -704,7 +2174,7 @@ mod tests {
         let mut message_received = false;
         while let Ok(event) = peer_a_rx.recv().await {
             if let ChannelEvent::Stream(ToolkittyStreamEvent {
-                data: ToolkittyEventData::Application(payload),
+                data: ToolkittyEventData::Application(Payload::Json(payload)),
                 meta: Some(ToolkittyEventMeta { author, .. }),
             }) = event
             {
@@ -722,7 +2192,7 @@ mod tests {
         let mut message_received = false;
         while let Ok(event) = peer_b_rx.recv().await {
             if let ChannelEvent::Stream(ToolkittyStreamEvent {
-                data: ToolkittyEventData::Application(payload),
+                data: ToolkittyEventData::Application(Payload::Json(payload)),
                 meta: Some(ToolkittyEventMeta { author, .. }),
             }) = event
             {
@@ -736,4 +2206,1022 @@ mod tests {
 
         assert!(message_received);
     }
+
+    #[tokio::test]
+    async fn backup_and_restore() {
+        let peer_a = Rpc {
+            context: Service::run().await,
+        };
+        let peer_b = Rpc {
+            context: Service::run().await,
+        };
+        let peer_a_public_key = peer_a.public_key().await.unwrap();
+
+        let (peer_a_tx, mut peer_a_rx) = broadcast::channel(100);
+        let (peer_b_tx, mut peer_b_rx) = broadcast::channel(100);
+        peer_a.init(peer_a_tx).await.unwrap();
+        peer_b.init(peer_b_tx).await.unwrap();
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let image_path = tmp_dir.path().join("image.png");
+        std::fs::write(&image_path, b"image").unwrap();
+        let blob_hash = peer_a.upload_file(image_path).await.unwrap();
+
+        let payload = json!({
+            "message": "organize!",
+            "image": blob_hash.to_hex(),
+        });
+        let (operation_id, _, _) = peer_a
+            .publish_persisted(
+                &serde_json::to_vec(&payload).unwrap(),
+                &StreamArgs::default(),
+                Some("messages"),
+                None,
+                &PublishOptions {
+                    blobs: vec![blob_hash].into(),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        peer_a.subscribe_persisted("messages").await.unwrap();
+
+        // Wait until the operation was persisted by the service of peer A.
+        while let Ok(event) = peer_a_rx.recv().await {
+            if let ChannelEvent::Stream(_) = event {
+                break;
+            }
+        }
+
+        let backup_path = tmp_dir.path().join("toolkitty.backup");
+        peer_a.export_backup(&backup_path, false).await.unwrap();
+
+        // Peer B keeps its own identity, no restart is required.
+        let restart_required = peer_b.import_backup(&backup_path).await.unwrap();
+        assert!(!restart_required);
+
+        let mut operation_received = false;
+        let mut subscribed = false;
+        while let Ok(event) = peer_b_rx.recv().await {
+            match event {
+                ChannelEvent::Stream(ToolkittyStreamEvent {
+                    data: ToolkittyEventData::Application(Payload::Json(value)),
+                    meta: Some(meta),
+                }) => {
+                    assert_eq!(meta.operation_id, operation_id);
+                    assert_eq!(meta.author, peer_a_public_key);
+                    assert_eq!(value, payload);
+                    operation_received = true;
+                }
+                ChannelEvent::SubscribedToTopic(topic) => {
+                    assert_eq!(topic, Topic::Persisted("messages".to_string()));
+                    subscribed = true;
+                }
+                _ => (),
+            }
+
+            if operation_received && subscribed {
+                break;
+            }
+        }
+        assert!(operation_received && subscribed);
+
+        // The operation and the blob it references were restored.
+        let context = peer_b.context.read().await;
+        assert!(context.store.has_operation(&operation_id).unwrap());
+        let mut file = context.node.read_file(blob_hash).await.unwrap().unwrap();
+        assert_eq!(
+            file.read_to_end().await.unwrap().to_vec(),
+            b"image".to_vec()
+        );
+    }
+
+    #[tokio::test]
+    async fn write_access() {
+        let owner = Rpc {
+            context: Service::run().await,
+        };
+        let peer = Rpc {
+            context: Service::run().await,
+        };
+
+        let (owner_tx, mut owner_rx) = broadcast::channel(100);
+        let (peer_tx, mut peer_rx) = broadcast::channel(100);
+        owner.init(owner_tx).await.unwrap();
+        peer.init(peer_tx).await.unwrap();
+
+        let (root_hash, stream_id, _) = owner
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "calendar_created" })).unwrap(),
+                &StreamArgs::default(),
+                Some("calendar"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        let stream_args = StreamArgs {
+            id: None,
+            root_hash: Some(root_hash),
+            owner: Some(owner.public_key().await.unwrap()),
+        };
+        owner
+            .set_write_access(
+                &stream_args,
+                Some("calendar"),
+                PrivateKey::new().public_key(),
+                WriteAccess::Grant,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let mut received = 0;
+        while let Ok(event) = owner_rx.recv().await {
+            if let ChannelEvent::Stream(_) = event {
+                received += 1;
+                if received == 2 {
+                    break;
+                }
+            }
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bundle_path = tmp_dir.path().join("stream.bundle");
+        owner
+            .export_stream(stream_id, &bundle_path, false)
+            .await
+            .unwrap();
+        peer.import_stream(&bundle_path).await.unwrap();
+
+        let mut received = 0;
+        while let Ok(event) = peer_rx.recv().await {
+            if let ChannelEvent::Stream(_) = event {
+                received += 1;
+                if received == 2 {
+                    break;
+                }
+            }
+        }
+
+        // Only granted identities may write to the restricted log path.
+        let result = peer
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "event_created" })).unwrap(),
+                &stream_args,
+                Some("calendar"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await;
+        assert!(matches!(
+            result,
+            Err(RpcError::Store(StoreError::Capability(
+                CapabilityError::Unauthorized { .. }
+            )))
+        ));
+
+        peer.publish_persisted(
+            &serde_json::to_vec(&json!({ "type": "calendar_access_requested" })).unwrap(),
+            &stream_args,
+            Some("inbox"),
+            None,
+            &PublishOptions::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn hold_back_operations_across_restarts() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let rpc = start(tmp_dir.path()).await;
+        let public_key = rpc.public_key().await.unwrap();
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+
+        let payload = serde_json::to_vec(&json!({ "type": "booking_requested" })).unwrap();
+        let (root_hash, stream_id, _) = rpc
+            .publish_persisted(
+                &payload,
+                &StreamArgs::default(),
+                Some("bookings"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        let stream_args = StreamArgs {
+            id: Some(stream_id),
+            root_hash: Some(root_hash),
+            owner: Some(public_key),
+        };
+        let depending_on = |hash: Hash| PublishOptions {
+            dependencies: Dependencies(vec![hash]),
+            ..Default::default()
+        };
+
+        // The request waits for an operation which never arrives, the comment waits for the
+        // request.
+        let (request_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &stream_args,
+                Some("bookings"),
+                None,
+                &depending_on(Hash::new(b"missing")),
+            )
+            .await
+            .unwrap();
+        let (comment_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &stream_args,
+                Some("comments"),
+                None,
+                &depending_on(request_id),
+            )
+            .await
+            .unwrap();
+        assert_eq!(delivered_operations(&mut channel_rx).await, vec![root_hash]);
+
+        // Persisted operations which were held back are not treated as delivered after a
+        // restart.
+        let rpc = restart(rpc).await;
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+        let (reply_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &stream_args,
+                Some("comments"),
+                None,
+                &depending_on(request_id),
+            )
+            .await
+            .unwrap();
+        assert!(delivered_operations(&mut channel_rx).await.is_empty());
+
+        // Pruning the request releases everything waiting for it.
+        let (prune_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &stream_args,
+                Some("bookings"),
+                None,
+                &PublishOptions {
+                    prune: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            delivered_operations(&mut channel_rx).await,
+            vec![comment_id, reply_id, prune_id]
+        );
+        let context = rpc.context.read().await;
+        assert!(context.store.pending_operations().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn accept_write_arriving_before_grant() {
+        let owner = Rpc {
+            context: Service::run().await,
+        };
+        let writer = Rpc {
+            context: Service::run().await,
+        };
+        let (owner_tx, mut owner_rx) = broadcast::channel(100);
+        let (writer_tx, mut writer_rx) = broadcast::channel(100);
+        owner.init(owner_tx).await.unwrap();
+        writer.init(writer_tx).await.unwrap();
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bundle_path = tmp_dir.path().join("stream.bundle");
+
+        let (root_hash, stream_id, _) = owner
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "calendar_created" })).unwrap(),
+                &StreamArgs::default(),
+                Some("calendar"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        let stream_args = StreamArgs {
+            id: None,
+            root_hash: Some(root_hash),
+            owner: Some(owner.public_key().await.unwrap()),
+        };
+        owner
+            .export_stream(stream_id, &bundle_path, false)
+            .await
+            .unwrap();
+        writer.import_stream(&bundle_path).await.unwrap();
+        delivered_operations(&mut writer_rx).await;
+
+        // The writer publishes while the log path is still open.
+        let (operation_id, _, _) = writer
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "event_created" })).unwrap(),
+                &stream_args,
+                Some("calendar"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // The owner restricts the log path before the write arrives and rejects it.
+        owner
+            .set_write_access(
+                &stream_args,
+                Some("calendar"),
+                PrivateKey::new().public_key(),
+                WriteAccess::Grant,
+                None,
+            )
+            .await
+            .unwrap();
+        writer
+            .export_stream(stream_id, &bundle_path, false)
+            .await
+            .unwrap();
+        owner.import_stream(&bundle_path).await.unwrap();
+        delivered_operations(&mut owner_rx).await;
+        {
+            let context = owner.context.read().await;
+            assert!(!context.store.has_operation(&operation_id).unwrap());
+            assert!(context.store.is_rejected(&operation_id).unwrap());
+        }
+
+        // Granting access later accepts the write after all.
+        owner
+            .set_write_access(
+                &stream_args,
+                Some("calendar"),
+                writer.public_key().await.unwrap(),
+                WriteAccess::Grant,
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(delivered_operations(&mut owner_rx)
+            .await
+            .contains(&operation_id));
+        let context = owner.context.read().await;
+        assert!(context.store.has_operation(&operation_id).unwrap());
+        assert!(!context.store.is_rejected(&operation_id).unwrap());
+    }
+
+    #[tokio::test]
+    async fn unsupported_schema_version() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let (channel_tx, mut channel_rx) = broadcast::channel(100);
+        rpc.init(channel_tx).await.unwrap();
+
+        let versions = SchemaVersions {
+            min: None,
+            max: Some(1.into()),
+        };
+        rpc.set_schema_versions(&versions).await.unwrap();
+        assert_eq!(rpc.schema_versions().await.unwrap(), versions);
+        assert!(matches!(
+            rpc.set_schema_versions(&SchemaVersions {
+                min: Some(2.into()),
+                max: Some(1.into()),
+            })
+            .await,
+            Err(RpcError::Schema(SchemaError::InvalidRange { .. }))
+        ));
+
+        for version in [1, 2] {
+            rpc.publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "event_created" })).unwrap(),
+                &StreamArgs::default(),
+                Some("calendar"),
+                None,
+                &PublishOptions {
+                    schema_version: Some(version.into()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        }
+
+        let mut events = Vec::new();
+        while let Ok(event) = channel_rx.recv().await {
+            if let ChannelEvent::Stream(event) = event {
+                events.push(event);
+                if events.len() == 2 {
+                    break;
+                }
+            }
+        }
+
+        assert_eq!(events[0].meta.as_ref().unwrap().schema_version, 1.into());
+        assert!(matches!(events[0].data, ToolkittyEventData::Application(_)));
+        assert_eq!(events[1].meta.as_ref().unwrap().schema_version, 2.into());
+        assert_eq!(
+            events[1].data,
+            ToolkittyEventData::UnsupportedSchema(versions)
+        );
+    }
+
+    #[tokio::test]
+    async fn publish_binary_payload() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let (channel_tx, mut channel_rx) = broadcast::channel(100);
+        rpc.init(channel_tx).await.unwrap();
+
+        let update = vec![0, 159, 146, 150];
+        rpc.publish_persisted(
+            &update,
+            &StreamArgs::default(),
+            Some("crdt"),
+            None,
+            &PublishOptions {
+                content_type: ContentType::Binary,
+                ..Default::default()
+            },
+        )
+        .await
+        .unwrap();
+
+        while let Ok(event) = channel_rx.recv().await {
+            if let ChannelEvent::Stream(event) = event {
+                assert_eq!(event.meta.unwrap().content_type, ContentType::Binary);
+                assert_eq!(
+                    event.data,
+                    ToolkittyEventData::Application(Payload::Binary(update))
+                );
+                break;
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn prune_log() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let public_key = rpc.public_key().await.unwrap();
+        let (channel_tx, mut channel_rx) = broadcast::channel(100);
+        rpc.init(channel_tx).await.unwrap();
+
+        let mut stream_args = StreamArgs::default();
+        let mut operation_ids = Vec::new();
+        for prune in [false, false, true] {
+            let (operation_id, stream_id, _) = rpc
+                .publish_persisted(
+                    &serde_json::to_vec(&json!({ "type": "calendar_updated" })).unwrap(),
+                    &stream_args,
+                    Some("calendar"),
+                    None,
+                    &PublishOptions {
+                        prune,
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            stream_args.id.get_or_insert(stream_id);
+            stream_args.root_hash.get_or_insert(operation_id);
+            stream_args.owner = Some(public_key);
+            operation_ids.push(operation_id);
+        }
+
+        // Wait until the prune point was processed by the service.
+        while let Ok(event) = channel_rx.recv().await {
+            if let ChannelEvent::Stream(event) = event {
+                if event.meta.unwrap().operation_id == operation_ids[2] {
+                    break;
+                }
+            }
+        }
+
+        // Earlier operations in the log are gone from both stores.
+        let context = rpc.context.read().await;
+        for operation_id in &operation_ids[..2] {
+            assert!(!context.store.has_operation(operation_id).unwrap());
+            assert!(!context
+                .node
+                .store
+                .has_operation(*operation_id)
+                .await
+                .unwrap());
+        }
+        assert!(context.store.has_operation(&operation_ids[2]).unwrap());
+        assert!(context
+            .node
+            .store
+            .has_operation(operation_ids[2])
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn expired_operations() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let (channel_tx, mut channel_rx) = broadcast::channel(100);
+        rpc.init(channel_tx).await.unwrap();
+
+        let mut operation_ids = Vec::new();
+        for expires_at in [1, u64::MAX] {
+            let (operation_id, _, _) = rpc
+                .publish_persisted(
+                    &serde_json::to_vec(&json!({ "type": "booking_requested" })).unwrap(),
+                    &StreamArgs::default(),
+                    Some("bookings"),
+                    None,
+                    &PublishOptions {
+                        expires_at: Some(expires_at.into()),
+                        ..Default::default()
+                    },
+                )
+                .await
+                .unwrap();
+            operation_ids.push(operation_id);
+        }
+
+        // Expired operations are not delivered.
+        while let Ok(event) = channel_rx.recv().await {
+            if let ChannelEvent::Stream(event) = event {
+                assert_eq!(event.meta.unwrap().operation_id, operation_ids[1]);
+                break;
+            }
+        }
+
+        // Only the payload of the expired operation is gone, its header stays in the log.
+        let context = rpc.context.read().await;
+        let payloads = context.store.payloads().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].header.hash(), operation_ids[1]);
+        assert!(context.store.has_operation(&operation_ids[0]).unwrap());
+    }
+
+    #[tokio::test]
+    async fn publish_after_expired_operation() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let public_key = rpc.public_key().await.unwrap();
+
+        let (root_hash, stream_id, _) = rpc
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "booking_requested" })).unwrap(),
+                &StreamArgs::default(),
+                Some("bookings"),
+                None,
+                &PublishOptions {
+                    expires_at: Some(1.into()),
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(rpc.purge_expired().await.unwrap(), vec![root_hash]);
+
+        // Our latest operation expired, the next one still continues the log after it.
+        let (operation_id, _, _) = rpc
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "type": "booking_requested" })).unwrap(),
+                &StreamArgs {
+                    id: Some(stream_id),
+                    root_hash: Some(root_hash),
+                    owner: Some(public_key),
+                },
+                Some("bookings"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        let context = rpc.context.read().await;
+        let operations = context.store.operations().unwrap();
+        assert_eq!(operations.len(), 2);
+        let (header, body, _) = &operations[0];
+        assert_eq!(header.hash(), root_hash);
+        assert!(body.is_none());
+        let (header, body, _) = &operations[1];
+        assert_eq!(header.hash(), operation_id);
+        assert_eq!(header.seq_num, 1);
+        assert_eq!(header.backlink, Some(root_hash));
+        assert!(body.is_some());
+    }
+
+    #[tokio::test]
+    async fn export_and_import_stream() {
+        let peer_a = Rpc {
+            context: Service::run().await,
+        };
+        let peer_b = Rpc {
+            context: Service::run().await,
+        };
+
+        let (peer_a_tx, mut peer_a_rx) = broadcast::channel(100);
+        let (peer_b_tx, mut peer_b_rx) = broadcast::channel(100);
+        peer_a.init(peer_a_tx).await.unwrap();
+        peer_b.init(peer_b_tx).await.unwrap();
+
+        let payload = json!({
+            "message": "organize!"
+        });
+        let (operation_id, stream_id, _) = peer_a
+            .publish_persisted(
+                &serde_json::to_vec(&payload).unwrap(),
+                &StreamArgs::default(),
+                Some("messages"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        // Wait until the operation was persisted by the service of peer A.
+        while let Ok(event) = peer_a_rx.recv().await {
+            if let ChannelEvent::Stream(_) = event {
+                break;
+            }
+        }
+
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let bundle_path = tmp_dir.path().join("stream.bundle");
+        peer_a
+            .export_stream(stream_id, &bundle_path, true)
+            .await
+            .unwrap();
+
+        let imported_stream_id = peer_b.import_stream(&bundle_path).await.unwrap();
+        assert_eq!(imported_stream_id, stream_id);
+
+        let mut operation_received = false;
+        while let Ok(event) = peer_b_rx.recv().await {
+            if let ChannelEvent::Stream(ToolkittyStreamEvent {
+                data: ToolkittyEventData::Application(Payload::Json(value)),
+                meta: Some(meta),
+            }) = event
+            {
+                assert_eq!(meta.operation_id, operation_id);
+                assert_eq!(meta.stream.id, stream_id);
+                assert_eq!(value, payload);
+                operation_received = true;
+                break;
+            }
+        }
+
+        assert!(operation_received);
+    }
+
+    #[tokio::test]
+    async fn flush_outbox() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let (tx, mut rx) = broadcast::channel(100);
+        rpc.init(tx).await.unwrap();
+
+        // Published operations are removed from the outbox.
+        let (_, _, status) = rpc
+            .publish_persisted(
+                &serde_json::to_vec(&json!({ "message": "organize!" })).unwrap(),
+                &StreamArgs::default(),
+                Some("messages"),
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(status, PublishStatus::Published);
+        assert!(rpc.outbox().await.unwrap().is_empty());
+
+        // Simulate the app being closed after creating an operation but before publishing it.
+        let payload = json!({ "message": "organize more!" });
+        let operation_id = {
+            let mut context = rpc.context.write().await;
+            let private_key = context.node.private_key.clone();
+            let (header, body) = create_operation(
+                &mut context.node.store,
+                &private_key,
+                None,
+                Some(Extensions::default()),
+                Some(&serde_json::to_vec(&payload).unwrap()),
+            )
+            .await;
+            let log_id: LogId = header.extension().unwrap();
+            context
+                .store
+                .insert_outbox(&header, body.as_ref(), &log_id, None)
+                .unwrap();
+            header.hash()
+        };
+
+        let entries = rpc.outbox().await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation_id, operation_id);
+
+        assert_eq!(rpc.flush_outbox().await.unwrap(), 0);
+        assert!(rpc.outbox().await.unwrap().is_empty());
+
+        while let Ok(event) = rx.recv().await {
+            if let ChannelEvent::Stream(ToolkittyStreamEvent {
+                data: ToolkittyEventData::Application(Payload::Json(value)),
+                meta: Some(meta),
+            }) = event
+            {
+                if meta.operation_id == operation_id {
+                    assert_eq!(value, payload);
+                    break;
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn set_key_store() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        let (private_key, app_data_dir) = {
+            let context = rpc.context.read().await;
+            (
+                context.node.private_key.clone(),
+                context.app_data_dir.clone(),
+            )
+        };
+        let private_key_path = app_data_dir.join(PRIVATE_KEY_FILE_NAME);
+        let encrypted_private_key_path = app_data_dir.join(ENCRYPTED_PRIVATE_KEY_FILE_NAME);
+
+        rpc.set_key_store(KeyStoreBackend::File, None)
+            .await
+            .unwrap();
+        assert!(private_key_path.exists());
+
+        // The in-memory key store can't be selected, the key file stays in place.
+        assert!(rpc
+            .set_key_store(KeyStoreBackend::Memory, None)
+            .await
+            .is_err());
+        assert!(private_key_path.exists());
+
+        // Encrypting the key removes the plain text key file.
+        rpc.set_key_store(KeyStoreBackend::EncryptedFile, Some("organize!"))
+            .await
+            .unwrap();
+        assert!(!private_key_path.exists());
+        let loaded_private_key =
+            EncryptedFileKeyStore::new(encrypted_private_key_path.clone(), "organize!")
+                .load()
+                .unwrap();
+        assert_eq!(loaded_private_key.as_bytes(), private_key.as_bytes());
+
+        // Changing the passphrase keeps the encrypted key file.
+        rpc.set_key_store(KeyStoreBackend::EncryptedFile, Some("organize more!"))
+            .await
+            .unwrap();
+        assert!(encrypted_private_key_path.exists());
+
+        rpc.set_key_store(KeyStoreBackend::File, None)
+            .await
+            .unwrap();
+        assert!(!encrypted_private_key_path.exists());
+        assert_eq!(
+            FileKeyStore::new(private_key_path)
+                .load()
+                .unwrap()
+                .as_bytes(),
+            private_key.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn switch_profiles() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let profiles = Profiles::open(tmp_dir.path().to_path_buf()).unwrap();
+        let unlocker = Unlocker::default();
+        let mut active_profile_rx = profiles.subscribe();
+
+        // Run the service of the active profile until another profile is chosen, like the
+        // service task does.
+        let run = |mut app: Service,
+                   channel: Option<broadcast::Sender<ChannelEvent>>,
+                   mut active_profile_rx: watch::Receiver<Profile>| {
+            tokio::spawn(async move {
+                let channel = match channel {
+                    Some(channel) => {
+                        app.context.write().await.channel_set = true;
+                        channel
+                    }
+                    None => app.recv_channel().await.unwrap(),
+                };
+                let profile_changed = async move {
+                    let _ = active_profile_rx.changed().await;
+                };
+                app.inner_run(channel, profile_changed).await.unwrap()
+            })
+        };
+
+        let profile = active_profile_rx.borrow_and_update().clone();
+        assert_eq!(profile.id, DEFAULT_PROFILE_ID);
+        let app = Service::open_profile(profiles.dir(&profile.id), &unlocker)
+            .await
+            .unwrap();
+        let context = app.context.clone();
+        let rpc = Rpc {
+            context: context.clone(),
+        };
+        let service = run(app, None, active_profile_rx.clone());
+
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+        let default_public_key = rpc.public_key().await.unwrap();
+        let payload = serde_json::to_vec(&json!({ "message": "organize!" })).unwrap();
+        let (operation_id, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &StreamArgs::default(),
+                None,
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            delivered_operations(&mut channel_rx).await,
+            vec![operation_id]
+        );
+
+        // The service of the new profile takes over the shared context and the channel.
+        let food_coop = profiles.create("Food coop").unwrap();
+        profiles.switch(&food_coop.id).unwrap();
+        let channel = service.await.unwrap();
+        Service::shutdown(&context).await;
+        active_profile_rx.borrow_and_update();
+        let mut app = Service::open_profile(profiles.dir(&food_coop.id), &unlocker)
+            .await
+            .unwrap();
+        app.share_context(&context).await;
+        let service = run(app, Some(channel), active_profile_rx.clone());
+
+        let food_coop_public_key = rpc.public_key().await.unwrap();
+        assert_ne!(food_coop_public_key, default_public_key);
+        assert!(!context
+            .read()
+            .await
+            .store
+            .has_operation(&operation_id)
+            .unwrap());
+
+        let (operation_id_food_coop, _, _) = rpc
+            .publish_persisted(
+                &payload,
+                &StreamArgs::default(),
+                None,
+                None,
+                &PublishOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            delivered_operations(&mut channel_rx).await,
+            vec![operation_id_food_coop]
+        );
+
+        // Switching back restores the identity and data of the default profile.
+        profiles.switch(DEFAULT_PROFILE_ID).unwrap();
+        let channel = service.await.unwrap();
+        Service::shutdown(&context).await;
+        active_profile_rx.borrow_and_update();
+        let mut app = Service::open_profile(profiles.dir(DEFAULT_PROFILE_ID), &unlocker)
+            .await
+            .unwrap();
+        app.share_context(&context).await;
+        let _service = run(app, Some(channel), active_profile_rx.clone());
+
+        assert_eq!(rpc.public_key().await.unwrap(), default_public_key);
+        let context = context.read().await;
+        assert!(context.store.has_operation(&operation_id).unwrap());
+        assert!(!context
+            .store
+            .has_operation(&operation_id_food_coop)
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn restore_mnemonic() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+
+        // Restoring the current identity is a no-op.
+        let words = rpc.export_mnemonic().await.unwrap();
+        assert!(!rpc.restore_mnemonic(&words).await.unwrap());
+
+        // Another identity is written to the key store and requires a restart.
+        let private_key = PrivateKey::new();
+        assert!(rpc
+            .restore_mnemonic(&to_mnemonic(&private_key))
+            .await
+            .unwrap());
+        let context = rpc.context.read().await;
+        assert_eq!(
+            context.key_store.load().unwrap().as_bytes(),
+            private_key.as_bytes()
+        );
+    }
+
+    #[tokio::test]
+    async fn rotate_key() {
+        let rpc = Rpc {
+            context: Service::run().await,
+        };
+        rpc.subscribe_persisted("calendar").await.unwrap();
+        rpc.subscribe_persisted("bookings").await.unwrap();
+        let previous_public_key = rpc.public_key().await.unwrap();
+        let (channel_tx, mut channel_rx) = broadcast::channel(10);
+        rpc.init(channel_tx).await.unwrap();
+
+        let next_public_key = rpc.rotate_key().await.unwrap();
+        assert_ne!(next_public_key, previous_public_key);
+
+        // The succession is not delivered to the frontend as an application event.
+        assert!(delivered_operations(&mut channel_rx).await.is_empty());
+
+        let context = rpc.context.read().await;
+        assert_eq!(
+            context.key_store.load().unwrap().public_key(),
+            next_public_key
+        );
+        assert_eq!(
+            context.store.identity_of(&next_public_key).unwrap(),
+            previous_public_key
+        );
+
+        // One succession is synced in our identity log on all topics we're subscribed to.
+        let identity_log_id = to_log_id(
+            Stream::identity(previous_public_key),
+            Some(LogPath::try_from(IDENTITY_LOG_PATH.to_string()).unwrap()),
+        );
+        for topic in ["calendar", "bookings"] {
+            let logs = context
+                .topic_map
+                .get(&Topic::Persisted(topic.to_string()))
+                .await
+                .unwrap();
+            assert_eq!(
+                logs.get(&previous_public_key).unwrap(),
+                &vec![identity_log_id.clone()]
+            );
+        }
+        let records = context
+            .store
+            .operations()
+            .unwrap()
+            .into_iter()
+            .filter(|(header, ..)| is_identity_record(header))
+            .count();
+        assert_eq!(records, 1);
+    }
+
+    #[tokio::test]
+    async fn delegate_device() {
+        let identity = Rpc {
+            context: Service::run().await,
+        };
+        let device = Rpc {
+            context: Service::run().await,
+        };
+        let identity_public_key = identity.public_key().await.unwrap();
+        let device_public_key = device.public_key().await.unwrap();
+
+        let request = device
+            .request_delegation(identity_public_key)
+            .await
+            .unwrap();
+        assert!(matches!(
+            device.delegate_device(request.clone()).await,
+            Err(RpcError::Delegation(DelegationError::IdentityMismatch))
+        ));
+        identity.delegate_device(request).await.unwrap();
+
+        let context = identity.context.read().await;
+        assert_eq!(
+            context.store.identity_of(&device_public_key).unwrap(),
+            identity_public_key
+        );
+        assert!(context
+            .store
+            .identity_keys(&identity_public_key)
+            .unwrap()
+            .contains(&device_public_key));
+    }
 }
@@ -0,0 +1,405 @@
+//! Backup archives of a whole node.
+//!
+//! A backup contains all operations, the topic log map, our subscriptions, all blobs referenced by
+//! operations and optionally the private key. It is written as one CBOR encoded file which can be
+//! imported on another device to move an installation there.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use iroh_io::AsyncSliceReaderExt;
+use p2panda_core::cbor::{decode_cbor, encode_cbor, DecodeError, EncodeError};
+use p2panda_core::{
+    validate_operation, Body, Hash, Header, Operation, OperationError, PrivateKey, PublicKey,
+};
+use p2panda_node::extensions::LogId;
+use p2panda_node::node::Node;
+use p2panda_node::topic::Topic;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::app::Context;
+use crate::extensions::{legacy_log_id, to_log_id, Blobs, Extensions, LogPath, Stream};
+use crate::store::{SqliteStore, StoreError};
+
+/// Version of the backup archive format written by this version of the app.
+///
+/// Version 2 encodes the log ids of topic logs with `to_log_id`, older backups are migrated when
+/// they are read.
+pub const BACKUP_VERSION: u32 = 2;
+
+/// File name of a backup which was staged for import on the next start.
+pub const STAGED_BACKUP_FILE_NAME: &str = "restore.backup";
+
+/// Signed operation header and optional body in their encoded form.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EncodedOperation {
+    #[serde(with = "serde_bytes")]
+    pub header: Vec<u8>,
+
+    #[serde(with = "serde_bytes")]
+    pub body: Option<Vec<u8>>,
+}
+
+impl EncodedOperation {
+    pub fn new(header: &Header<Extensions>, body: Option<&Body>) -> Self {
+        Self {
+            header: header.to_bytes(),
+            body: body.map(|body| body.to_bytes()),
+        }
+    }
+
+    /// Decode the operation and check that it is correctly signed and the body matches the
+    /// header.
+    pub fn decode_and_verify(&self) -> Result<(Header<Extensions>, Option<Body>), BackupError> {
+        let header: Header<Extensions> = decode_cbor(&self.header[..])?;
+        let body = self.body.as_ref().map(|bytes| Body::new(bytes));
+
+        let operation = Operation {
+            hash: header.hash(),
+            header,
+            body,
+        };
+        validate_operation(&operation)?;
+
+        Ok((operation.header, operation.body))
+    }
+}
+
+/// Content of a blob referenced by an operation.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EncodedBlob {
+    pub hash: Hash,
+
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TopicLog {
+    pub topic: String,
+    pub public_key: PublicKey,
+    pub log_id: LogId,
+}
+
+/// Archive of everything a node needs to be restored on another device.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Backup {
+    pub version: u32,
+
+    /// Hex-encoded private key of the node, only present if it was explicitly included.
+    pub private_key: Option<String>,
+
+    /// All operations, ordered by author, log and sequence number.
+    pub operations: Vec<EncodedOperation>,
+
+    pub topic_logs: Vec<TopicLog>,
+
+    /// Persisted topics the node was subscribed to.
+    pub subscriptions: Vec<String>,
+
+    pub blobs: Vec<EncodedBlob>,
+}
+
+impl Backup {
+    /// Collect all data of the node into a backup.
+    pub async fn export(context: &Context, include_private_key: bool) -> Result<Self, BackupError> {
+        let mut operations = Vec::new();
+        let mut blob_hashes = HashSet::new();
+        for (header, body, _) in context.store.operations()? {
+            blob_hashes.extend(referenced_blobs(&header));
+            operations.push(EncodedOperation::new(&header, body.as_ref()));
+        }
+
+        let blobs = read_blobs(&context.node, blob_hashes).await?;
+
+        let topic_logs = context
+            .store
+            .topic_logs()?
+            .into_iter()
+            .map(|(topic, public_key, log_id)| TopicLog {
+                topic,
+                public_key,
+                log_id,
+            })
+            .collect();
+
+        Ok(Self {
+            version: BACKUP_VERSION,
+            private_key: include_private_key.then(|| context.node.private_key.to_hex()),
+            operations,
+            topic_logs,
+            subscriptions: context.store.subscriptions()?,
+            blobs,
+        })
+    }
+
+    /// Read and verify a backup file.
+    pub fn read(path: &Path) -> Result<Self, BackupError> {
+        let mut backup: Self = decode_cbor(fs::File::open(path)?)?;
+        if backup.version > BACKUP_VERSION {
+            return Err(BackupError::UnsupportedVersion(backup.version));
+        }
+        backup.verify()?;
+        if backup.version < 2 {
+            backup.migrate_log_ids()?;
+        }
+        Ok(backup)
+    }
+
+    /// Rewrite the log ids of topic logs from the `<stream_id>/<log_path>` format of version 1
+    /// backups to the current encoding. Like in `SqliteStore::migrate_log_ids` only logs with
+    /// operations in the backup can be mapped, the remaining ones are removed and logged.
+    fn migrate_log_ids(&mut self) -> Result<(), BackupError> {
+        let mut log_ids = HashMap::new();
+        for operation in &self.operations {
+            let header: Header<Extensions> = decode_cbor(&operation.header[..])?;
+            // Operations in an archive are signed but not necessarily complete.
+            let Some(stream) = header.extension::<Stream>() else {
+                continue;
+            };
+            let log_path: Option<LogPath> = header.extension();
+            log_ids.insert(
+                legacy_log_id(&stream, log_path.as_ref()).0,
+                to_log_id(stream, log_path),
+            );
+        }
+
+        self.topic_logs
+            .retain_mut(|topic_log| match log_ids.get(&topic_log.log_id.0) {
+                Some(log_id) => {
+                    topic_log.log_id = log_id.clone();
+                    true
+                }
+                None => {
+                    warn!(
+                        "removing log {} without operations from topic {}",
+                        topic_log.log_id.0, topic_log.topic
+                    );
+                    false
+                }
+            });
+        self.version = BACKUP_VERSION;
+        Ok(())
+    }
+
+    /// Write the backup to a file.
+    pub fn write(&self, path: &Path) -> Result<(), BackupError> {
+        fs::write(path, encode_cbor(self)?)?;
+        Ok(())
+    }
+
+    /// Check the signatures of all operations and the hashes of all blobs.
+    pub fn verify(&self) -> Result<(), BackupError> {
+        for operation in &self.operations {
+            operation.decode_and_verify()?;
+        }
+
+        for blob in &self.blobs {
+            if Hash::new(&blob.bytes) != blob.hash {
+                return Err(BackupError::InvalidBlob(blob.hash));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Read all given blobs we have locally, blobs we don't have are skipped.
+pub async fn read_blobs(
+    node: &Node<Topic, LogId, Extensions>,
+    hashes: impl IntoIterator<Item = Hash>,
+) -> Result<Vec<EncodedBlob>, BackupError> {
+    let mut blobs = Vec::new();
+    for hash in hashes {
+        let Some(mut file) = node
+            .read_file(hash)
+            .await
+            .map_err(|err| BackupError::Blob(err.to_string()))?
+        else {
+            continue;
+        };
+        let bytes = file.read_to_end().await?;
+        blobs.push(EncodedBlob {
+            hash,
+            bytes: bytes.to_vec(),
+        });
+    }
+    Ok(blobs)
+}
+
+/// Add blobs to the local blob store, checking that their content matches their hash.
+pub async fn write_blobs(
+    node: &Node<Topic, LogId, Extensions>,
+    store: &SqliteStore,
+    blobs: &[EncodedBlob],
+) -> Result<(), BackupError> {
+    for blob in blobs {
+        let mut file = tempfile::NamedTempFile::new()?;
+        file.write_all(&blob.bytes)?;
+        let hash = node
+            .upload_file(file.path().to_path_buf())
+            .await
+            .map_err(|err| BackupError::Blob(err.to_string()))?;
+        if hash != blob.hash {
+            return Err(BackupError::InvalidBlob(blob.hash));
+        }
+        store.record_blob(&hash, blob.bytes.len() as u64, false)?;
+    }
+    Ok(())
+}
+
+/// Decode a hex-encoded private key as it is stored in backups.
+pub fn decode_private_key(value: &str) -> Result<PrivateKey, BackupError> {
+    let bytes =
+        hex::decode(value).map_err(|err| BackupError::InvalidPrivateKey(err.to_string()))?;
+    let bytes: [u8; 32] = bytes
+        .as_slice()
+        .try_into()
+        .map_err(|_| BackupError::InvalidPrivateKey("invalid length".to_string()))?;
+    Ok(PrivateKey::from_bytes(&bytes))
+}
+
+/// Blobs the author of an operation declared as referenced by its payload.
+pub fn referenced_blobs(header: &Header<Extensions>) -> Vec<Hash> {
+    let blobs: Blobs = header.extension().unwrap_or_default();
+    blobs.0
+}
+
+#[derive(Debug, Error)]
+pub enum BackupError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("encoding backup failed: {0}")]
+    Encode(#[from] EncodeError),
+
+    #[error("decoding backup failed: {0}")]
+    Decode(#[from] DecodeError),
+
+    #[error("invalid operation in backup: {0}")]
+    InvalidOperation(#[from] OperationError),
+
+    #[error("blob {0} does not match its hash")]
+    InvalidBlob(Hash),
+
+    #[error("accessing blob store failed: {0}")]
+    Blob(String),
+
+    #[error("backup version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error("invalid private key in backup: {0}")]
+    InvalidPrivateKey(String),
+
+    #[error("refusing to replace local identity which already authored operations")]
+    IdentityInUse,
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Header, PrivateKey};
+    use p2panda_node::extensions::LogId;
+    use serde_json::json;
+
+    use crate::extensions::{legacy_log_id, to_log_id, Extensions, Stream};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{referenced_blobs, Backup, EncodedOperation, TopicLog, BACKUP_VERSION};
+
+    fn create_operation(private_key: &PrivateKey, body: &Body) -> EncodedOperation {
+        let header = create_header(private_key, body, with_extensions(Extensions::default()));
+        EncodedOperation::new(&header, Some(body))
+    }
+
+    #[test]
+    fn verify_operations() {
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let operation = create_operation(&private_key, &body);
+
+        let mut backup = Backup {
+            version: BACKUP_VERSION,
+            private_key: None,
+            operations: vec![operation.clone()],
+            topic_logs: vec![],
+            subscriptions: vec![],
+            blobs: vec![],
+        };
+        assert!(backup.verify().is_ok());
+
+        // Swapping the body invalidates the operation.
+        backup.operations[0].body = Some(b"disorganize!".to_vec());
+        assert!(backup.verify().is_err());
+    }
+
+    #[test]
+    fn find_referenced_blobs() {
+        let private_key = PrivateKey::new();
+        let hash = Body::new(b"image").hash();
+
+        // Hashes in the payload are not taken for blob references, only the declared ones are.
+        let body = Body::new(
+            &serde_json::to_vec(&json!({
+                "type": "event_created",
+                "data": { "name": "Party", "images": [hash.to_hex()] }
+            }))
+            .unwrap(),
+        );
+        let header = create_header(&private_key, &body, with_extensions(Extensions::default()));
+        assert!(referenced_blobs(&header).is_empty());
+
+        let header = create_header(
+            &private_key,
+            &body,
+            with_extensions(Extensions {
+                blobs: vec![hash].into(),
+                ..Default::default()
+            }),
+        );
+        assert_eq!(referenced_blobs(&header), vec![hash]);
+    }
+
+    #[test]
+    fn migrate_version_1_backups() {
+        let private_key = PrivateKey::new();
+        let operation = create_operation(&private_key, &Body::new(b"organize!"));
+        let (header, _) = operation.decode_and_verify().unwrap();
+        let stream: Stream = header.extension().unwrap();
+
+        let topic_log = |log_id: LogId| TopicLog {
+            topic: "messages".to_string(),
+            public_key: private_key.public_key(),
+            log_id,
+        };
+        // Signed operations without extensions are skipped.
+        let body = Body::new(b"incomplete");
+        let incomplete = create_header(&private_key, &body, Header::default());
+
+        let backup = Backup {
+            version: 1,
+            private_key: None,
+            operations: vec![operation, EncodedOperation::new(&incomplete, Some(&body))],
+            topic_logs: vec![
+                topic_log(legacy_log_id(&stream, None)),
+                topic_log(LogId("unknown/".to_string())),
+            ],
+            subscriptions: vec![],
+            blobs: vec![],
+        };
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("toolkitty.backup");
+        backup.write(&path).unwrap();
+
+        // Logs without operations in the backup can't be mapped and are removed.
+        let backup = Backup::read(&path).unwrap();
+        assert_eq!(backup.version, BACKUP_VERSION);
+        assert_eq!(backup.topic_logs, vec![topic_log(to_log_id(stream, None))]);
+    }
+}
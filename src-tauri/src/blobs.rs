@@ -12,6 +12,8 @@ use tokio::task::LocalSet;
 use tokio::time::timeout;
 
 use crate::app::{Context, Rpc};
+use crate::gc::StorageQuota;
+use crate::store::{SqliteStore, StoreError};
 
 /// blobstore://<hash>
 pub const BLOBSTORE_URI_SCHEME: &str = "blobstore";
@@ -107,9 +109,19 @@ async fn handle_request(
     match result {
         // We have the blob locally on our machine, forward it to the frontend.
         Ok(Some(mut file)) => match file.read_to_end().await {
-            Ok(file_bytes) => Response::builder()
-                .status(StatusCode::OK)
-                .body(file_bytes.to_vec()),
+            Ok(file_bytes) => {
+                // Account for the blob so it counts towards the storage quota.
+                if let Err(err) =
+                    context_read
+                        .store
+                        .record_blob(&blob_hash, file_bytes.len() as u64, false)
+                {
+                    return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string());
+                }
+                Response::builder()
+                    .status(StatusCode::OK)
+                    .body(file_bytes.to_vec())
+            }
             Err(err) => error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
         },
         // We don't have the blob, try to sync it from another peer first.
@@ -127,6 +139,19 @@ async fn handle_request(
 
 async fn try_lazy_sync(context: Arc<RwLock<Context>>, blob_hash: Hash) -> ResponseResult {
     let context_read = context.read().await;
+
+    // Don't download any more blobs from other peers when the storage quota is exhausted.
+    match quota_exhausted(&context_read.store) {
+        Ok(false) => (),
+        Ok(true) => {
+            return error(
+                StatusCode::INSUFFICIENT_STORAGE,
+                "storage quota exhausted".to_string(),
+            )
+        }
+        Err(err) => return error(StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+    }
+
     let result = timeout(SYNC_TIMEOUT, context_read.node.sync_remote_file(blob_hash)).await;
     match result {
         // Sync succeeded, we have the file! Continue handling request and respond with our now
@@ -148,6 +173,11 @@ async fn try_lazy_sync(context: Arc<RwLock<Context>>, blob_hash: Hash) -> Respon
     }
 }
 
+fn quota_exhausted(store: &SqliteStore) -> Result<bool, StoreError> {
+    let quota = StorageQuota::load(store)?;
+    Ok(quota.exceeds_global(store.payload_usage()? + store.blob_usage()?))
+}
+
 fn error(status_code: StatusCode, message: String) -> ResponseResult {
     Response::builder()
         .status(status_code)
@@ -0,0 +1,189 @@
+//! Offline bundles of a single stream.
+//!
+//! A bundle contains every log of a stream as signed operations and optionally all blobs they
+//! reference. Bundles can be carried to another device, for example on a USB stick, where they
+//! are ingested into the node as if the operations were received via sync.
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::Path;
+
+use p2panda_core::cbor::{decode_cbor, encode_cbor, DecodeError, EncodeError};
+use p2panda_core::{Body, Hash, Header};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::app::Context;
+use crate::backup::{read_blobs, referenced_blobs, BackupError, EncodedBlob, EncodedOperation};
+use crate::extensions::{Extensions, Stream};
+use crate::store::StoreError;
+
+/// Version of the bundle format written by this version of the app.
+pub const BUNDLE_VERSION: u32 = 1;
+
+/// Self-describing archive of all operations in one stream.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StreamBundle {
+    pub version: u32,
+
+    /// The stream all operations in this bundle belong to.
+    pub stream: Stream,
+
+    /// All operations of the stream, ordered by author, log and sequence number.
+    pub operations: Vec<EncodedOperation>,
+
+    pub blobs: Vec<EncodedBlob>,
+}
+
+impl StreamBundle {
+    /// Collect all operations of the stream with the given id into a bundle.
+    pub async fn export(
+        context: &Context,
+        stream_id: Hash,
+        include_blobs: bool,
+    ) -> Result<Self, BundleError> {
+        let mut stream = None;
+        let mut operations = Vec::new();
+        let mut blob_hashes = HashSet::new();
+        for (header, body, _) in context.store.operations()? {
+            let operation_stream: Stream = header.extension().expect("extract stream extension");
+            if operation_stream.id() != stream_id {
+                continue;
+            }
+
+            if include_blobs {
+                blob_hashes.extend(referenced_blobs(&header));
+            }
+            operations.push(EncodedOperation::new(&header, body.as_ref()));
+            stream = Some(operation_stream);
+        }
+
+        let Some(stream) = stream else {
+            return Err(BundleError::UnknownStream(stream_id));
+        };
+
+        let blobs = read_blobs(&context.node, blob_hashes).await?;
+
+        Ok(Self {
+            version: BUNDLE_VERSION,
+            stream,
+            operations,
+            blobs,
+        })
+    }
+
+    /// Read a bundle from a file. The operations in it are checked when they are decoded with
+    /// `verify`.
+    pub fn read(path: &Path) -> Result<Self, BundleError> {
+        let bundle: Self = decode_cbor(fs::File::open(path)?)?;
+        if bundle.version > BUNDLE_VERSION {
+            return Err(BundleError::UnsupportedVersion(bundle.version));
+        }
+        Ok(bundle)
+    }
+
+    /// Write the bundle to a file.
+    pub fn write(&self, path: &Path) -> Result<(), BundleError> {
+        fs::write(path, encode_cbor(self)?)?;
+        Ok(())
+    }
+
+    /// Decode all operations, checking their signatures and that they belong to the bundled
+    /// stream.
+    pub fn verify(&self) -> Result<Vec<(Header<Extensions>, Option<Body>)>, BundleError> {
+        let mut operations = Vec::with_capacity(self.operations.len());
+        for operation in &self.operations {
+            let (header, body) = operation.decode_and_verify()?;
+            let stream: Option<Stream> = header.extension();
+            if stream.as_ref() != Some(&self.stream) {
+                return Err(BundleError::ForeignOperation(header.hash()));
+            }
+            operations.push((header, body));
+        }
+
+        for blob in &self.blobs {
+            if Hash::new(&blob.bytes) != blob.hash {
+                return Err(BackupError::InvalidBlob(blob.hash).into());
+            }
+        }
+
+        Ok(operations)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum BundleError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("encoding bundle failed: {0}")]
+    Encode(#[from] EncodeError),
+
+    #[error("decoding bundle failed: {0}")]
+    Decode(#[from] DecodeError),
+
+    #[error("no operations found for stream {0}")]
+    UnknownStream(Hash),
+
+    #[error("operation {0} does not belong to the bundled stream")]
+    ForeignOperation(Hash),
+
+    #[error("bundle version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error(transparent)]
+    Archive(#[from] BackupError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Header, PrivateKey};
+
+    use crate::backup::EncodedOperation;
+    use crate::extensions::{Extensions, Stream};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{BundleError, StreamBundle, BUNDLE_VERSION};
+
+    #[test]
+    fn reject_operations_from_other_streams() {
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let header_a = create_header(&private_key, &body, with_extensions(Extensions::default()));
+        let header_b = create_header(
+            &private_key,
+            &Body::new(b"another stream"),
+            with_extensions(Extensions::default()),
+        );
+        let stream: Stream = header_a.extension().unwrap();
+
+        let mut bundle = StreamBundle {
+            version: BUNDLE_VERSION,
+            stream,
+            operations: vec![EncodedOperation::new(&header_a, Some(&body))],
+            blobs: vec![],
+        };
+        assert_eq!(bundle.verify().unwrap().len(), 1);
+
+        // Every operation without a stream root hash starts a new stream.
+        bundle.operations.push(EncodedOperation::new(
+            &header_b,
+            Some(&Body::new(b"another stream")),
+        ));
+        assert!(matches!(
+            bundle.verify(),
+            Err(BundleError::ForeignOperation(_))
+        ));
+
+        // Operations without extensions belong to no stream at all.
+        let header_c = create_header(&private_key, &body, Header::default());
+        bundle.operations[1] = EncodedOperation::new(&header_c, Some(&body));
+        assert!(matches!(
+            bundle.verify(),
+            Err(BundleError::ForeignOperation(_))
+        ));
+    }
+}
@@ -0,0 +1,95 @@
+//! Write permissions of streams.
+//!
+//! The owner of a stream controls who may write to its logs by publishing write capabilities into
+//! the stream. A capability grants or revokes write access to one log path of the stream for one
+//! identity, the latest capability for a log path and identity wins. Capabilities are operations
+//! with a JSON payload of type `write_capability` and are only valid when they are authored by a
+//! key of the stream owner's identity.
+//!
+//! Log paths without any capabilities are open to everyone, which keeps streams like inboxes
+//! writable by anyone. As soon as the owner published a capability for a log path only the owner
+//! and identities with granted access may write to it. Owners should restrict a log path right
+//! after creating the stream, operations which arrived before the first capability are not
+//! rejected in hindsight.
+//!
+//! Capabilities and the operations they authorize can arrive in any order. Operations rejected
+//! for missing write access are kept aside and checked again whenever a capability of their
+//! stream arrives, so a write which arrives before its grant is accepted once the grant is there.
+
+use p2panda_core::{Body, Hash, PublicKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::extensions::LogPath;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WriteAccess {
+    Grant,
+    Revoke,
+}
+
+/// Grants or revokes write access to a log path of the stream the capability is published in.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "write_capability", rename_all = "camelCase")]
+pub struct WriteCapability {
+    /// Key of the identity the capability is issued for.
+    pub subject: PublicKey,
+
+    /// Log path the capability applies to, `None` for the log without a path.
+    pub log_path: Option<LogPath>,
+
+    pub access: WriteAccess,
+}
+
+impl WriteCapability {
+    /// Extract the capability of an operation payload. Returns `None` if the payload does not
+    /// contain one.
+    ///
+    /// Whether the author is allowed to issue capabilities depends on the identities we know
+    /// about and is checked when the capability is inserted into the store.
+    pub fn from_body(body: &Body) -> Option<Self> {
+        serde_json::from_slice(&body.to_bytes()).ok()
+    }
+
+    /// Encode the capability as an operation payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("encode write capability")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum CapabilityError {
+    #[error("only the stream owner can issue write capabilities")]
+    NotOwner,
+
+    #[error("{author} has no write access to log \"{log_path}\" of stream {stream_id}")]
+    Unauthorized {
+        author: PublicKey,
+        stream_id: Hash,
+        log_path: String,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, PrivateKey};
+
+    use crate::extensions::LogPath;
+
+    use super::{WriteAccess, WriteCapability};
+
+    #[test]
+    fn capability_from_body() {
+        let capability = WriteCapability {
+            subject: PrivateKey::new().public_key(),
+            log_path: Some(LogPath::try_from("calendar".to_string()).unwrap()),
+            access: WriteAccess::Grant,
+        };
+        let body = Body::new(&capability.to_bytes());
+        assert_eq!(WriteCapability::from_body(&body), Some(capability));
+
+        let body = Body::new(br#"{"type":"event","name":"Assembly"}"#);
+        assert_eq!(WriteCapability::from_body(&body), None);
+    }
+}
@@ -0,0 +1,183 @@
+//! Delegated device keys.
+//!
+//! Every device has its own private key. To act as one user on several devices, an identity
+//! delegates the keys of its other devices with a delegation certificate. The certificate names
+//! the identity and the device key and is signed by both: the device requests the delegation by
+//! signing it first, the identity completes it with its own signature. This way nobody can claim
+//! the device of someone else, and no device can attach itself to an identity on its own.
+//!
+//! Certificates are published as operations authored by the delegating identity in its identity
+//! log, with a JSON payload of type `device_delegation`. A device key can only be delegated once
+//! and delegated keys can't delegate further devices.
+
+use p2panda_core::{Body, Header, PrivateKey, PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::extensions::{is_identity_record, Extensions};
+
+/// Domain separation prefix of the signed delegation message.
+const DELEGATION_CONTEXT: &[u8] = b"toolkitty/device-delegation/v1";
+
+/// Request of a device to be delegated by an identity, signed by the device.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DelegationRequest {
+    pub identity: PublicKey,
+    pub device: PublicKey,
+    pub device_signature: Signature,
+}
+
+impl DelegationRequest {
+    pub fn new(identity: PublicKey, device: &PrivateKey) -> Self {
+        let message = message(&identity, &device.public_key());
+        Self {
+            identity,
+            device: device.public_key(),
+            device_signature: device.sign(&message),
+        }
+    }
+}
+
+/// Certificate stating that `device` acts on behalf of `identity`, signed by both keys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "device_delegation", rename_all = "camelCase")]
+pub struct DeviceDelegation {
+    pub identity: PublicKey,
+    pub device: PublicKey,
+    pub identity_signature: Signature,
+    pub device_signature: Signature,
+}
+
+impl DeviceDelegation {
+    /// Complete a delegation request with the signature of the identity.
+    pub fn new(identity: &PrivateKey, request: DelegationRequest) -> Result<Self, DelegationError> {
+        if request.identity != identity.public_key() {
+            return Err(DelegationError::IdentityMismatch);
+        }
+
+        let message = message(&request.identity, &request.device);
+        let delegation = Self {
+            identity: request.identity,
+            device: request.device,
+            identity_signature: identity.sign(&message),
+            device_signature: request.device_signature,
+        };
+        delegation.verify()?;
+
+        Ok(delegation)
+    }
+
+    /// Check that the identity and the device signed the certificate.
+    pub fn verify(&self) -> Result<(), DelegationError> {
+        if self.identity == self.device {
+            return Err(DelegationError::SameKey);
+        }
+
+        let message = message(&self.identity, &self.device);
+        if !self.identity.verify(&message, &self.identity_signature)
+            || !self.device.verify(&message, &self.device_signature)
+        {
+            return Err(DelegationError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Extract and verify the delegation certificate of an operation. Returns `None` if the
+    /// operation does not contain one or was not published in an identity log.
+    pub fn from_operation(
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+    ) -> Result<Option<Self>, DelegationError> {
+        let Some(body) = body.filter(|_| is_identity_record(header)) else {
+            return Ok(None);
+        };
+        let Ok(delegation) = serde_json::from_slice::<Self>(&body.to_bytes()) else {
+            return Ok(None);
+        };
+
+        if header.public_key != delegation.identity {
+            return Err(DelegationError::IdentityMismatch);
+        }
+        delegation.verify()?;
+
+        Ok(Some(delegation))
+    }
+
+    /// Encode the certificate as an operation payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("encode device delegation")
+    }
+}
+
+fn message(identity: &PublicKey, device: &PublicKey) -> Vec<u8> {
+    [
+        DELEGATION_CONTEXT,
+        identity.as_bytes().as_slice(),
+        device.as_bytes().as_slice(),
+    ]
+    .concat()
+}
+
+#[derive(Debug, Error)]
+pub enum DelegationError {
+    #[error("device delegation is not signed by the identity and the device")]
+    InvalidSignature,
+
+    #[error("device delegation must be issued by the delegating identity")]
+    IdentityMismatch,
+
+    #[error("device delegation must name two different keys")]
+    SameKey,
+
+    #[error("device delegation conflicts with an earlier one")]
+    Conflict,
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, PrivateKey};
+
+    use crate::extensions::Extensions;
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{DelegationError, DelegationRequest, DeviceDelegation};
+
+    #[test]
+    fn delegate_device_key() {
+        let identity = PrivateKey::new();
+        let device = PrivateKey::new();
+
+        let request = DelegationRequest::new(identity.public_key(), &device);
+        let delegation = DeviceDelegation::new(&identity, request.clone()).unwrap();
+        assert!(delegation.verify().is_ok());
+
+        // Only the requested identity can complete the delegation.
+        assert!(matches!(
+            DeviceDelegation::new(&PrivateKey::new(), request.clone()),
+            Err(DelegationError::IdentityMismatch)
+        ));
+
+        // Devices can't be delegated without their consent.
+        let forged = DelegationRequest {
+            device: PrivateKey::new().public_key(),
+            ..request
+        };
+        assert!(matches!(
+            DeviceDelegation::new(&identity, forged),
+            Err(DelegationError::InvalidSignature)
+        ));
+
+        let body = Body::new(&delegation.to_bytes());
+        let header = create_header(
+            &identity,
+            &body,
+            with_extensions(Extensions::identity(identity.public_key())),
+        );
+        assert_eq!(
+            DeviceDelegation::from_operation(&header, Some(&body)).unwrap(),
+            Some(delegation)
+        );
+    }
+}
@@ -0,0 +1,300 @@
+//! Causal delivery of operations to the frontend.
+//!
+//! Operations can name other operations they depend on in the dependencies extension, for
+//! example a booking request which refers to the event it books. Operations arrive in any order
+//! across logs, an operation is therefore held back until all its dependencies were delivered to
+//! the frontend. Dependencies count as delivered when they were persisted and are not held back
+//! themselves, so operations delivered during earlier runs satisfy them as well. Dependencies
+//! which were rejected or pruned will never be delivered, they don't hold back their dependants
+//! either. Held back operations are also kept in the store by the service, so they are buffered
+//! again after a restart.
+//!
+//! Operations whose dependencies don't arrive within a timeout are reported to the frontend once,
+//! they stay buffered and are delivered as soon as the dependencies show up. The buffer holds a
+//! limited number of operations, when it is full the operation waiting longest is delivered
+//! without its dependencies.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant};
+
+use p2panda_core::{Hash, Header};
+use p2panda_node::stream::StreamEvent;
+use tracing::warn;
+
+use crate::extensions::{Dependencies, Extensions};
+
+/// Time after which operations with missing dependencies are reported to the frontend.
+pub const DEPENDENCY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of operations which are held back at the same time.
+pub const MAX_PENDING_OPERATIONS: usize = 1024;
+
+struct PendingEvent {
+    event: StreamEvent<Extensions>,
+    missing: HashSet<Hash>,
+    since: Instant,
+    reported: bool,
+
+    /// Position in the order the operations were buffered in.
+    position: u64,
+}
+
+/// Operations which are held back until their dependencies were delivered.
+pub struct DependencyBuffer {
+    pending: HashMap<Hash, PendingEvent>,
+
+    /// Operations which are waiting for the operation with the given hash.
+    dependants: HashMap<Hash, Vec<Hash>>,
+
+    capacity: usize,
+    next_position: u64,
+}
+
+impl Default for DependencyBuffer {
+    fn default() -> Self {
+        Self::with_capacity(MAX_PENDING_OPERATIONS)
+    }
+}
+
+impl DependencyBuffer {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            pending: HashMap::new(),
+            dependants: HashMap::new(),
+            capacity,
+            next_position: 0,
+        }
+    }
+
+    /// Add an event to the buffer and return all events which can be delivered now, in causal
+    /// order.
+    ///
+    /// `is_delivered` tells if an operation which is not buffered was already delivered or will
+    /// never be.
+    pub fn release(
+        &mut self,
+        event: StreamEvent<Extensions>,
+        is_delivered: impl Fn(&Hash) -> bool,
+    ) -> Vec<StreamEvent<Extensions>> {
+        let Some(header) = &event.header else {
+            return vec![event];
+        };
+
+        let operation_id = header.hash();
+        let dependencies: Dependencies = header.extension().unwrap_or_default();
+        let missing: HashSet<Hash> = dependencies
+            .0
+            .into_iter()
+            .filter(|hash| self.pending.contains_key(hash) || !is_delivered(hash))
+            .collect();
+        if missing.is_empty() {
+            let mut ready = vec![event];
+            ready.extend(self.resolve(operation_id));
+            return ready;
+        }
+
+        for hash in &missing {
+            self.dependants.entry(*hash).or_default().push(operation_id);
+        }
+        self.pending.insert(
+            operation_id,
+            PendingEvent {
+                event,
+                missing,
+                since: Instant::now(),
+                reported: false,
+                position: self.next_position,
+            },
+        );
+        self.next_position += 1;
+        if self.pending.len() <= self.capacity {
+            return Vec::new();
+        }
+
+        let oldest = self
+            .pending
+            .iter()
+            .min_by_key(|(_, pending)| pending.position)
+            .map(|(hash, _)| *hash)
+            .expect("buffer is not empty");
+        warn!("too many operations held back, delivering {oldest} without its dependencies");
+        let pending = self.remove(&oldest).expect("pending event");
+        let mut ready = vec![pending.event];
+        ready.extend(self.resolve(oldest));
+        ready
+    }
+
+    /// Mark an operation as delivered or as never to be delivered, and return all events waiting
+    /// for it which can be delivered now, in causal order.
+    pub fn resolve(&mut self, operation_id: Hash) -> Vec<StreamEvent<Extensions>> {
+        // Operations which were pruned while being held back are never delivered.
+        self.remove(&operation_id);
+
+        let mut ready = Vec::new();
+        let mut resolved = VecDeque::from([operation_id]);
+        while let Some(resolved_id) = resolved.pop_front() {
+            for dependant in self.dependants.remove(&resolved_id).unwrap_or_default() {
+                let Some(pending) = self.pending.get_mut(&dependant) else {
+                    continue;
+                };
+                pending.missing.remove(&resolved_id);
+                if pending.missing.is_empty() {
+                    let pending = self.pending.remove(&dependant).expect("pending event");
+                    resolved.push_back(dependant);
+                    ready.push(pending.event);
+                }
+            }
+        }
+        ready
+    }
+
+    /// Remove a held back operation without delivering it.
+    fn remove(&mut self, operation_id: &Hash) -> Option<PendingEvent> {
+        let pending = self.pending.remove(operation_id)?;
+        for hash in &pending.missing {
+            if let Some(dependants) = self.dependants.get_mut(hash) {
+                dependants.retain(|dependant| dependant != operation_id);
+            }
+        }
+        Some(pending)
+    }
+
+    /// Return the headers and missing dependencies of all operations which are waiting longer
+    /// than the timeout and were not reported yet.
+    pub fn expired(&mut self, timeout: Duration) -> Vec<(Header<Extensions>, Vec<Hash>)> {
+        self.pending
+            .values_mut()
+            .filter(|pending| !pending.reported && pending.since.elapsed() >= timeout)
+            .map(|pending| {
+                pending.reported = true;
+                let header = pending
+                    .event
+                    .header
+                    .clone()
+                    .expect("pending event has header");
+                (header, pending.missing.iter().copied().collect())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use p2panda_core::{Body, Hash, Header, PrivateKey};
+    use p2panda_node::stream::{EventData, StreamEvent};
+
+    use crate::extensions::{Dependencies, Extensions};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::DependencyBuffer;
+
+    fn create_event(
+        private_key: &PrivateKey,
+        seq_num: u64,
+        dependencies: Vec<Hash>,
+    ) -> (Hash, StreamEvent<Extensions>) {
+        let body = Body::new(b"{}");
+        let header = create_header(
+            private_key,
+            &body,
+            Header {
+                seq_num,
+                ..with_extensions(Extensions {
+                    dependencies: Dependencies(dependencies),
+                    ..Default::default()
+                })
+            },
+        );
+        let event = StreamEvent {
+            header: Some(header.clone()),
+            data: EventData::Application(body.to_bytes()),
+        };
+        (header.hash(), event)
+    }
+
+    fn hashes(events: &[StreamEvent<Extensions>]) -> Vec<Hash> {
+        events
+            .iter()
+            .map(|event| event.header.as_ref().unwrap().hash())
+            .collect()
+    }
+
+    #[test]
+    fn hold_back_until_dependencies_were_delivered() {
+        let private_key = PrivateKey::new();
+        let mut buffer = DependencyBuffer::default();
+
+        let (event_created, event_created_event) = create_event(&private_key, 0, vec![]);
+        let (booking_requested, booking_requested_event) =
+            create_event(&private_key, 1, vec![event_created]);
+        let (booking_accepted, booking_accepted_event) =
+            create_event(&private_key, 2, vec![event_created, booking_requested]);
+
+        assert!(buffer.release(booking_accepted_event, |_| false).is_empty());
+        assert!(buffer
+            .release(booking_requested_event, |_| false)
+            .is_empty());
+        assert_eq!(
+            hashes(&buffer.release(event_created_event, |_| false)),
+            vec![event_created, booking_requested, booking_accepted]
+        );
+
+        // Dependencies delivered earlier don't hold back operations.
+        let (event_updated, event_updated_event) =
+            create_event(&private_key, 3, vec![event_created]);
+        assert_eq!(
+            hashes(&buffer.release(event_updated_event, |_| true)),
+            vec![event_updated]
+        );
+    }
+
+    #[test]
+    fn report_missing_dependencies() {
+        let private_key = PrivateKey::new();
+        let mut buffer = DependencyBuffer::default();
+
+        let missing = Hash::new(b"missing");
+        let (_, event) = create_event(&private_key, 0, vec![missing]);
+        assert!(buffer.release(event, |_| false).is_empty());
+        assert!(buffer.expired(Duration::from_secs(60)).is_empty());
+
+        let expired = buffer.expired(Duration::ZERO);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].1, vec![missing]);
+
+        // Every operation is only reported once.
+        assert!(buffer.expired(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn resolve_dependencies_which_are_never_delivered() {
+        let private_key = PrivateKey::new();
+        let mut buffer = DependencyBuffer::default();
+
+        let rejected = Hash::new(b"rejected");
+        let (booking_requested, event) = create_event(&private_key, 0, vec![rejected]);
+        assert!(buffer.release(event, |_| false).is_empty());
+        assert_eq!(hashes(&buffer.resolve(rejected)), vec![booking_requested]);
+    }
+
+    #[test]
+    fn deliver_oldest_operation_when_full() {
+        let private_key = PrivateKey::new();
+        let mut buffer = DependencyBuffer::with_capacity(2);
+
+        let missing = Hash::new(b"missing");
+        let (first, first_event) = create_event(&private_key, 0, vec![missing]);
+        let (second, second_event) = create_event(&private_key, 1, vec![first]);
+        let (_, third_event) = create_event(&private_key, 2, vec![missing]);
+        assert!(buffer.release(first_event, |_| false).is_empty());
+        assert!(buffer.release(second_event, |_| false).is_empty());
+
+        // The oldest operation makes room and completes the dependencies of the second one.
+        assert_eq!(
+            hashes(&buffer.release(third_event, |_| false)),
+            vec![first, second]
+        );
+    }
+}
@@ -1,10 +1,13 @@
 use std::fmt::Display;
 use std::hash::Hash as StdHash;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use anyhow::anyhow;
 use p2panda_core::{Extension, Hash, Header, PruneFlag, PublicKey};
 use p2panda_node::extensions::LogId;
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 /// Globally unique stream identified derived from hashing over the bytes of a streams' `root_hash`
 /// and `owner` fields.
@@ -35,8 +38,27 @@ impl Stream {
         let bytes = [*self.root_hash.0.as_bytes(), *self.owner.0.as_bytes()].concat();
         StreamId::new(&bytes)
     }
+
+    /// The identity stream of an author, see `IDENTITY_LOG_PATH`.
+    pub fn identity(owner: PublicKey) -> Self {
+        Self {
+            root_hash: Hash::new(IDENTITY_STREAM_CONTEXT).into(),
+            owner: owner.into(),
+        }
+    }
 }
 
+/// Log path of the identity log.
+///
+/// Identity records like key successions and device delegations are published in the identity
+/// log of their author. It lives in a stream owned by the author whose root hash is derived from
+/// a fixed value instead of a first operation, so every author has exactly one identity stream.
+/// Operations in it are processed by the node and never delivered to the frontend.
+pub const IDENTITY_LOG_PATH: &str = "identity";
+
+/// Value hashed into the root hash of identity streams.
+const IDENTITY_STREAM_CONTEXT: &[u8] = b"toolkitty/identity/v1";
+
 impl TryFrom<Extensions> for Stream {
     type Error = anyhow::Error;
 
@@ -76,20 +98,53 @@ impl From<PublicKey> for StreamOwner {
     }
 }
 
+impl From<StreamOwner> for PublicKey {
+    fn from(owner: StreamOwner) -> Self {
+        owner.0
+    }
+}
+
 impl Display for StreamOwner {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
-/// The log path is any arbitrary value defined by the application layer. It's the application
-/// layers concern to ensure that no namespace collision occurs _within_ a stream.
-#[derive(Clone, Debug, PartialEq, Eq, StdHash, Serialize, Deserialize)]
+/// Maximum length of a log path in bytes.
+pub const MAX_LOG_PATH_LENGTH: usize = 256;
+
+/// The log path is a value defined by the application layer, like `calendar/inbox`. It's the
+/// application layers concern to ensure that no namespace collision occurs _within_ a stream.
+///
+/// Log paths consist of ASCII alphanumerics and `-_./:`. They are validated whenever they are
+/// decoded, also from the headers of other peers and from arguments of the frontend.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, StdHash, Serialize, Deserialize)]
+#[serde(try_from = "String")]
 pub struct LogPath(pub(crate) String);
 
-impl From<String> for LogPath {
-    fn from(value: String) -> Self {
-        Self(value)
+impl LogPath {
+    pub fn validate(&self) -> Result<(), LogPathError> {
+        if self.0.len() > MAX_LOG_PATH_LENGTH {
+            return Err(LogPathError::TooLong);
+        }
+        if let Some(character) = self
+            .0
+            .chars()
+            .find(|character| !character.is_ascii_alphanumeric() && !"-_./:".contains(*character))
+        {
+            return Err(LogPathError::InvalidCharacter(character));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<String> for LogPath {
+    type Error = LogPathError;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let log_path = Self(value);
+        log_path.validate()?;
+        Ok(log_path)
     }
 }
 
@@ -104,22 +159,186 @@ impl TryFrom<Extensions> for LogId {
     }
 }
 
+/// Encode the id of a log in a stream.
+///
+/// Log ids have the form `<root_hash>/<owner>` for logs without a path and
+/// `<root_hash>/<owner>/<log_path>` otherwise. Root hash and owner are hex strings which never
+/// contain a slash, the encoding can therefore be reversed with `from_log_id`, also for empty log
+/// paths or paths containing slashes.
 pub fn to_log_id(stream: Stream, log_path: Option<LogPath>) -> LogId {
+    let mut log_id = format!("{}/{}", stream.root_hash, stream.owner);
+    if let Some(log_path) = log_path {
+        log_id.push('/');
+        log_id.push_str(&log_path.0);
+    }
+    LogId(log_id)
+}
+
+/// Log id of a log in the ambiguous `<stream_id>/<log_path>` format used before `to_log_id`. Only
+/// needed to migrate data written by earlier versions.
+pub fn legacy_log_id(stream: &Stream, log_path: Option<&LogPath>) -> LogId {
     LogId(format!(
         "{}/{}",
         stream.id(),
-        log_path
-            .map(|path| path.to_string())
-            .unwrap_or("".to_string())
+        log_path.map(ToString::to_string).unwrap_or_default()
     ))
 }
 
+/// Decode a log id created with `to_log_id` into the stream and log path it was derived from.
+///
+/// Ids with an invalid log path or which are spelled differently than `to_log_id` would encode
+/// them, for example with upper case hex strings, are refused. Every log has exactly one id.
+pub fn from_log_id(log_id: &LogId) -> Result<(Stream, Option<LogPath>), LogIdError> {
+    let (root_hash, rest) = log_id.0.split_once('/').ok_or(LogIdError::MissingOwner)?;
+    let (owner, log_path) = match rest.split_once('/') {
+        Some((owner, log_path)) => (owner, Some(LogPath::try_from(log_path.to_string())?)),
+        None => (rest, None),
+    };
+
+    let root_hash =
+        Hash::from_str(root_hash).map_err(|err| LogIdError::InvalidRootHash(err.to_string()))?;
+    let owner =
+        PublicKey::from_str(owner).map_err(|err| LogIdError::InvalidOwner(err.to_string()))?;
+    let stream = Stream {
+        root_hash: root_hash.into(),
+        owner: owner.into(),
+    };
+    if to_log_id(stream.clone(), log_path.clone()).0 != log_id.0 {
+        return Err(LogIdError::NotCanonical);
+    }
+    Ok((stream, log_path))
+}
+
 impl Display for LogPath {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(f, "{}", self.0)
     }
 }
 
+#[derive(Debug, Error)]
+pub enum LogPathError {
+    #[error("log path is longer than {MAX_LOG_PATH_LENGTH} bytes")]
+    TooLong,
+
+    #[error("log path contains invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+#[derive(Debug, Error)]
+pub enum LogIdError {
+    #[error("log id is missing the stream owner")]
+    MissingOwner,
+
+    #[error("invalid stream root hash in log id: {0}")]
+    InvalidRootHash(String),
+
+    #[error("invalid stream owner in log id: {0}")]
+    InvalidOwner(String),
+
+    #[error("invalid log path in log id: {0}")]
+    InvalidLogPath(#[from] LogPathError),
+
+    #[error("log id is not in its canonical form")]
+    NotCanonical,
+}
+
+/// Version of the payload format chosen by the application layer. Operations published before
+/// payloads were versioned have version 0.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, StdHash, Serialize, Deserialize,
+)]
+pub struct SchemaVersion(pub(crate) u32);
+
+impl From<u32> for SchemaVersion {
+    fn from(version: u32) -> Self {
+        Self(version)
+    }
+}
+
+impl Display for SchemaVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Encoding of an operation payload. Operations published before content types were introduced
+/// contain JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, StdHash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ContentType {
+    #[default]
+    Json,
+    Cbor,
+    Binary,
+}
+
+impl ContentType {
+    pub fn is_json(&self) -> bool {
+        *self == ContentType::Json
+    }
+}
+
+/// Operations which need to be delivered to the application before this one.
+#[derive(Clone, Debug, Default, PartialEq, Eq, StdHash, Serialize, Deserialize)]
+pub struct Dependencies(pub(crate) Vec<Hash>);
+
+impl Dependencies {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<Hash>> for Dependencies {
+    fn from(hashes: Vec<Hash>) -> Self {
+        Self(hashes)
+    }
+}
+
+/// Blobs referenced by the payload of an operation. They are included in backups and stream
+/// bundles together with the operation.
+#[derive(Clone, Debug, Default, PartialEq, Eq, StdHash, Serialize, Deserialize)]
+pub struct Blobs(pub(crate) Vec<Hash>);
+
+impl Blobs {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl From<Vec<Hash>> for Blobs {
+    fn from(hashes: Vec<Hash>) -> Self {
+        Self(hashes)
+    }
+}
+
+/// UNIX timestamp in seconds after which an operation is expired. Expired operations are not
+/// delivered to the application, their payloads are purged from the store and not synced to other
+/// peers anymore. Headers are kept so logs stay intact.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, StdHash, Serialize, Deserialize,
+)]
+pub struct ExpiresAt(pub(crate) u64);
+
+impl ExpiresAt {
+    pub fn is_expired(&self) -> bool {
+        self.0 <= unix_time()
+    }
+}
+
+impl From<u64> for ExpiresAt {
+    fn from(timestamp: u64) -> Self {
+        Self(timestamp)
+    }
+}
+
+/// Current UNIX timestamp in seconds.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("time since epoch")
+        .as_secs()
+}
+
 #[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct Extensions {
     #[serde(rename = "r")]
@@ -137,6 +356,42 @@ pub struct Extensions {
         default = "PruneFlag::default"
     )]
     pub prune_flag: PruneFlag,
+
+    #[serde(rename = "v", skip_serializing_if = "Option::is_none", default)]
+    pub schema_version: Option<SchemaVersion>,
+
+    #[serde(rename = "c", skip_serializing_if = "ContentType::is_json", default)]
+    pub content_type: ContentType,
+
+    #[serde(rename = "d", skip_serializing_if = "Dependencies::is_empty", default)]
+    pub dependencies: Dependencies,
+
+    #[serde(rename = "e", skip_serializing_if = "Option::is_none", default)]
+    pub expires_at: Option<ExpiresAt>,
+
+    #[serde(rename = "b", skip_serializing_if = "Blobs::is_empty", default)]
+    pub blobs: Blobs,
+}
+
+impl Extensions {
+    /// Extensions of an operation in the identity log of the given author.
+    pub fn identity(author: PublicKey) -> Self {
+        let stream = Stream::identity(author);
+        Self {
+            stream_root_hash: Some(stream.root_hash),
+            stream_owner: Some(stream.owner),
+            log_path: Some(LogPath(IDENTITY_LOG_PATH.to_string())),
+            ..Default::default()
+        }
+    }
+}
+
+/// Returns `true` if the operation was published in the identity log of its author.
+pub fn is_identity_record(header: &Header<Extensions>) -> bool {
+    let stream: Option<Stream> = header.extension();
+    let log_path: Option<LogPath> = header.extension();
+    stream == Some(Stream::identity(header.public_key))
+        && log_path.is_some_and(|log_path| log_path.0 == IDENTITY_LOG_PATH)
 }
 
 impl Extension<StreamRootHash> for Extensions {
@@ -163,8 +418,8 @@ impl Extension<StreamOwner> for Extensions {
 
 impl Extension<Stream> for Extensions {
     fn extract(header: &Header<Self>) -> Option<Stream> {
-        let root_hash = header.extension().expect("extract root hash extension");
-        let owner = header.extension().expect("extract owner extension");
+        let root_hash = header.extension()?;
+        let owner = header.extension()?;
         Some(Stream { root_hash, owner })
     }
 }
@@ -179,12 +434,52 @@ impl Extension<LogPath> for Extensions {
 
 impl Extension<LogId> for Extensions {
     fn extract(header: &Header<Self>) -> Option<LogId> {
-        let stream: Stream = header.extension().expect("extract stream id extension");
+        let stream: Stream = header.extension()?;
         let log_path: Option<LogPath> = header.extension();
         Some(to_log_id(stream, log_path))
     }
 }
 
+impl Extension<SchemaVersion> for Extensions {
+    fn extract(header: &Header<Self>) -> Option<SchemaVersion> {
+        let extensions = header.extensions.as_ref()?;
+
+        Some(extensions.schema_version.unwrap_or_default())
+    }
+}
+
+impl Extension<ContentType> for Extensions {
+    fn extract(header: &Header<Self>) -> Option<ContentType> {
+        let extensions = header.extensions.as_ref()?;
+
+        Some(extensions.content_type)
+    }
+}
+
+impl Extension<Dependencies> for Extensions {
+    fn extract(header: &Header<Self>) -> Option<Dependencies> {
+        let extensions = header.extensions.as_ref()?;
+
+        Some(extensions.dependencies.clone())
+    }
+}
+
+impl Extension<Blobs> for Extensions {
+    fn extract(header: &Header<Self>) -> Option<Blobs> {
+        let extensions = header.extensions.as_ref()?;
+
+        Some(extensions.blobs.clone())
+    }
+}
+
+impl Extension<ExpiresAt> for Extensions {
+    fn extract(header: &Header<Self>) -> Option<ExpiresAt> {
+        let extensions = header.extensions.as_ref()?;
+
+        extensions.expires_at
+    }
+}
+
 impl Extension<PruneFlag> for Extensions {
     fn extract(header: &Header<Self>) -> Option<PruneFlag> {
         header
@@ -193,3 +488,103 @@ impl Extension<PruneFlag> for Extensions {
             .map(|extensions| extensions.prune_flag.clone())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Hash, PrivateKey};
+    use p2panda_node::extensions::LogId;
+    use proptest::prelude::*;
+
+    use super::{from_log_id, to_log_id, LogIdError, LogPath, LogPathError, Stream};
+
+    fn stream() -> impl Strategy<Value = Stream> {
+        (any::<[u8; 32]>(), any::<[u8; 32]>()).prop_map(|(root_hash, owner)| Stream {
+            root_hash: Hash::new(&root_hash).into(),
+            owner: PrivateKey::from_bytes(&owner).public_key().into(),
+        })
+    }
+
+    // Any valid log path, including the empty one.
+    fn log_path() -> impl Strategy<Value = Option<LogPath>> {
+        proptest::option::of("[a-zA-Z0-9_./:-]{0,256}".prop_map(LogPath))
+    }
+
+    proptest! {
+        #[test]
+        fn log_id_roundtrip(stream in stream(), log_path in log_path()) {
+            let log_id = to_log_id(stream.clone(), log_path.clone());
+            prop_assert_eq!(from_log_id(&log_id).unwrap(), (stream, log_path));
+        }
+
+        #[test]
+        fn log_id_is_injective(
+            stream_a in stream(),
+            stream_b in stream(),
+            log_path_a in log_path(),
+            log_path_b in log_path(),
+            same_stream in any::<bool>(),
+        ) {
+            // Logs of the same stream are the interesting case, independent streams differ in
+            // the hex prefix already.
+            let stream_b = if same_stream { stream_a.clone() } else { stream_b };
+            prop_assume!((&stream_a, &log_path_a) != (&stream_b, &log_path_b));
+            prop_assert_ne!(
+                to_log_id(stream_a, log_path_a),
+                to_log_id(stream_b, log_path_b)
+            );
+        }
+    }
+
+    #[test]
+    fn empty_log_path() {
+        let stream = Stream {
+            root_hash: Hash::new(b"root").into(),
+            owner: PrivateKey::new().public_key().into(),
+        };
+        assert_ne!(
+            to_log_id(stream.clone(), None),
+            to_log_id(stream.clone(), Some(LogPath(String::new())))
+        );
+        assert!(from_log_id(&LogId(stream.id().to_string())).is_err());
+    }
+
+    #[test]
+    fn refuse_invalid_log_ids() {
+        let stream = Stream {
+            root_hash: Hash::new(b"root").into(),
+            owner: PrivateKey::new().public_key().into(),
+        };
+        let log_id = to_log_id(stream, Some(LogPath("calendar".to_string())));
+
+        let invalid_path = LogId(format!("{}/bad path!", log_id.0));
+        assert!(matches!(
+            from_log_id(&invalid_path),
+            Err(LogIdError::InvalidLogPath(LogPathError::InvalidCharacter(
+                ' '
+            )))
+        ));
+
+        let upper_case = LogId(log_id.0.to_uppercase().replace("CALENDAR", "calendar"));
+        assert!(matches!(
+            from_log_id(&upper_case),
+            Err(LogIdError::NotCanonical)
+        ));
+
+        // Log paths are validated when deserializing as well.
+        assert!(serde_json::from_str::<LogPath>(r#""calendar inbox""#).is_err());
+    }
+
+    #[test]
+    fn validate_log_path() {
+        assert!(LogPath::try_from("calendar/inbox".to_string()).is_ok());
+        assert!(LogPath::try_from("v1:bookings_2024-10.json".to_string()).is_ok());
+        assert!(matches!(
+            LogPath::try_from("calendar inbox".to_string()),
+            Err(LogPathError::InvalidCharacter(' '))
+        ));
+        assert!(matches!(
+            LogPath::try_from("a".repeat(257)),
+            Err(LogPathError::TooLong)
+        ));
+    }
+}
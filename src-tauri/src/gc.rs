@@ -0,0 +1,295 @@
+//! Storage quotas and garbage collection.
+//!
+//! Operation payloads and blobs are accounted against an optional global quota and an optional
+//! quota per stream, blobs count towards every stream with an operation referencing them. When a
+//! quota is exceeded the garbage collector evicts blobs we downloaded from other peers, least
+//! recently used first. Blobs we uploaded ourselves, blobs referenced by operations of our
+//! identity and pinned blobs are never evicted. Evicted blobs are downloaded again when they are
+//! accessed the next time.
+//!
+//! Operation payloads are never evicted, they are needed to sync logs to other peers and to back
+//! them up. New remote blobs are not downloaded anymore once the global quota is reached.
+
+use std::collections::{HashMap, HashSet};
+
+use p2panda_core::{Hash, PublicKey};
+use p2panda_node::extensions::LogId;
+use p2panda_node::node::Node;
+use p2panda_node::topic::Topic;
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use crate::backup::referenced_blobs;
+use crate::extensions::{Extensions, Stream};
+use crate::store::{SqliteStore, StoreError, StoredBlob, StoredPayload};
+
+/// Key of the storage quota in the settings table.
+pub const STORAGE_QUOTA_SETTING: &str = "storage_quota";
+
+/// Storage limits in bytes, `None` means unlimited.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct StorageQuota {
+    /// Limit for all payloads and blobs together.
+    pub global: Option<u64>,
+
+    /// Limit for the payloads and referenced blobs of every single stream.
+    pub per_stream: Option<u64>,
+}
+
+impl StorageQuota {
+    pub fn load(store: &SqliteStore) -> Result<Self, StoreError> {
+        Ok(store.setting(STORAGE_QUOTA_SETTING)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, store: &SqliteStore) -> Result<(), StoreError> {
+        store.set_setting(STORAGE_QUOTA_SETTING, self)
+    }
+
+    /// Returns `true` if the given usage in bytes exceeds the global quota.
+    pub fn exceeds_global(&self, usage: u64) -> bool {
+        self.global.is_some_and(|quota| usage > quota)
+    }
+}
+
+/// Result of a garbage collection pass.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GcReport {
+    /// Blobs which were removed from the blob store.
+    pub evicted_blobs: Vec<Hash>,
+
+    /// Total number of bytes which were freed.
+    pub reclaimed_bytes: u64,
+}
+
+/// Evict blobs until all quotas are met or no candidates are left.
+pub async fn collect_garbage(
+    store: &SqliteStore,
+    node: &Node<Topic, LogId, Extensions>,
+    quota: &StorageQuota,
+    local_public_key: &PublicKey,
+) -> Result<GcReport, StoreError> {
+    let payloads = store.payloads()?;
+    let blobs = store.blobs()?;
+    let local_public_keys = store.identity_keys(local_public_key)?;
+
+    let mut report = GcReport::default();
+    for blob in plan(payloads, blobs, quota, &local_public_keys) {
+        if let Err(err) = node.delete_file(blob.hash).await {
+            error!("failed to evict blob {} from blob store: {err}", blob.hash);
+            continue;
+        }
+        store.delete_blob(&blob.hash)?;
+        report.evicted_blobs.push(blob.hash);
+        report.reclaimed_bytes += blob.size;
+    }
+
+    Ok(report)
+}
+
+/// Select the blobs which need to be evicted to meet the quotas.
+///
+/// Blobs referenced by streams exceeding their quota are evicted first, then blobs of all streams
+/// are evicted until the global quota is met. Candidates are evicted least recently used first.
+fn plan(
+    payloads: Vec<StoredPayload>,
+    blobs: Vec<StoredBlob>,
+    quota: &StorageQuota,
+    local_public_keys: &[PublicKey],
+) -> Vec<StoredBlob> {
+    let mut total_usage = 0;
+    let mut stream_usage: HashMap<Hash, u64> = HashMap::new();
+    let mut blob_streams: HashMap<Hash, HashSet<Hash>> = HashMap::new();
+    let mut own_blobs = HashSet::new();
+    for payload in payloads {
+        let stream: Stream = payload
+            .header
+            .extension()
+            .expect("extract stream extension");
+        total_usage += payload.size;
+        *stream_usage.entry(stream.id()).or_default() += payload.size;
+
+        for hash in referenced_blobs(&payload.header) {
+            blob_streams.entry(hash).or_default().insert(stream.id());
+            if local_public_keys.contains(&payload.header.public_key) {
+                own_blobs.insert(hash);
+            }
+        }
+    }
+
+    let mut candidates = Vec::new();
+    for blob in blobs {
+        total_usage += blob.size;
+        for stream_id in blob_streams.get(&blob.hash).into_iter().flatten() {
+            *stream_usage.entry(*stream_id).or_default() += blob.size;
+        }
+
+        if !blob.authored && !blob.pinned && !own_blobs.contains(&blob.hash) {
+            candidates.push(blob);
+        }
+    }
+    candidates.sort_by_key(|blob| blob.last_accessed);
+
+    let mut evicted = Vec::new();
+    if let Some(per_stream) = quota.per_stream {
+        candidates.retain(|blob| {
+            let Some(stream_ids) = blob_streams.get(&blob.hash) else {
+                return true;
+            };
+            if stream_ids
+                .iter()
+                .all(|stream_id| stream_usage[stream_id] <= per_stream)
+            {
+                return true;
+            }
+            for stream_id in stream_ids {
+                *stream_usage
+                    .get_mut(stream_id)
+                    .expect("stream was accounted") -= blob.size;
+            }
+            total_usage -= blob.size;
+            evicted.push(blob.clone());
+            false
+        });
+    }
+
+    if let Some(global) = quota.global {
+        for blob in candidates {
+            if total_usage <= global {
+                break;
+            }
+            total_usage -= blob.size;
+            evicted.push(blob);
+        }
+    }
+
+    evicted
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Hash, PrivateKey};
+
+    use crate::extensions::{Extensions, StreamOwner, StreamRootHash};
+    use crate::store::{StoredBlob, StoredPayload};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{plan, StorageQuota};
+
+    fn create_payload(
+        private_key: &PrivateKey,
+        stream: (StreamRootHash, StreamOwner),
+        size: u64,
+        blobs: Vec<Hash>,
+    ) -> StoredPayload {
+        let body = Body::new(&vec![0; size as usize]);
+        let header = create_header(
+            private_key,
+            &body,
+            with_extensions(Extensions {
+                stream_root_hash: Some(stream.0),
+                stream_owner: Some(stream.1),
+                blobs: blobs.into(),
+                ..Default::default()
+            }),
+        );
+        StoredPayload { header, size }
+    }
+
+    fn create_blob(name: &str, size: u64, last_accessed: u64) -> StoredBlob {
+        StoredBlob {
+            hash: Body::new(name.as_bytes()).hash(),
+            size,
+            authored: false,
+            pinned: false,
+            last_accessed,
+        }
+    }
+
+    #[test]
+    fn evict_least_recently_used_remote_blobs() {
+        let local_key = PrivateKey::new();
+        let remote_key = PrivateKey::new();
+        let stream = (
+            StreamRootHash::from(Body::new(b"root").hash()),
+            StreamOwner::from(remote_key.public_key()),
+        );
+        let other_stream = (
+            StreamRootHash::from(Body::new(b"other root").hash()),
+            StreamOwner::from(remote_key.public_key()),
+        );
+
+        let old = create_blob("old", 100, 1);
+        let recent = create_blob("recent", 100, 3);
+        let unreferenced = create_blob("unreferenced", 100, 2);
+        let mut pinned = create_blob("pinned", 100, 0);
+        pinned.pinned = true;
+        let mut uploaded = create_blob("uploaded", 100, 0);
+        uploaded.authored = true;
+        let own = create_blob("own", 100, 0);
+        let blobs = vec![
+            old.clone(),
+            recent.clone(),
+            unreferenced.clone(),
+            pinned.clone(),
+            uploaded,
+            own.clone(),
+        ];
+
+        let payloads = vec![
+            create_payload(
+                &remote_key,
+                stream,
+                10,
+                vec![old.hash, recent.hash, pinned.hash],
+            ),
+            create_payload(&local_key, other_stream, 10, vec![own.hash]),
+        ];
+
+        // Nothing is evicted without quotas.
+        let quota = StorageQuota::default();
+        assert!(plan(
+            payloads.clone(),
+            blobs.clone(),
+            &quota,
+            &[local_key.public_key()]
+        )
+        .is_empty());
+
+        // Payloads count towards the global quota but are never evicted.
+        let quota = StorageQuota {
+            global: Some(420),
+            per_stream: None,
+        };
+        let evicted = plan(
+            payloads.clone(),
+            blobs.clone(),
+            &quota,
+            &[local_key.public_key()],
+        );
+        assert_eq!(evicted, vec![old.clone(), unreferenced.clone()]);
+
+        // Only blobs referenced by the stream exceeding its quota are evicted.
+        let quota = StorageQuota {
+            global: None,
+            per_stream: Some(150),
+        };
+        let evicted = plan(
+            payloads.clone(),
+            blobs.clone(),
+            &quota,
+            &[local_key.public_key()],
+        );
+        assert_eq!(evicted, vec![old.clone(), recent.clone()]);
+
+        // Pinned blobs, our uploads and blobs of our operations are never evicted, even if the
+        // quota can't be met.
+        let quota = StorageQuota {
+            global: Some(0),
+            per_stream: None,
+        };
+        let evicted = plan(payloads, blobs, &quota, &[local_key.public_key()]);
+        assert_eq!(evicted, vec![old, unreferenced, recent]);
+    }
+}
@@ -0,0 +1,218 @@
+//! Inventory of the streams and logs known to the node.
+//!
+//! The inventory is derived from the persisted operations and is meant for storage and sync
+//! diagnostics. Sizes include headers and payloads, deleted payloads of expired operations don't
+//! count.
+
+use std::collections::BTreeMap;
+
+use p2panda_core::{Hash, Header, PublicKey};
+use serde::Serialize;
+
+use crate::extensions::{Extensions, LogPath, Stream};
+use crate::messages::StreamMeta;
+use crate::store::{SqliteStore, StoreError};
+
+/// A stream with all logs we persisted operations of.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamInventory {
+    pub stream: StreamMeta,
+    pub logs: Vec<LogInventory>,
+
+    /// Number of operations in all logs of the stream.
+    pub operations: u64,
+
+    /// Size of all operations in the stream in bytes.
+    pub size: u64,
+
+    /// Timestamp of the latest operation in the stream.
+    pub latest_timestamp: u64,
+}
+
+/// The log of one author in a stream.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogInventory {
+    pub log_path: Option<LogPath>,
+    pub author: PublicKey,
+    pub operations: u64,
+
+    /// Highest sequence number we know of, gaps indicate pruned or expired operations.
+    pub latest_seq_num: u64,
+
+    pub latest_timestamp: u64,
+    pub size: u64,
+}
+
+/// List all streams we persisted operations of, the most recently active first.
+pub fn streams(store: &SqliteStore) -> Result<Vec<StreamInventory>, StoreError> {
+    let mut streams = collect(store.operation_sizes(None)?);
+    streams.sort_by(|a, b| b.latest_timestamp.cmp(&a.latest_timestamp));
+    Ok(streams)
+}
+
+/// Inventory of a single stream, `None` if we didn't persist any operations of it.
+pub fn stream(
+    store: &SqliteStore,
+    stream_id: &Hash,
+) -> Result<Option<StreamInventory>, StoreError> {
+    Ok(collect(store.operation_sizes(Some(stream_id))?).pop())
+}
+
+fn collect(operations: Vec<(Header<Extensions>, u64)>) -> Vec<StreamInventory> {
+    let mut streams: BTreeMap<
+        Hash,
+        (Stream, BTreeMap<(Option<LogPath>, PublicKey), LogInventory>),
+    > = BTreeMap::new();
+    for (header, size) in operations {
+        let stream: Stream = header.extension().expect("extract stream extension");
+        let log_path: Option<LogPath> = header.extension();
+
+        let (_, logs) = streams
+            .entry(stream.id())
+            .or_insert_with(|| (stream, BTreeMap::new()));
+        let log = logs
+            .entry((log_path.clone(), header.public_key))
+            .or_insert_with(|| LogInventory {
+                log_path,
+                author: header.public_key,
+                operations: 0,
+                latest_seq_num: 0,
+                latest_timestamp: 0,
+                size: 0,
+            });
+        log.operations += 1;
+        log.latest_seq_num = log.latest_seq_num.max(header.seq_num);
+        log.latest_timestamp = log.latest_timestamp.max(header.timestamp);
+        log.size += size;
+    }
+
+    streams
+        .into_values()
+        .map(|(stream, logs)| {
+            let logs: Vec<LogInventory> = logs.into_values().collect();
+            StreamInventory {
+                stream: stream.into(),
+                operations: logs.iter().map(|log| log.operations).sum(),
+                size: logs.iter().map(|log| log.size).sum(),
+                latest_timestamp: logs
+                    .iter()
+                    .map(|log| log.latest_timestamp)
+                    .max()
+                    .unwrap_or_default(),
+                logs,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Hash, Header, PrivateKey};
+    use p2panda_node::extensions::LogId;
+
+    use crate::extensions::{Extensions, LogPath, Stream, StreamOwner, StreamRootHash};
+    use crate::store::SqliteStore;
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{stream, streams};
+
+    #[test]
+    fn list_streams() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let owner = PrivateKey::new();
+        let member = PrivateKey::new();
+
+        let mut stream = None;
+        for (private_key, log_path, seq_num, timestamp) in [
+            (&owner, "calendar", 0, 10),
+            (&owner, "calendar", 1, 20),
+            (&member, "inbox", 0, 30),
+        ] {
+            let body = Body::new(b"{}");
+            let header = create_header(
+                private_key,
+                &body,
+                Header {
+                    seq_num,
+                    timestamp,
+                    ..with_extensions(Extensions {
+                        stream_root_hash: stream.as_ref().map(|stream: &Stream| stream.root_hash),
+                        stream_owner: Some(StreamOwner::from(owner.public_key())),
+                        log_path: Some(LogPath::try_from(log_path.to_string()).unwrap()),
+                        ..Default::default()
+                    })
+                },
+            );
+            stream.get_or_insert(Stream {
+                root_hash: StreamRootHash::from(header.hash()),
+                owner: owner.public_key().into(),
+            });
+
+            let log_id: LogId = header.extension().unwrap();
+            store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap();
+        }
+
+        let streams = streams(&store).unwrap();
+        assert_eq!(streams.len(), 1);
+        assert_eq!(streams[0].stream.id, stream.unwrap().id());
+        assert_eq!(streams[0].operations, 3);
+        assert_eq!(streams[0].latest_timestamp, 30);
+
+        let logs = &streams[0].logs;
+        assert_eq!(logs.len(), 2);
+        assert_eq!(logs[0].author, owner.public_key());
+        assert_eq!(logs[0].operations, 2);
+        assert_eq!(logs[0].latest_seq_num, 1);
+        assert_eq!(logs[1].author, member.public_key());
+        assert_eq!(
+            logs[1].log_path,
+            Some(LogPath::try_from("inbox".to_string()).unwrap())
+        );
+        assert_eq!(streams[0].size, logs[0].size + logs[1].size);
+    }
+
+    #[test]
+    fn separate_log_without_path() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let owner = PrivateKey::new();
+
+        let mut known = None;
+        for (seq_num, log_path) in [(0, None), (1, Some(LogPath(String::new())))] {
+            let body = Body::new(b"{}");
+            let header = create_header(
+                &owner,
+                &body,
+                Header {
+                    seq_num,
+                    ..with_extensions(Extensions {
+                        stream_root_hash: known.as_ref().map(|stream: &Stream| stream.root_hash),
+                        stream_owner: Some(StreamOwner::from(owner.public_key())),
+                        log_path,
+                        ..Default::default()
+                    })
+                },
+            );
+            known.get_or_insert(Stream {
+                root_hash: StreamRootHash::from(header.hash()),
+                owner: owner.public_key().into(),
+            });
+
+            let log_id: LogId = header.extension().unwrap();
+            store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap();
+        }
+
+        let inventory = stream(&store, &known.unwrap().id()).unwrap().unwrap();
+        assert_eq!(inventory.operations, 2);
+        assert_eq!(inventory.logs.len(), 2);
+        assert_eq!(inventory.logs[0].log_path, None);
+        assert_eq!(inventory.logs[1].log_path, Some(LogPath(String::new())));
+
+        assert!(stream(&store, &Hash::new(b"unknown")).unwrap().is_none());
+    }
+}
@@ -1,103 +1,788 @@
 //! Persistent storage for private keys.
+//!
+//! Keys are kept in one of several backends which can be selected by configuration:
+//!
+//! - Plain file: hex string protected only by file permissions
+//! - Encrypted file: an encryption key is derived from a passphrase with Argon2id and the private
+//!   key is sealed with XChaCha20-Poly1305
+//! - OS secret: the platform's credential store (Keychain, Credential Manager, Secret Service)
+//! - Memory: nothing is persisted, used for tests and development builds
+//!
+//! Key files are versioned JSON envelopes which contain the public key next to the private key, so
+//! a damaged file can be told apart from a missing one. Files are written atomically and existing
+//! keys are only overwritten when explicitly replaced.
 
 #[cfg(not(windows))]
 use std::os::unix::fs::PermissionsExt;
 
-use std::fs::{self, File};
-use std::io::prelude::*;
-use std::path::Path;
+use std::fs;
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 
-use anyhow::{Context, Result};
-use p2panda_core::PrivateKey;
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use p2panda_core::{PrivateKey, PublicKey};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::migrations::{ENCRYPTED_PRIVATE_KEY_FILE_NAME, PRIVATE_KEY_FILE_NAME};
+use crate::store::{SqliteStore, StoreError};
+
+/// Key of the configured key store backend in the settings table.
+pub const KEY_STORE_SETTING: &str = "key_store";
+
+/// Version of the key file format written by this version of the app.
+pub const KEY_FILE_VERSION: u32 = 1;
+
+/// Upper limits of the key derivation parameters accepted from a key file. Key files are not
+/// trusted, exceeding parameters could make deriving the key exhaust memory or never finish.
+const MAX_KDF_MEMORY: u32 = 1024 * 1024;
+const MAX_KDF_ITERATIONS: u32 = 16;
+const MAX_KDF_PARALLELISM: u32 = 16;
+
+/// Service name under which the private key is stored in the OS secret store.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows",
+    target_os = "linux"
+))]
+const OS_SECRET_SERVICE: &str = "toolkitty";
 
 /// Storage and retrieval trait for an Ed25519 private key.
-pub trait KeyStore {
-    /// Save the hex-encoded private key at the given path.
-    fn save(&self, path: &Path) -> Result<()>;
+pub trait KeyStore: Send + Sync {
+    /// Load the private key, fails with `KeyStoreError::NotFound` if no key was stored yet.
+    fn load(&self) -> Result<PrivateKey, KeyStoreError>;
+
+    /// Save a new private key, fails with `KeyStoreError::AlreadyExists` if a key is stored
+    /// already.
+    fn save(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError>;
 
-    /// Load the private key from file at the given path.
-    fn load(path: &Path) -> Result<PrivateKey>;
+    /// Save the private key, replacing any key stored before.
+    fn replace(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError>;
 
-    /// Load the private key from file at the given path if it exists.
-    /// Otherise create a new, random private key, save it to file and
+    /// Remove the private key from the store.
+    fn delete(&self) -> Result<(), KeyStoreError>;
+
+    /// The backend this store is implemented with.
+    fn backend(&self) -> KeyStoreBackend;
+
+    /// Load the private key if it exists. Otherwise create a new, random private key, save it and
     /// return it.
-    fn load_or_create_new(path: &Path) -> Result<PrivateKey>;
+    ///
+    /// A new key is only created if there is none yet, keys which can't be read are reported as
+    /// errors.
+    fn load_or_create_new(&self) -> Result<PrivateKey, KeyStoreError> {
+        match self.load() {
+            Err(KeyStoreError::NotFound) => {
+                let private_key = PrivateKey::new();
+                self.save(&private_key)?;
+                Ok(private_key)
+            }
+            result => result,
+        }
+    }
 }
 
-impl KeyStore for PrivateKey {
-    fn save(&self, path: &Path) -> Result<()> {
-        let encoded_private_key = &self.to_hex();
+/// Available key store backends.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyStoreBackend {
+    #[default]
+    File,
+    EncryptedFile,
+    OsSecret,
+    Memory,
+}
+
+impl KeyStoreBackend {
+    /// Read the configured backend.
+    ///
+    /// Installations without configuration keep using the key file they already have.
+    pub fn load(store: &SqliteStore, app_data_dir: &Path) -> Result<Self, StoreError> {
+        if let Some(backend) = store.setting(KEY_STORE_SETTING)? {
+            return Ok(backend);
+        }
+
+        if app_data_dir.join(ENCRYPTED_PRIVATE_KEY_FILE_NAME).exists() {
+            Ok(Self::EncryptedFile)
+        } else {
+            Ok(Self::File)
+        }
+    }
 
-        fs::create_dir_all(
-            path.parent()
-                .context("failed to get parent directory of key store path")?,
-        )?;
-        let mut file = File::create(path)?;
-        file.write_all(encoded_private_key.as_bytes())?;
-        file.sync_all()?;
+    pub fn save(&self, store: &SqliteStore) -> Result<(), StoreError> {
+        store.set_setting(KEY_STORE_SETTING, self)
+    }
 
-        let mut permissions = file.metadata()?.permissions();
+    /// Open the key store of this backend. The passphrase is required for encrypted files and
+    /// ignored by all other backends.
+    pub fn open(
+        self,
+        app_data_dir: &Path,
+        passphrase: Option<&str>,
+    ) -> Result<Box<dyn KeyStore>, KeyStoreError> {
+        let key_store: Box<dyn KeyStore> = match self {
+            Self::File => Box::new(FileKeyStore::new(app_data_dir.join(PRIVATE_KEY_FILE_NAME))),
+            Self::EncryptedFile => {
+                let passphrase = passphrase.ok_or(KeyStoreError::PassphraseRequired)?;
+                Box::new(EncryptedFileKeyStore::new(
+                    app_data_dir.join(ENCRYPTED_PRIVATE_KEY_FILE_NAME),
+                    passphrase,
+                ))
+            }
+            #[cfg(any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "windows",
+                target_os = "linux"
+            ))]
+            Self::OsSecret => Box::new(OsSecretKeyStore::new(
+                OS_SECRET_SERVICE,
+                &app_data_dir.to_string_lossy(),
+            )?),
+            #[cfg(not(any(
+                target_os = "macos",
+                target_os = "ios",
+                target_os = "windows",
+                target_os = "linux"
+            )))]
+            Self::OsSecret => return Err(KeyStoreError::Unsupported),
+            Self::Memory => Box::new(MemoryKeyStore::default()),
+        };
+        Ok(key_store)
+    }
+}
 
-        #[cfg(windows)]
-        permissions.set_readonly(true);
-        #[cfg(not(windows))]
-        permissions.set_mode(0o600);
+/// Hex-encoded private key in a file which is only readable by the current user.
+pub struct FileKeyStore {
+    path: PathBuf,
+}
 
-        fs::set_permissions(path, permissions)?;
+impl FileKeyStore {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
 
-        Ok(())
+    fn write(&self, private_key: &PrivateKey, overwrite: bool) -> Result<(), KeyStoreError> {
+        let key_file = KeyFile {
+            version: KEY_FILE_VERSION,
+            public_key: Some(private_key.public_key()),
+            key: PlainKey {
+                private_key: private_key.to_hex(),
+            },
+        };
+        write_secret(&self.path, &serde_json::to_vec(&key_file)?, overwrite)
     }
+}
 
-    fn load(path: &Path) -> Result<Self> {
-        let mut file = File::open(path)?;
-        let mut contents = String::new();
-        file.read_to_string(&mut contents)?;
+impl KeyStore for FileKeyStore {
+    fn load(&self) -> Result<PrivateKey, KeyStoreError> {
+        let contents = read_secret(&self.path)?;
 
-        let private_key_bytes = hex::decode(contents)?;
-        let private_key_bytes_array: [u8; 32] = private_key_bytes.as_slice().try_into()?;
-        let private_key = PrivateKey::from_bytes(&private_key_bytes_array);
+        // Files written before the envelope was introduced only contain the hex-encoded key.
+        if !contents.starts_with(b"{") {
+            let contents = String::from_utf8(contents)
+                .map_err(|err| KeyStoreError::Corrupt(err.to_string()))?;
+            return decode_private_key(contents.trim());
+        }
 
+        let key_file: KeyFile<PlainKey> = KeyFile::decode(&contents)?;
+        let private_key = decode_private_key(&key_file.key.private_key)?;
+        key_file.verify(&private_key)?;
         Ok(private_key)
     }
 
-    fn load_or_create_new(path: &Path) -> Result<Self> {
-        let private_key = if let Ok(private_key) = Self::load(path) {
-            private_key
-        } else {
-            let private_key = PrivateKey::new();
-            Self::save(&private_key, path)?;
-            private_key
+    fn save(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        self.write(private_key, false)
+    }
+
+    fn replace(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        self.write(private_key, true)
+    }
+
+    fn delete(&self) -> Result<(), KeyStoreError> {
+        remove_secret(&self.path)
+    }
+
+    fn backend(&self) -> KeyStoreBackend {
+        KeyStoreBackend::File
+    }
+}
+
+/// Private key encrypted with a passphrase.
+pub struct EncryptedFileKeyStore {
+    path: PathBuf,
+    passphrase: String,
+}
+
+impl EncryptedFileKeyStore {
+    pub fn new(path: PathBuf, passphrase: &str) -> Self {
+        Self {
+            path,
+            passphrase: passphrase.to_string(),
+        }
+    }
+
+    fn write(&self, private_key: &PrivateKey, overwrite: bool) -> Result<(), KeyStoreError> {
+        let kdf = KdfParams::generate();
+        let cipher = XChaCha20Poly1305::new(&kdf.derive_key(&self.passphrase)?.into());
+
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                private_key.as_bytes().as_slice(),
+            )
+            .map_err(|_| KeyStoreError::Inaccessible("failed to encrypt private key".into()))?;
+
+        let key_file = KeyFile {
+            version: KEY_FILE_VERSION,
+            public_key: Some(private_key.public_key()),
+            key: EncryptedKey {
+                kdf,
+                nonce: hex::encode(nonce),
+                ciphertext: hex::encode(ciphertext),
+            },
         };
+        write_secret(&self.path, &serde_json::to_vec(&key_file)?, overwrite)
+    }
+}
+
+impl KeyStore for EncryptedFileKeyStore {
+    /// Fails with `KeyStoreError::WrongPassphrase` if the passphrase is wrong or the ciphertext
+    /// was tampered with.
+    fn load(&self) -> Result<PrivateKey, KeyStoreError> {
+        let key_file: KeyFile<EncryptedKey> = KeyFile::decode(&read_secret(&self.path)?)?;
+        let encrypted = &key_file.key;
+        let cipher = XChaCha20Poly1305::new(&encrypted.kdf.derive_key(&self.passphrase)?.into());
+
+        let nonce: [u8; 24] = decode_hex(&encrypted.nonce)?
+            .as_slice()
+            .try_into()
+            .map_err(|_| KeyStoreError::Corrupt("invalid nonce length".into()))?;
+        let private_key_bytes = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                decode_hex(&encrypted.ciphertext)?.as_slice(),
+            )
+            .map_err(|_| KeyStoreError::WrongPassphrase)?;
 
+        let private_key = private_key_from_bytes(&private_key_bytes)?;
+        key_file.verify(&private_key)?;
         Ok(private_key)
     }
+
+    fn save(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        self.write(private_key, false)
+    }
+
+    fn replace(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        self.write(private_key, true)
+    }
+
+    fn delete(&self) -> Result<(), KeyStoreError> {
+        remove_secret(&self.path)
+    }
+
+    fn backend(&self) -> KeyStoreBackend {
+        KeyStoreBackend::EncryptedFile
+    }
+}
+
+/// Private key in the credential store of the operating system.
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows",
+    target_os = "linux"
+))]
+pub struct OsSecretKeyStore {
+    entry: keyring::Entry,
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows",
+    target_os = "linux"
+))]
+impl OsSecretKeyStore {
+    /// The account distinguishes several installations of the app on the same system.
+    pub fn new(service: &str, account: &str) -> Result<Self, KeyStoreError> {
+        Ok(Self {
+            entry: keyring::Entry::new(service, account)?,
+        })
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows",
+    target_os = "linux"
+))]
+impl KeyStore for OsSecretKeyStore {
+    fn load(&self) -> Result<PrivateKey, KeyStoreError> {
+        decode_private_key(&self.entry.get_password()?)
+    }
+
+    fn save(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        match self.entry.get_password() {
+            Err(keyring::Error::NoEntry) => self.replace(private_key),
+            Ok(_) => Err(KeyStoreError::AlreadyExists),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn replace(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        self.entry.set_password(&private_key.to_hex())?;
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), KeyStoreError> {
+        match self.entry.delete_credential() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn backend(&self) -> KeyStoreBackend {
+        KeyStoreBackend::OsSecret
+    }
+}
+
+/// Private key which is only kept in memory.
+#[derive(Default)]
+pub struct MemoryKeyStore {
+    private_key: Mutex<Option<PrivateKey>>,
+}
+
+impl KeyStore for MemoryKeyStore {
+    fn load(&self) -> Result<PrivateKey, KeyStoreError> {
+        self.private_key
+            .lock()
+            .expect("acquire private key lock")
+            .clone()
+            .ok_or(KeyStoreError::NotFound)
+    }
+
+    fn save(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        let mut stored = self.private_key.lock().expect("acquire private key lock");
+        if stored.is_some() {
+            return Err(KeyStoreError::AlreadyExists);
+        }
+        *stored = Some(private_key.clone());
+        Ok(())
+    }
+
+    fn replace(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        *self.private_key.lock().expect("acquire private key lock") = Some(private_key.clone());
+        Ok(())
+    }
+
+    fn delete(&self) -> Result<(), KeyStoreError> {
+        self.private_key
+            .lock()
+            .expect("acquire private key lock")
+            .take();
+        Ok(())
+    }
+
+    fn backend(&self) -> KeyStoreBackend {
+        KeyStoreBackend::Memory
+    }
+}
+
+/// Envelope of a key file as it is stored on disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile<T> {
+    /// Format version, encrypted key files written before versioning was introduced have none.
+    #[serde(default)]
+    version: u32,
+
+    /// Public key of the stored private key, used as a checksum when loading.
+    public_key: Option<PublicKey>,
+
+    #[serde(flatten)]
+    key: T,
+}
+
+impl<T: DeserializeOwned> KeyFile<T> {
+    fn decode(bytes: &[u8]) -> Result<Self, KeyStoreError> {
+        let key_file: Self = serde_json::from_slice(bytes)?;
+        if key_file.version > KEY_FILE_VERSION {
+            return Err(KeyStoreError::UnsupportedVersion(key_file.version));
+        }
+        Ok(key_file)
+    }
+}
+
+impl<T> KeyFile<T> {
+    fn verify(&self, private_key: &PrivateKey) -> Result<(), KeyStoreError> {
+        match &self.public_key {
+            Some(public_key) if *public_key != private_key.public_key() => Err(
+                KeyStoreError::Corrupt("private key does not match public key".into()),
+            ),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Unencrypted private key.
+#[derive(Debug, Serialize, Deserialize)]
+struct PlainKey {
+    /// Hex-encoded private key.
+    private_key: String,
+}
+
+/// Encrypted private key.
+#[derive(Debug, Serialize, Deserialize)]
+struct EncryptedKey {
+    kdf: KdfParams,
+
+    /// Hex-encoded XChaCha20-Poly1305 nonce.
+    nonce: String,
+
+    /// Hex-encoded private key, encrypted and authenticated.
+    ciphertext: String,
+}
+
+/// Argon2id parameters used to derive the encryption key from a passphrase. They are stored with
+/// every key so they can be raised in the future without breaking existing key files.
+#[derive(Debug, Serialize, Deserialize)]
+struct KdfParams {
+    /// Memory cost in KiB.
+    memory: u32,
+    iterations: u32,
+    parallelism: u32,
+
+    /// Hex-encoded random salt.
+    salt: String,
+}
+
+impl KdfParams {
+    fn generate() -> Self {
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+
+        Self {
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+            salt: hex::encode(salt),
+        }
+    }
+
+    fn derive_key(&self, passphrase: &str) -> Result<[u8; 32], KeyStoreError> {
+        if passphrase.is_empty() {
+            return Err(KeyStoreError::PassphraseRequired);
+        }
+        if self.memory > MAX_KDF_MEMORY
+            || self.iterations > MAX_KDF_ITERATIONS
+            || self.parallelism > MAX_KDF_PARALLELISM
+        {
+            return Err(KeyStoreError::Corrupt(
+                "kdf parameters exceed the supported limits".into(),
+            ));
+        }
+
+        let params = Params::new(self.memory, self.iterations, self.parallelism, Some(32))
+            .map_err(|err| KeyStoreError::Corrupt(format!("invalid kdf parameters: {err}")))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &decode_hex(&self.salt)?, &mut key)
+            .map_err(|err| KeyStoreError::Corrupt(format!("key derivation failed: {err}")))?;
+        Ok(key)
+    }
+}
+
+fn decode_hex(value: &str) -> Result<Vec<u8>, KeyStoreError> {
+    hex::decode(value).map_err(|err| KeyStoreError::Corrupt(err.to_string()))
+}
+
+fn decode_private_key(value: &str) -> Result<PrivateKey, KeyStoreError> {
+    private_key_from_bytes(&decode_hex(value)?)
+}
+
+fn private_key_from_bytes(bytes: &[u8]) -> Result<PrivateKey, KeyStoreError> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| KeyStoreError::Corrupt("invalid private key length".into()))?;
+    Ok(PrivateKey::from_bytes(&bytes))
+}
+
+fn read_secret(path: &Path) -> Result<Vec<u8>, KeyStoreError> {
+    let mut file = fs::File::open(path)?;
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(contents)
+}
+
+/// Write secret bytes to a file which is only readable by the current user.
+///
+/// The bytes are written to a temporary file in the same directory first which is then renamed,
+/// so a crash never leaves a truncated key behind. Existing files are only replaced if `overwrite`
+/// is set.
+fn write_secret(path: &Path, bytes: &[u8], overwrite: bool) -> Result<(), KeyStoreError> {
+    let parent = path
+        .parent()
+        .ok_or_else(|| KeyStoreError::Inaccessible("key store path has no parent".into()))?;
+    fs::create_dir_all(parent)?;
+
+    let mut file = tempfile::NamedTempFile::new_in(parent)?;
+    file.write_all(bytes)?;
+    file.as_file().sync_all()?;
+
+    let mut permissions = file.as_file().metadata()?.permissions();
+
+    #[cfg(windows)]
+    permissions.set_readonly(true);
+    #[cfg(not(windows))]
+    permissions.set_mode(0o600);
+
+    fs::set_permissions(file.path(), permissions)?;
+
+    if overwrite {
+        // Read-only files can't be replaced on Windows.
+        #[cfg(windows)]
+        make_writable(path)?;
+
+        file.persist(path).map_err(|err| err.error)?;
+    } else {
+        file.persist_noclobber(path).map_err(|err| err.error)?;
+    }
+
+    Ok(())
+}
+
+fn remove_secret(path: &Path) -> Result<(), KeyStoreError> {
+    if path.exists() {
+        #[cfg(windows)]
+        make_writable(path)?;
+
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+fn make_writable(path: &Path) -> io::Result<()> {
+    if path.exists() {
+        let mut permissions = fs::metadata(path)?.permissions();
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+        fs::set_permissions(path, permissions)?;
+    }
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum KeyStoreError {
+    #[error("no private key found")]
+    NotFound,
+
+    #[error("private key could not be accessed: {0}")]
+    Inaccessible(String),
+
+    #[error("private key is corrupt: {0}")]
+    Corrupt(String),
+
+    #[error("wrong passphrase or tampered key file")]
+    WrongPassphrase,
+
+    #[error("passphrase required")]
+    PassphraseRequired,
+
+    #[error("refusing to overwrite existing private key")]
+    AlreadyExists,
+
+    #[error("key file version {0} is not supported")]
+    UnsupportedVersion(u32),
+
+    #[error("key store backend is not supported on this platform")]
+    Unsupported,
+
+    #[error("in-memory key store can't hold the private key across restarts")]
+    NotPersistent,
+}
+
+impl From<io::Error> for KeyStoreError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::NotFound => Self::NotFound,
+            io::ErrorKind::AlreadyExists => Self::AlreadyExists,
+            _ => Self::Inaccessible(err.to_string()),
+        }
+    }
+}
+
+impl From<serde_json::Error> for KeyStoreError {
+    fn from(err: serde_json::Error) -> Self {
+        Self::Corrupt(err.to_string())
+    }
+}
+
+#[cfg(any(
+    target_os = "macos",
+    target_os = "ios",
+    target_os = "windows",
+    target_os = "linux"
+))]
+impl From<keyring::Error> for KeyStoreError {
+    fn from(err: keyring::Error) -> Self {
+        match err {
+            keyring::Error::NoEntry => Self::NotFound,
+            keyring::Error::BadEncoding(_) => Self::Corrupt(err.to_string()),
+            _ => Self::Inaccessible(err.to_string()),
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
+
     use p2panda_core::PrivateKey;
     use tempfile::tempdir;
 
-    use super::KeyStore;
+    use super::{
+        EncryptedFileKeyStore, FileKeyStore, KeyFile, KeyStore, KeyStoreError, MemoryKeyStore,
+        PlainKey, KEY_FILE_VERSION,
+    };
 
     #[test]
     fn load_and_save_private_key() {
         let tmp_dir = tempdir().unwrap();
-        let file_path = tmp_dir.path().join("test_secret.txt");
+        let key_store = FileKeyStore::new(tmp_dir.path().join("test_secret.txt"));
 
-        // Ensure load attempt fails.
-        let load_attempt = PrivateKey::load(&file_path);
-        assert!(load_attempt.is_err());
+        // Ensure there is no key yet.
+        assert!(matches!(key_store.load(), Err(KeyStoreError::NotFound)));
 
         // Attempt to load nonexistent private key from file (creates a new one).
-        let private_key = PrivateKey::load_or_create_new(&file_path).unwrap();
+        let private_key = key_store.load_or_create_new().unwrap();
 
         // Ensure the private key was saved to file by `load_or_create_new()`.
-        let load_attempt = PrivateKey::load(&file_path);
-        assert!(load_attempt.is_ok());
+        assert!(key_store.load().is_ok());
 
         // Load the private key from file and ensure it matches the original.
-        let retrieved_private_key = PrivateKey::load_or_create_new(&file_path).unwrap();
+        let retrieved_private_key = key_store.load_or_create_new().unwrap();
         assert_eq!(private_key.as_bytes(), retrieved_private_key.as_bytes());
+
+        // An existing key is only overwritten when it is explicitly replaced.
+        let other_private_key = PrivateKey::new();
+        assert!(matches!(
+            key_store.save(&other_private_key),
+            Err(KeyStoreError::AlreadyExists)
+        ));
+        key_store.replace(&other_private_key).unwrap();
+        assert_eq!(
+            key_store.load().unwrap().as_bytes(),
+            other_private_key.as_bytes()
+        );
+
+        key_store.delete().unwrap();
+        assert!(matches!(key_store.load(), Err(KeyStoreError::NotFound)));
+    }
+
+    #[test]
+    fn refuse_to_replace_corrupt_private_key() {
+        let tmp_dir = tempdir().unwrap();
+        let file_path = tmp_dir.path().join("private_key.txt");
+        let key_store = FileKeyStore::new(file_path.clone());
+
+        // Key files written before the envelope was introduced can still be read.
+        let private_key = PrivateKey::new();
+        fs::write(&file_path, private_key.to_hex()).unwrap();
+        assert_eq!(
+            key_store.load_or_create_new().unwrap().as_bytes(),
+            private_key.as_bytes()
+        );
+
+        // A corrupt key is reported and left untouched.
+        fs::write(&file_path, "not a key").unwrap();
+        assert!(matches!(
+            key_store.load_or_create_new(),
+            Err(KeyStoreError::Corrupt(_))
+        ));
+        assert_eq!(fs::read_to_string(&file_path).unwrap(), "not a key");
+
+        // The private key must match the public key stored next to it.
+        let key_file = KeyFile {
+            version: KEY_FILE_VERSION,
+            public_key: Some(PrivateKey::new().public_key()),
+            key: PlainKey {
+                private_key: private_key.to_hex(),
+            },
+        };
+        fs::write(&file_path, serde_json::to_vec(&key_file).unwrap()).unwrap();
+        assert!(matches!(key_store.load(), Err(KeyStoreError::Corrupt(_))));
+
+        // Files from newer versions of the app are not interpreted.
+        fs::write(&file_path, r#"{"version":99,"private_key":""}"#).unwrap();
+        assert!(matches!(
+            key_store.load(),
+            Err(KeyStoreError::UnsupportedVersion(99))
+        ));
+    }
+
+    #[test]
+    fn save_and_load_encrypted_private_key() {
+        let tmp_dir = tempdir().unwrap();
+        let file_path = tmp_dir.path().join("private_key.enc");
+        let key_store =
+            EncryptedFileKeyStore::new(file_path.clone(), "correct horse battery staple");
+
+        let private_key = PrivateKey::new();
+        key_store.save(&private_key).unwrap();
+
+        // The key is not stored in plain text.
+        let contents = fs::read_to_string(&file_path).unwrap();
+        assert!(!contents.contains(&private_key.to_hex()));
+
+        let loaded_private_key = key_store.load().unwrap();
+        assert_eq!(private_key.as_bytes(), loaded_private_key.as_bytes());
+
+        let key_store = EncryptedFileKeyStore::new(file_path.clone(), "wrong passphrase");
+        assert!(matches!(
+            key_store.load(),
+            Err(KeyStoreError::WrongPassphrase)
+        ));
+        assert!(matches!(
+            FileKeyStore::new(file_path).load(),
+            Err(KeyStoreError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn refuse_excessive_kdf_parameters() {
+        let tmp_dir = tempdir().unwrap();
+        let file_path = tmp_dir.path().join("private_key.enc");
+        let key_store = EncryptedFileKeyStore::new(file_path.clone(), "organize!");
+        key_store.save(&PrivateKey::new()).unwrap();
+
+        let mut key_file: serde_json::Value =
+            serde_json::from_slice(&fs::read(&file_path).unwrap()).unwrap();
+        key_file["kdf"]["memory"] = u32::MAX.into();
+        fs::write(&file_path, serde_json::to_vec(&key_file).unwrap()).unwrap();
+
+        assert!(matches!(key_store.load(), Err(KeyStoreError::Corrupt(_))));
+    }
+
+    #[test]
+    fn memory_key_store() {
+        let key_store = MemoryKeyStore::default();
+        assert!(matches!(key_store.load(), Err(KeyStoreError::NotFound)));
+
+        let private_key = key_store.load_or_create_new().unwrap();
+        assert_eq!(key_store.load().unwrap().as_bytes(), private_key.as_bytes());
+
+        key_store.delete().unwrap();
+        assert!(matches!(key_store.load(), Err(KeyStoreError::NotFound)));
     }
 }
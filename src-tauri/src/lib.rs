@@ -1,16 +1,40 @@
 mod app;
+mod backup;
 mod blobs;
+mod bundle;
+mod capabilities;
+mod delegation;
+mod dependencies;
 mod extensions;
+mod gc;
+mod inventory;
 mod keystore;
 mod messages;
+mod migrations;
+mod mnemonic;
+mod payload;
+mod profiles;
 mod rpc;
+mod schema;
+mod signing;
+mod store;
+mod succession;
+#[cfg(test)]
+mod test_utils;
+mod topic_map;
+mod unlock;
 
 use tauri::Builder;
 use tracing_subscriber::EnvFilter;
 
 use crate::rpc::{
-    ack, add_topic_log, init, public_key, publish_ephemeral, publish_persisted, replay,
-    subscribe_ephemeral, subscribe_persisted, upload_file,
+    ack, active_profile, add_topic_log, collect_garbage, create_profile, delegate_device,
+    delete_profile, export_backup, export_mnemonic, export_stream, import_backup, import_stream,
+    init, list_profiles, locked, outbox, pin_blob, public_key, publish_ephemeral,
+    publish_persisted, remove_topic_log, replay, request_delegation, restore_mnemonic, rotate_key,
+    schema_versions, set_key_store, set_schema_versions, set_storage_quota, set_write_access, sign,
+    storage_quota, stream, streams, subscribe_ephemeral, subscribe_persisted, switch_profile,
+    unlock, upload_file, verify,
 };
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -49,12 +73,42 @@ pub fn run() {
             ack,
             public_key,
             add_topic_log,
+            remove_topic_log,
             publish_persisted,
             publish_ephemeral,
+            set_write_access,
             replay,
             subscribe_persisted,
             subscribe_ephemeral,
             upload_file,
+            export_backup,
+            import_backup,
+            export_mnemonic,
+            restore_mnemonic,
+            rotate_key,
+            request_delegation,
+            delegate_device,
+            sign,
+            verify,
+            export_stream,
+            import_stream,
+            storage_quota,
+            set_storage_quota,
+            schema_versions,
+            set_schema_versions,
+            streams,
+            stream,
+            collect_garbage,
+            pin_blob,
+            outbox,
+            locked,
+            unlock,
+            set_key_store,
+            list_profiles,
+            active_profile,
+            create_profile,
+            switch_profile,
+            delete_profile,
         ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
@@ -7,7 +7,14 @@ use p2panda_node::topic::Topic;
 use serde::ser::SerializeStruct;
 use serde::{Deserialize, Serialize};
 
-use crate::extensions::{to_log_id, Extensions, LogPath, Stream, StreamOwner, StreamRootHash};
+use crate::extensions::{
+    to_log_id, Blobs, ContentType, Dependencies, ExpiresAt, Extensions, LogPath, SchemaVersion,
+    Stream, StreamOwner, StreamRootHash,
+};
+use crate::gc::GcReport;
+use crate::payload::Payload;
+use crate::profiles::Profile;
+use crate::schema::SchemaVersions;
 
 #[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -17,6 +24,39 @@ pub struct StreamArgs {
     pub(crate) owner: Option<PublicKey>,
 }
 
+/// Optional settings for publishing an operation to a persisted topic.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct PublishOptions {
+    /// Mark the operation as a prune point, all earlier operations in the same log are deleted.
+    pub(crate) prune: bool,
+
+    /// Version of the payload format, omitted for unversioned payloads.
+    pub(crate) schema_version: Option<SchemaVersion>,
+
+    /// Encoding of the payload, payloads which are not JSON are passed as base64 strings.
+    pub(crate) content_type: ContentType,
+
+    /// Operations which need to be delivered before this one.
+    pub(crate) dependencies: Dependencies,
+
+    /// UNIX timestamp in seconds after which the operation expires.
+    pub(crate) expires_at: Option<ExpiresAt>,
+
+    /// Blobs referenced by the payload, they are backed up and bundled with the operation.
+    pub(crate) blobs: Blobs,
+}
+
+/// Whether a new operation reached the node or waits in the outbox for another attempt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PublishStatus {
+    Published,
+
+    /// Publishing failed, the operation is retried from the outbox.
+    Queued,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq, StdHash, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ToolkittyLogId {
@@ -36,15 +76,32 @@ pub enum ChannelEvent {
     Stream(ToolkittyStreamEvent),
     SubscribedToTopic(Topic),
     NetworkEvent(NetworkEvent),
+    GarbageCollected(GcReport),
+    ProfileSwitched(Profile),
 }
 
 #[allow(dead_code)]
 #[derive(Clone, Debug, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum ToolkittyEventData {
-    Application(serde_json::Value),
-    Ephemeral(serde_json::Value),
+    Application(Payload),
+    Ephemeral(Payload),
     Error(StreamError),
+
+    /// Operation which was rejected by the backend, for example because the author had no write
+    /// access to the log.
+    Rejected(String),
+
+    /// Operation with a payload schema version outside of the supported range. The payload is
+    /// withheld, the data contains the supported range instead.
+    UnsupportedSchema(SchemaVersions),
+
+    /// Operation which is held back because its dependencies did not arrive in time. It is
+    /// delivered as soon as they do, the data lists the missing operations.
+    MissingDependencies(Vec<Hash>),
+
+    /// Ephemeral message which could not be decoded, the data is the reason.
+    InvalidEphemeral(String),
 }
 
 impl ToolkittyEventData {
@@ -52,7 +109,10 @@ impl ToolkittyEventData {
         match self {
             ToolkittyEventData::Application(_) => "application",
             ToolkittyEventData::Ephemeral(_) => "ephemeral",
-            ToolkittyEventData::Error(_) => "error",
+            ToolkittyEventData::Error(_) | ToolkittyEventData::Rejected(_) => "error",
+            ToolkittyEventData::UnsupportedSchema(_) => "unsupported_schema",
+            ToolkittyEventData::MissingDependencies(_) => "missing_dependencies",
+            ToolkittyEventData::InvalidEphemeral(_) => "invalid_ephemeral",
         }
     }
 }
@@ -86,29 +146,54 @@ impl From<StreamEvent<Extensions>> for ToolkittyStreamEvent {
 }
 
 impl ToolkittyStreamEvent {
+    /// Decode the payload of an operation according to its content type. Payloads which can't
+    /// be decoded are reported as rejected.
     pub fn from_operation(header: Header<Extensions>, body: Body) -> Self {
-        let json = serde_json::from_slice(&body.to_bytes()).unwrap();
+        let content_type: ContentType = header.extension().unwrap_or_default();
+        match Payload::from_bytes(&body.to_bytes(), content_type) {
+            Ok(payload) => Self {
+                meta: Some(header.into()),
+                data: ToolkittyEventData::Application(payload),
+            },
+            Err(err) => Self::rejected(header, err.to_string()),
+        }
+    }
 
+    /// Decode an ephemeral message. Messages which can't be decoded are reported as invalid.
+    pub fn from_bytes(payload: Vec<u8>) -> Self {
+        let data = match Payload::from_ephemeral(&payload) {
+            Ok(payload) => ToolkittyEventData::Ephemeral(payload),
+            Err(err) => ToolkittyEventData::InvalidEphemeral(err.to_string()),
+        };
+        Self { meta: None, data }
+    }
+
+    #[allow(dead_code)]
+    pub fn from_error(error: StreamError, header: Header<Extensions>) -> Self {
         Self {
             meta: Some(header.into()),
-            data: ToolkittyEventData::Application(json),
+            data: ToolkittyEventData::Error(error),
         }
     }
 
-    pub fn from_bytes(payload: Vec<u8>) -> Self {
-        let json = serde_json::from_slice(&payload).unwrap();
+    pub fn rejected(header: Header<Extensions>, reason: String) -> Self {
+        Self {
+            meta: Some(header.into()),
+            data: ToolkittyEventData::Rejected(reason),
+        }
+    }
 
+    pub fn unsupported_schema(header: Header<Extensions>, supported: SchemaVersions) -> Self {
         Self {
-            meta: None,
-            data: ToolkittyEventData::Ephemeral(json),
+            meta: Some(header.into()),
+            data: ToolkittyEventData::UnsupportedSchema(supported),
         }
     }
 
-    #[allow(dead_code)]
-    pub fn from_error(error: StreamError, header: Header<Extensions>) -> Self {
+    pub fn missing_dependencies(header: Header<Extensions>, missing: Vec<Hash>) -> Self {
         Self {
             meta: Some(header.into()),
-            data: ToolkittyEventData::Error(error),
+            data: ToolkittyEventData::MissingDependencies(missing),
         }
     }
 }
@@ -120,12 +205,27 @@ impl Serialize for ToolkittyStreamEvent {
     {
         let mut state = serializer.serialize_struct("StreamEvent", 3)?;
         state.serialize_field("event", &self.data.tag())?;
-        state.serialize_field("meta", &self.meta)?;
+        match (&self.meta, &self.data) {
+            (None, ToolkittyEventData::Ephemeral(payload)) => state.serialize_field(
+                "meta",
+                &EphemeralMeta {
+                    content_type: payload.content_type(),
+                },
+            )?,
+            (meta, _) => state.serialize_field("meta", meta)?,
+        }
         state.serialize_field("data", &self.data)?;
         state.end()
     }
 }
 
+/// Meta data of ephemeral messages, which have no operation header.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EphemeralMeta {
+    pub content_type: ContentType,
+}
+
 #[derive(Clone, Debug, PartialEq, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct StreamMeta {
@@ -149,8 +249,19 @@ impl From<Stream> for StreamMeta {
 pub struct ToolkittyEventMeta {
     pub operation_id: Hash,
     pub author: PublicKey,
+
+    /// Identity of the author, this is the first key of the author's key succession chain. For
+    /// delegated devices it is the identity which delegated the device.
+    pub identity: PublicKey,
+
     pub stream: StreamMeta,
+
+    /// `true` if the author belongs to the same identity as the stream owner.
+    pub owns_stream: bool,
+
     pub log_path: Option<LogPath>,
+    pub schema_version: SchemaVersion,
+    pub content_type: ContentType,
     pub timestamp: u64,
 }
 
@@ -158,12 +269,18 @@ impl From<Header<Extensions>> for ToolkittyEventMeta {
     fn from(header: Header<Extensions>) -> Self {
         let stream: Stream = header.extension().expect("extract stream id extensions");
         let log_path: Option<LogPath> = header.extension();
+        let schema_version: SchemaVersion = header.extension().unwrap_or_default();
+        let content_type: ContentType = header.extension().unwrap_or_default();
 
         Self {
             operation_id: header.hash(),
             author: header.public_key,
+            identity: header.public_key,
+            owns_stream: stream.owner == header.public_key.into(),
             stream: stream.into(),
             log_path,
+            schema_version,
+            content_type,
             timestamp: header.timestamp,
         }
     }
@@ -199,6 +316,18 @@ impl Serialize for ChannelEvent {
                 state.serialize_field("data", event)?;
                 state.end()
             }
+            ChannelEvent::GarbageCollected(report) => {
+                let mut state = serializer.serialize_struct("StreamEvent", 2)?;
+                state.serialize_field("event", "garbage_collected")?;
+                state.serialize_field("data", report)?;
+                state.end()
+            }
+            ChannelEvent::ProfileSwitched(profile) => {
+                let mut state = serializer.serialize_struct("StreamEvent", 2)?;
+                state.serialize_field("event", "profile_switched")?;
+                state.serialize_field("data", profile)?;
+                state.end()
+            }
         }
     }
 }
@@ -0,0 +1,273 @@
+//! Versioned layout of the app data directory.
+//!
+//! A manifest file in the app data directory records the layout version the data was written
+//! with. When the app starts all migrations between that version and the current one are applied
+//! in place, one step at a time. Data written by a newer version of the app is never touched.
+//!
+//! The schema of the SQLite database is versioned separately, see `store.rs`.
+
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tracing::warn;
+
+use crate::keystore::{FileKeyStore, KeyStore, KeyStoreError};
+use crate::store::{SqliteStore, StoreError};
+
+/// Layout version of the app data directory written by this version of the app.
+pub const LAYOUT_VERSION: u32 = 2;
+
+/// File name of the layout manifest inside the app data directory.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// File name of the private key inside the app data directory.
+pub const PRIVATE_KEY_FILE_NAME: &str = "private_key.txt";
+
+/// File name of the passphrase-encrypted private key inside the app data directory. Only one of
+/// both key files exists at a time.
+pub const ENCRYPTED_PRIVATE_KEY_FILE_NAME: &str = "private_key.enc";
+
+type Migration = fn(&Path) -> Result<(), MigrationError>;
+
+/// Migration steps, the step at index `n` upgrades the layout from version `n` to `n + 1`.
+const MIGRATIONS: [Migration; LAYOUT_VERSION as usize] = [migrate_v0_to_v1, migrate_v1_to_v2];
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Manifest {
+    version: u32,
+}
+
+/// Read the layout version of the app data directory.
+///
+/// Directories which contain data but no manifest were written before the layout was versioned
+/// and are treated as version 0. Returns `None` for a fresh installation.
+pub fn layout_version(app_data_dir: &Path) -> Result<Option<u32>, MigrationError> {
+    let manifest_path = app_data_dir.join(MANIFEST_FILE_NAME);
+    if manifest_path.exists() {
+        let manifest: Manifest = serde_json::from_slice(&fs::read(manifest_path)?)?;
+        return Ok(Some(manifest.version));
+    }
+
+    let is_empty = !app_data_dir.exists() || fs::read_dir(app_data_dir)?.next().is_none();
+    Ok(if is_empty { None } else { Some(0) })
+}
+
+/// Upgrade the app data directory to the current layout version.
+pub fn migrate(app_data_dir: &Path) -> Result<(), MigrationError> {
+    let Some(mut version) = layout_version(app_data_dir)? else {
+        fs::create_dir_all(app_data_dir)?;
+        return write_manifest(app_data_dir, LAYOUT_VERSION);
+    };
+
+    if version > LAYOUT_VERSION {
+        return Err(MigrationError::UnsupportedVersion {
+            found: version,
+            supported: LAYOUT_VERSION,
+        });
+    }
+
+    while version < LAYOUT_VERSION {
+        MIGRATIONS[version as usize](app_data_dir)?;
+        version += 1;
+        write_manifest(app_data_dir, version)?;
+    }
+
+    Ok(())
+}
+
+fn write_manifest(app_data_dir: &Path, version: u32) -> Result<(), MigrationError> {
+    let manifest_path = app_data_dir.join(MANIFEST_FILE_NAME);
+    let tmp_path = manifest_path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&Manifest { version })?)?;
+    fs::rename(tmp_path, manifest_path)?;
+    Ok(())
+}
+
+/// Version 0 to 1: Rewrite the private key into the versioned key file envelope and create the
+/// database which holds topic maps, subscriptions and acknowledgements from now on.
+///
+/// Version 0 is the layout of the first releases: a key file with nothing but the hex-encoded
+/// private key next to the blob store, topic maps only lived in memory. Key files which can't be
+/// read are left untouched, loading them reports the error later.
+fn migrate_v0_to_v1(app_data_dir: &Path) -> Result<(), MigrationError> {
+    SqliteStore::open(app_data_dir)?;
+
+    let private_key_path = app_data_dir.join(PRIVATE_KEY_FILE_NAME);
+    if private_key_path.exists() {
+        let key_store = FileKeyStore::new(private_key_path);
+        match key_store.load() {
+            Ok(private_key) => key_store.replace(&private_key)?,
+            Err(err) => warn!("not upgrading unreadable private key file: {err}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Version 1 to 2: Re-encode persisted log ids, the earlier encoding could not tell a log without
+/// path apart from one with an empty path.
+fn migrate_v1_to_v2(app_data_dir: &Path) -> Result<(), MigrationError> {
+    SqliteStore::open(app_data_dir)?.migrate_log_ids()?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum MigrationError {
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+
+    #[error("invalid layout manifest: {0}")]
+    Manifest(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error("app data was written by a newer version (layout {found}, supported {supported})")]
+    UnsupportedVersion { found: u32, supported: u32 },
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use p2panda_core::{Body, PrivateKey};
+    use p2panda_node::extensions::LogId;
+    use tempfile::tempdir;
+
+    use crate::extensions::{to_log_id, Extensions, LogPath, Stream};
+    use crate::keystore::{FileKeyStore, KeyStore, KEY_FILE_VERSION};
+    use crate::store::{SqliteStore, DATABASE_FILE_NAME};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{
+        layout_version, migrate, write_manifest, MigrationError, LAYOUT_VERSION,
+        PRIVATE_KEY_FILE_NAME,
+    };
+
+    #[test]
+    fn fresh_install() {
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = tmp_dir.path().join("app");
+
+        assert_eq!(layout_version(&app_data_dir).unwrap(), None);
+        migrate(&app_data_dir).unwrap();
+        assert_eq!(layout_version(&app_data_dir).unwrap(), Some(LAYOUT_VERSION));
+    }
+
+    #[test]
+    fn migrate_v0_to_v1() {
+        let tmp_dir = tempdir().unwrap();
+        let private_key = PrivateKey::new();
+        let private_key_path = tmp_dir.path().join(PRIVATE_KEY_FILE_NAME);
+        fs::write(&private_key_path, private_key.to_hex()).unwrap();
+
+        assert_eq!(layout_version(tmp_dir.path()).unwrap(), Some(0));
+        migrate(tmp_dir.path()).unwrap();
+        assert_eq!(
+            layout_version(tmp_dir.path()).unwrap(),
+            Some(LAYOUT_VERSION)
+        );
+        assert!(tmp_dir.path().join(DATABASE_FILE_NAME).exists());
+
+        // The key was rewritten into the envelope and is still only readable by us.
+        let key_file: serde_json::Value =
+            serde_json::from_slice(&fs::read(&private_key_path).unwrap()).unwrap();
+        assert_eq!(key_file["private_key"], private_key.to_hex());
+        assert_eq!(key_file["version"], KEY_FILE_VERSION);
+
+        #[cfg(not(windows))]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&private_key_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        // Running the migrations again is a no-op.
+        migrate(tmp_dir.path()).unwrap();
+        assert_eq!(
+            layout_version(tmp_dir.path()).unwrap(),
+            Some(LAYOUT_VERSION)
+        );
+        let key_store = FileKeyStore::new(private_key_path);
+        assert_eq!(key_store.load().unwrap().as_bytes(), private_key.as_bytes());
+    }
+
+    #[test]
+    fn keep_unreadable_private_key() {
+        let tmp_dir = tempdir().unwrap();
+        let private_key_path = tmp_dir.path().join(PRIVATE_KEY_FILE_NAME);
+        fs::write(&private_key_path, "not a key").unwrap();
+
+        migrate(tmp_dir.path()).unwrap();
+        assert_eq!(fs::read_to_string(&private_key_path).unwrap(), "not a key");
+    }
+
+    #[test]
+    fn migrate_v1_to_v2() {
+        let tmp_dir = tempdir().unwrap();
+        let private_key = PrivateKey::new();
+        let log_path = LogPath::try_from("calendar".to_string()).unwrap();
+
+        let body = Body::new(b"{}");
+        let header = create_header(
+            &private_key,
+            &body,
+            with_extensions(Extensions {
+                log_path: Some(log_path.clone()),
+                ..Default::default()
+            }),
+        );
+        let stream: Stream = header.extension().unwrap();
+
+        {
+            let store = SqliteStore::open(tmp_dir.path()).unwrap();
+            let legacy_log_id = LogId(format!("{}/calendar", stream.id()));
+            store
+                .insert_operation(&header, Some(&body), &legacy_log_id)
+                .unwrap();
+            store
+                .insert_topic_log("calendar", &private_key.public_key(), &legacy_log_id)
+                .unwrap();
+            store
+                .insert_topic_log(
+                    "calendar",
+                    &private_key.public_key(),
+                    &LogId("unknown/".into()),
+                )
+                .unwrap();
+        }
+        write_manifest(tmp_dir.path(), 1).unwrap();
+
+        migrate(tmp_dir.path()).unwrap();
+        assert_eq!(layout_version(tmp_dir.path()).unwrap(), Some(2));
+
+        let store = SqliteStore::open(tmp_dir.path()).unwrap();
+        assert_eq!(
+            store.topic_logs().unwrap(),
+            vec![(
+                "calendar".to_string(),
+                private_key.public_key(),
+                to_log_id(stream, Some(log_path))
+            )]
+        );
+    }
+
+    #[test]
+    fn refuse_newer_version() {
+        let tmp_dir = tempdir().unwrap();
+        write_manifest(tmp_dir.path(), LAYOUT_VERSION + 1).unwrap();
+
+        assert!(matches!(
+            migrate(tmp_dir.path()),
+            Err(MigrationError::UnsupportedVersion { .. })
+        ));
+    }
+}
@@ -0,0 +1,111 @@
+//! Human-readable backup of the private key as a BIP39 word list.
+//!
+//! The 32 bytes of the Ed25519 private key are used as entropy for a 24 word mnemonic from the
+//! English BIP39 word list. The last word contains a checksum, so typos are detected when an
+//! identity is restored instead of silently creating a different one.
+
+use bip39::{Language, Mnemonic};
+use p2panda_core::PrivateKey;
+use thiserror::Error;
+
+/// Number of words of a mnemonic encoding a private key.
+pub const MNEMONIC_WORD_COUNT: usize = 24;
+
+/// Encode the private key as a mnemonic of 24 space-separated words.
+pub fn to_mnemonic(private_key: &PrivateKey) -> String {
+    Mnemonic::from_entropy_in(Language::English, private_key.as_bytes())
+        .expect("32 bytes are valid entropy")
+        .to_string()
+}
+
+/// Decode a private key from its mnemonic.
+///
+/// Words are matched case-insensitively and can be separated by any whitespace.
+pub fn from_mnemonic(words: &str) -> Result<PrivateKey, MnemonicError> {
+    let words = words
+        .split_whitespace()
+        .map(|word| word.to_lowercase())
+        .collect::<Vec<_>>();
+    if words.len() != MNEMONIC_WORD_COUNT {
+        return Err(MnemonicError::WordCount(words.len()));
+    }
+
+    let mnemonic = Mnemonic::parse_in_normalized(Language::English, &words.join(" ")).map_err(
+        |err| match err {
+            bip39::Error::UnknownWord(index) => {
+                MnemonicError::UnknownWord(index + 1, words[index].clone())
+            }
+            bip39::Error::InvalidChecksum => MnemonicError::InvalidChecksum,
+            err => MnemonicError::Invalid(err.to_string()),
+        },
+    )?;
+
+    let bytes: [u8; 32] = mnemonic
+        .to_entropy()
+        .as_slice()
+        .try_into()
+        .map_err(|_| MnemonicError::WordCount(words.len()))?;
+    Ok(PrivateKey::from_bytes(&bytes))
+}
+
+#[derive(Debug, Error)]
+pub enum MnemonicError {
+    #[error("expected {MNEMONIC_WORD_COUNT} words, got {0}")]
+    WordCount(usize),
+
+    #[error("word {0} \"{1}\" is not in the word list")]
+    UnknownWord(usize, String),
+
+    #[error("checksum does not match, please check the words and their order")]
+    InvalidChecksum,
+
+    #[error("invalid mnemonic: {0}")]
+    Invalid(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::PrivateKey;
+
+    use super::{from_mnemonic, to_mnemonic, MnemonicError, MNEMONIC_WORD_COUNT};
+
+    #[test]
+    fn mnemonic_roundtrip() {
+        let private_key = PrivateKey::new();
+        let words = to_mnemonic(&private_key);
+        assert_eq!(words.split(' ').count(), MNEMONIC_WORD_COUNT);
+
+        let restored = from_mnemonic(&words).unwrap();
+        assert_eq!(restored.as_bytes(), private_key.as_bytes());
+
+        // Case and whitespace don't matter.
+        let restored = from_mnemonic(&format!("  {}\n", words.to_uppercase())).unwrap();
+        assert_eq!(restored.as_bytes(), private_key.as_bytes());
+    }
+
+    #[test]
+    fn reject_invalid_mnemonics() {
+        let words = to_mnemonic(&PrivateKey::new());
+        let mut words = words.split(' ').collect::<Vec<_>>();
+
+        assert!(matches!(
+            from_mnemonic(&words[1..].join(" ")),
+            Err(MnemonicError::WordCount(23))
+        ));
+
+        words[3] = "toolkitty";
+        assert!(matches!(
+            from_mnemonic(&words.join(" ")),
+            Err(MnemonicError::UnknownWord(4, _))
+        ));
+
+        // All words are known but the checksum in the last word doesn't match.
+        let words = (0..MNEMONIC_WORD_COUNT)
+            .map(|index| if index == 0 { "zoo" } else { "abandon" })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            from_mnemonic(&words.join(" ")),
+            Err(MnemonicError::InvalidChecksum)
+        ));
+    }
+}
@@ -0,0 +1,188 @@
+//! Payloads of operations and ephemeral messages.
+//!
+//! Payloads are JSON by default, compact data and CRDT updates can be published as CBOR or raw
+//! binary instead. The content type of an operation payload is recorded in its header. Between
+//! frontend and backend JSON payloads are exchanged as they are, all other payloads as base64
+//! strings.
+//!
+//! Ephemeral messages have no header to record the content type in. Their payload is wrapped in a
+//! small CBOR envelope which carries the content type instead. Older peers send bare JSON
+//! messages, these are still accepted.
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use p2panda_core::cbor::{decode_cbor, encode_cbor, DecodeError};
+use serde::de::IgnoredAny;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::extensions::ContentType;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    Json(serde_json::Value),
+    Cbor(Vec<u8>),
+    Binary(Vec<u8>),
+}
+
+impl Payload {
+    /// Decode a payload passed by the frontend, payloads which are not JSON are expected as
+    /// base64 strings.
+    pub fn from_value(
+        value: serde_json::Value,
+        content_type: ContentType,
+    ) -> Result<Self, PayloadError> {
+        if content_type == ContentType::Json {
+            return Ok(Self::Json(value));
+        }
+
+        let serde_json::Value::String(encoded) = value else {
+            return Err(PayloadError::ExpectedBase64);
+        };
+        let bytes = BASE64_STANDARD.decode(encoded)?;
+        Self::from_bytes(&bytes, content_type)
+    }
+
+    /// Decode the bytes of a payload with the given content type.
+    pub fn from_bytes(bytes: &[u8], content_type: ContentType) -> Result<Self, PayloadError> {
+        match content_type {
+            ContentType::Json => Ok(Self::Json(serde_json::from_slice(bytes)?)),
+            ContentType::Cbor => {
+                decode_cbor::<IgnoredAny, _>(bytes)?;
+                Ok(Self::Cbor(bytes.to_vec()))
+            }
+            ContentType::Binary => Ok(Self::Binary(bytes.to_vec())),
+        }
+    }
+
+    /// Decode an ephemeral message according to the content type in its envelope. Messages
+    /// without an envelope are decoded as JSON.
+    pub fn from_ephemeral(bytes: &[u8]) -> Result<Self, PayloadError> {
+        match decode_cbor::<EphemeralEnvelope, _>(bytes) {
+            Ok(envelope) => Self::from_bytes(&envelope.payload, envelope.content_type),
+            Err(err) => Self::from_bytes(bytes, ContentType::Json).map_err(|_| err.into()),
+        }
+    }
+
+    /// Encode the payload as an ephemeral message.
+    pub fn to_ephemeral(&self) -> Vec<u8> {
+        encode_cbor(&EphemeralEnvelope {
+            content_type: self.content_type(),
+            payload: self.to_bytes(),
+        })
+        .expect("encode ephemeral message")
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Payload::Json(_) => ContentType::Json,
+            Payload::Cbor(_) => ContentType::Cbor,
+            Payload::Binary(_) => ContentType::Binary,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Payload::Json(value) => serde_json::to_vec(value).expect("encode json payload"),
+            Payload::Cbor(bytes) | Payload::Binary(bytes) => bytes.clone(),
+        }
+    }
+}
+
+impl Serialize for Payload {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match self {
+            Payload::Json(value) => value.serialize(serializer),
+            Payload::Cbor(bytes) | Payload::Binary(bytes) => {
+                serializer.serialize_str(&BASE64_STANDARD.encode(bytes))
+            }
+        }
+    }
+}
+
+/// Envelope of an ephemeral message.
+#[derive(Serialize, Deserialize)]
+struct EphemeralEnvelope {
+    #[serde(rename = "c")]
+    content_type: ContentType,
+
+    #[serde(rename = "p", with = "serde_bytes")]
+    payload: Vec<u8>,
+}
+
+#[derive(Debug, Error)]
+pub enum PayloadError {
+    #[error("payloads which are not json are expected as base64 strings")]
+    ExpectedBase64,
+
+    #[error("invalid base64 payload: {0}")]
+    Base64(#[from] base64::DecodeError),
+
+    #[error("invalid json payload: {0}")]
+    Json(#[from] serde_json::Error),
+
+    #[error("invalid cbor payload: {0}")]
+    Cbor(#[from] DecodeError),
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::extensions::ContentType;
+
+    use super::{Payload, PayloadError};
+
+    #[test]
+    fn decode_payloads() {
+        let payload = Payload::from_value(json!({ "type": "event" }), ContentType::Json).unwrap();
+        assert_eq!(
+            Payload::from_bytes(&payload.to_bytes(), ContentType::Json).unwrap(),
+            payload
+        );
+
+        // CBOR encoding of the map `{"a": 1}`.
+        let payload = Payload::from_value(json!("oWFhAQ=="), ContentType::Cbor).unwrap();
+        assert_eq!(payload, Payload::Cbor(vec![0xa1, 0x61, 0x61, 0x01]));
+        assert_eq!(serde_json::to_value(&payload).unwrap(), json!("oWFhAQ=="));
+        assert!(matches!(
+            Payload::from_bytes(&[0xa1, 0x61], ContentType::Cbor),
+            Err(PayloadError::Cbor(_))
+        ));
+
+        let payload = Payload::from_value(json!("AAE="), ContentType::Binary).unwrap();
+        assert_eq!(payload, Payload::Binary(vec![0, 1]));
+        assert!(matches!(
+            Payload::from_value(json!([0, 1]), ContentType::Binary),
+            Err(PayloadError::ExpectedBase64)
+        ));
+    }
+
+    #[test]
+    fn ephemeral_payloads() {
+        let payload = Payload::Json(json!({ "code": "1234" }));
+        assert_eq!(
+            Payload::from_ephemeral(&payload.to_ephemeral()).unwrap(),
+            payload
+        );
+
+        // Binary payloads stay binary even if they happen to be valid JSON.
+        let payload = Payload::Binary(b"1234".to_vec());
+        assert_eq!(
+            Payload::from_ephemeral(&payload.to_ephemeral()).unwrap(),
+            payload
+        );
+
+        // Messages of older peers without an envelope are JSON.
+        assert_eq!(
+            Payload::from_ephemeral(br#"{"code":"1234"}"#).unwrap(),
+            Payload::Json(json!({ "code": "1234" }))
+        );
+        assert!(matches!(
+            Payload::from_ephemeral(b"code: 1234"),
+            Err(PayloadError::Cbor(_))
+        ));
+    }
+}
@@ -0,0 +1,422 @@
+//! Local profiles with separate identities.
+//!
+//! Every profile has its own private key, database and blob store in a directory of its own
+//! inside the `profiles` directory. A registry file in the app data directory lists all profiles
+//! and records which one is active. Installations from before profiles were introduced kept their
+//! data directly in the app data directory, it is moved into the directory of the default profile
+//! when the registry is opened.
+//!
+//! Only one profile is active at a time. Switching profiles restarts the service with the data
+//! of the chosen profile.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::OsRng;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::sync::watch;
+
+use crate::keystore::{KeyStoreBackend, KeyStoreError};
+use crate::migrations::{self, MigrationError};
+use crate::store::{SqliteStore, StoreError, DATABASE_FILE_NAME};
+
+/// Id of the profile which was used before profiles were introduced.
+pub const DEFAULT_PROFILE_ID: &str = "default";
+
+/// Directory inside the app data directory where all profiles are stored.
+pub const PROFILES_DIR_NAME: &str = "profiles";
+
+/// Directory inside the profiles directory where data of the default profile is collected while
+/// it is moved out of the app data directory.
+const DEFAULT_PROFILE_STAGING_DIR_NAME: &str = ".default.tmp";
+
+/// File name of the profile registry inside the app data directory.
+pub const PROFILES_FILE_NAME: &str = "profiles.json";
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Profile {
+    /// Random id which also names the directory of the profile.
+    pub id: String,
+
+    /// Human-readable name chosen by the user.
+    pub name: String,
+
+    /// UNIX timestamp in seconds of when the profile was created.
+    pub created_at: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Registry {
+    active: String,
+    profiles: Vec<Profile>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            active: DEFAULT_PROFILE_ID.to_string(),
+            profiles: vec![Profile {
+                id: DEFAULT_PROFILE_ID.to_string(),
+                name: "Default".to_string(),
+                created_at: 0,
+            }],
+        }
+    }
+}
+
+impl Registry {
+    fn get(&self, id: &str) -> Result<&Profile, ProfileError> {
+        self.profiles
+            .iter()
+            .find(|profile| profile.id == id)
+            .ok_or_else(|| ProfileError::NotFound(id.to_string()))
+    }
+
+    fn active(&self) -> &Profile {
+        self.get(&self.active)
+            .expect("active profile is registered")
+    }
+}
+
+/// Registry of all local profiles.
+pub struct Profiles {
+    app_data_dir: PathBuf,
+    registry: Mutex<Registry>,
+
+    /// The active profile, the service is restarted whenever it changes.
+    active_tx: watch::Sender<Profile>,
+}
+
+impl Profiles {
+    /// Read the profile registry from the app data directory. Without a registry only the
+    /// default profile exists.
+    pub fn open(app_data_dir: PathBuf) -> Result<Self, ProfileError> {
+        migrate_default_profile(&app_data_dir)?;
+
+        let registry_path = app_data_dir.join(PROFILES_FILE_NAME);
+        let registry: Registry = if registry_path.exists() {
+            serde_json::from_slice(&fs::read(registry_path)?)?
+        } else {
+            Registry::default()
+        };
+
+        // Fall back to the default profile if the active one went missing.
+        let active = match registry.get(&registry.active) {
+            Ok(profile) => profile.clone(),
+            Err(_) => registry.get(DEFAULT_PROFILE_ID)?.clone(),
+        };
+
+        Ok(Self {
+            app_data_dir,
+            registry: Mutex::new(Registry {
+                active: active.id.clone(),
+                ..registry
+            }),
+            active_tx: watch::Sender::new(active),
+        })
+    }
+
+    /// Subscribe to changes of the active profile.
+    pub fn subscribe(&self) -> watch::Receiver<Profile> {
+        self.active_tx.subscribe()
+    }
+
+    pub fn active(&self) -> Profile {
+        self.registry
+            .lock()
+            .expect("acquire profiles lock")
+            .active()
+            .clone()
+    }
+
+    pub fn list(&self) -> Vec<Profile> {
+        self.registry
+            .lock()
+            .expect("acquire profiles lock")
+            .profiles
+            .clone()
+    }
+
+    /// Directory where all data of the given profile is stored.
+    pub fn dir(&self, id: &str) -> PathBuf {
+        self.app_data_dir.join(PROFILES_DIR_NAME).join(id)
+    }
+
+    /// Create a new profile. Its private key is generated when the profile is used for the
+    /// first time.
+    pub fn create(&self, name: &str) -> Result<Profile, ProfileError> {
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(ProfileError::InvalidName);
+        }
+
+        let mut id = [0; 8];
+        OsRng.fill_bytes(&mut id);
+        let profile = Profile {
+            id: hex::encode(id),
+            name: name.to_string(),
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("time since epoch")
+                .as_secs(),
+        };
+
+        let mut registry = self.registry.lock().expect("acquire profiles lock");
+        migrations::migrate(&self.dir(&profile.id))?;
+        registry.profiles.push(profile.clone());
+        write_registry(&self.app_data_dir, &registry)?;
+
+        Ok(profile)
+    }
+
+    /// Make the given profile the active one.
+    pub fn switch(&self, id: &str) -> Result<Profile, ProfileError> {
+        let mut registry = self.registry.lock().expect("acquire profiles lock");
+        let profile = registry.get(id)?.clone();
+        if registry.active == profile.id {
+            return Ok(profile);
+        }
+
+        registry.active = profile.id.clone();
+        write_registry(&self.app_data_dir, &registry)?;
+        self.active_tx.send_replace(profile.clone());
+
+        Ok(profile)
+    }
+
+    /// Delete a profile together with all its data. The default and the active profile can't be
+    /// deleted.
+    pub fn delete(&self, id: &str) -> Result<(), ProfileError> {
+        let mut registry = self.registry.lock().expect("acquire profiles lock");
+        registry.get(id)?;
+        if id == DEFAULT_PROFILE_ID {
+            return Err(ProfileError::DefaultProfile);
+        }
+        if registry.active == id {
+            return Err(ProfileError::ActiveProfile);
+        }
+
+        // Keys in the OS secret store are not part of the profile directory.
+        let profile_dir = self.dir(id);
+        if profile_dir.exists() {
+            let backend = KeyStoreBackend::load(&SqliteStore::open(&profile_dir)?, &profile_dir)?;
+            if backend == KeyStoreBackend::OsSecret {
+                backend.open(&profile_dir, None)?.delete()?;
+            }
+            fs::remove_dir_all(&profile_dir)?;
+        }
+
+        registry.profiles.retain(|profile| profile.id != id);
+        write_registry(&self.app_data_dir, &registry)?;
+
+        Ok(())
+    }
+}
+
+/// Move the data of the default profile which earlier versions kept directly in the app data
+/// directory into the profiles directory.
+///
+/// All entries are first collected in a staging directory which is renamed to the profile
+/// directory in the end, an interrupted migration is continued on the next start.
+fn migrate_default_profile(app_data_dir: &Path) -> Result<(), ProfileError> {
+    let profiles_dir = app_data_dir.join(PROFILES_DIR_NAME);
+    let default_dir = profiles_dir.join(DEFAULT_PROFILE_ID);
+    if default_dir.exists() {
+        return Ok(());
+    }
+
+    let staging_dir = profiles_dir.join(DEFAULT_PROFILE_STAGING_DIR_NAME);
+    fs::create_dir_all(&staging_dir)?;
+
+    let registry_tmp_path = app_data_dir
+        .join(PROFILES_FILE_NAME)
+        .with_extension("json.tmp");
+    for entry in fs::read_dir(app_data_dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if path == profiles_dir
+            || path == app_data_dir.join(PROFILES_FILE_NAME)
+            || path == registry_tmp_path
+        {
+            continue;
+        }
+        fs::rename(path, staging_dir.join(entry.file_name()))?;
+    }
+
+    // The account of a key in the OS secret store is named after the directory of the profile.
+    if staging_dir.join(DATABASE_FILE_NAME).exists() {
+        let backend = KeyStoreBackend::load(&SqliteStore::open(&staging_dir)?, &staging_dir)?;
+        if backend == KeyStoreBackend::OsSecret {
+            let legacy_key_store = backend.open(app_data_dir, None)?;
+            match legacy_key_store.load() {
+                Ok(private_key) => {
+                    backend.open(&default_dir, None)?.replace(&private_key)?;
+                    legacy_key_store.delete()?;
+                }
+                // The key was already moved before the migration was interrupted.
+                Err(KeyStoreError::NotFound) => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fs::rename(staging_dir, default_dir)?;
+    Ok(())
+}
+
+fn write_registry(app_data_dir: &Path, registry: &Registry) -> Result<(), ProfileError> {
+    let registry_path = app_data_dir.join(PROFILES_FILE_NAME);
+    let tmp_path = registry_path.with_extension("json.tmp");
+    fs::write(&tmp_path, serde_json::to_vec(registry)?)?;
+    fs::rename(tmp_path, registry_path)?;
+    Ok(())
+}
+
+#[derive(Debug, Error)]
+pub enum ProfileError {
+    #[error("profile {0} not found")]
+    NotFound(String),
+
+    #[error("profile name must not be empty")]
+    InvalidName,
+
+    #[error("the default profile can't be deleted")]
+    DefaultProfile,
+
+    #[error("the active profile can't be deleted, switch to another profile first")]
+    ActiveProfile,
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("invalid profile registry: {0}")]
+    Registry(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Migration(#[from] MigrationError),
+
+    #[error(transparent)]
+    Store(#[from] StoreError),
+
+    #[error(transparent)]
+    KeyStore(#[from] KeyStoreError),
+}
+
+impl Serialize for ProfileError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::tempdir;
+
+    use crate::migrations::PRIVATE_KEY_FILE_NAME;
+    use crate::store::{SqliteStore, DATABASE_FILE_NAME};
+
+    use super::{
+        ProfileError, Profiles, DEFAULT_PROFILE_ID, PROFILES_DIR_NAME, PROFILES_FILE_NAME,
+    };
+
+    #[test]
+    fn create_switch_and_delete_profiles() {
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = tmp_dir.path().to_path_buf();
+
+        let profiles = Profiles::open(app_data_dir.clone()).unwrap();
+        assert_eq!(profiles.active().id, DEFAULT_PROFILE_ID);
+        assert_eq!(
+            profiles.dir(DEFAULT_PROFILE_ID),
+            app_data_dir
+                .join(PROFILES_DIR_NAME)
+                .join(DEFAULT_PROFILE_ID)
+        );
+
+        let profile = profiles.create("Food coop").unwrap();
+        assert_eq!(
+            profiles.dir(&profile.id),
+            app_data_dir.join(PROFILES_DIR_NAME).join(&profile.id)
+        );
+        assert!(profiles.dir(&profile.id).exists());
+        assert!(matches!(
+            profiles.create("  "),
+            Err(ProfileError::InvalidName)
+        ));
+
+        let mut active_rx = profiles.subscribe();
+        profiles.switch(&profile.id).unwrap();
+        assert!(active_rx.has_changed().unwrap());
+        assert_eq!(active_rx.borrow_and_update().id, profile.id);
+        assert!(matches!(
+            profiles.switch("unknown"),
+            Err(ProfileError::NotFound(_))
+        ));
+
+        // The registry survives restarts.
+        let profiles = Profiles::open(app_data_dir).unwrap();
+        assert_eq!(profiles.active(), profile);
+        assert_eq!(profiles.list().len(), 2);
+
+        assert!(matches!(
+            profiles.delete(&profile.id),
+            Err(ProfileError::ActiveProfile)
+        ));
+        assert!(matches!(
+            profiles.delete(DEFAULT_PROFILE_ID),
+            Err(ProfileError::DefaultProfile)
+        ));
+
+        profiles.switch(DEFAULT_PROFILE_ID).unwrap();
+        profiles.delete(&profile.id).unwrap();
+        assert!(!profiles.dir(&profile.id).exists());
+        assert_eq!(profiles.list().len(), 1);
+    }
+
+    #[test]
+    fn move_default_profile_into_profiles_dir() {
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = tmp_dir.path().to_path_buf();
+
+        // Data of an installation from before profiles were introduced.
+        SqliteStore::open(&app_data_dir)
+            .unwrap()
+            .insert_subscription("organize")
+            .unwrap();
+        fs::write(app_data_dir.join(PRIVATE_KEY_FILE_NAME), "key").unwrap();
+
+        let profiles = Profiles::open(app_data_dir.clone()).unwrap();
+        let default_dir = profiles.dir(DEFAULT_PROFILE_ID);
+        assert!(!app_data_dir.join(DATABASE_FILE_NAME).exists());
+        assert!(!app_data_dir.join(PRIVATE_KEY_FILE_NAME).exists());
+        assert_eq!(
+            fs::read_to_string(default_dir.join(PRIVATE_KEY_FILE_NAME)).unwrap(),
+            "key"
+        );
+        assert_eq!(
+            SqliteStore::open(&default_dir)
+                .unwrap()
+                .subscriptions()
+                .unwrap(),
+            vec!["organize".to_string()]
+        );
+
+        // The registry stays outside of the profile directories and nothing is moved again.
+        profiles.create("Food coop").unwrap();
+        let profiles = Profiles::open(app_data_dir.clone()).unwrap();
+        assert_eq!(profiles.list().len(), 2);
+        assert!(app_data_dir.join(PROFILES_FILE_NAME).exists());
+        assert!(!default_dir.join(PROFILES_FILE_NAME).exists());
+        assert!(!default_dir.join(PROFILES_DIR_NAME).exists());
+    }
+}
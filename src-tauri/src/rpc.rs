@@ -1,4 +1,4 @@
-use p2panda_core::{Hash, PublicKey};
+use p2panda_core::{Hash, PublicKey, Signature};
 use tauri::AppHandle;
 use tauri::{ipc::Channel, State};
 use tauri_plugin_dialog::DialogExt;
@@ -6,7 +6,19 @@ use tokio::sync::broadcast;
 use tracing::debug;
 
 use crate::app::{Rpc, RpcError};
-use crate::messages::{ChannelEvent, StreamArgs, ToolkittyLogId};
+use crate::capabilities::WriteAccess;
+use crate::delegation::DelegationRequest;
+use crate::extensions::ContentType;
+use crate::gc::{GcReport, StorageQuota};
+use crate::inventory::StreamInventory;
+use crate::keystore::KeyStoreBackend;
+use crate::messages::{ChannelEvent, PublishOptions, PublishStatus, StreamArgs, ToolkittyLogId};
+use crate::payload::Payload;
+use crate::profiles::{Profile, ProfileError, Profiles};
+use crate::schema::SchemaVersions;
+use crate::signing;
+use crate::store::OutboxEntry;
+use crate::unlock::{UnlockError, Unlocker};
 
 /// Initialize the app by passing it a channel from the frontend.
 #[tauri::command]
@@ -79,6 +91,26 @@ pub async fn add_topic_log(
     Ok(())
 }
 
+/// Remove a log from the topic log map.
+#[tauri::command]
+pub async fn remove_topic_log(
+    rpc: State<'_, Rpc>,
+    public_key: PublicKey,
+    topic: &str,
+    log_id: ToolkittyLogId,
+) -> Result<(), RpcError> {
+    debug!(
+        command.name = "remove_topic_log",
+        command.public_key = public_key.to_hex(),
+        command.topic = topic,
+        "RPC request received"
+    );
+
+    rpc.remove_topic_log(&public_key, topic, &log_id.into())
+        .await?;
+    Ok(())
+}
+
 /// Subscribe to a persisted topic.
 #[tauri::command]
 pub async fn subscribe_persisted(rpc: State<'_, Rpc>, topic: String) -> Result<(), RpcError> {
@@ -113,37 +145,250 @@ pub async fn publish_persisted(
     stream_args: StreamArgs,
     log_path: Option<String>,
     topic: Option<String>,
-) -> Result<(Hash, Hash), RpcError> {
+    options: Option<PublishOptions>,
+) -> Result<(Hash, Hash, PublishStatus), RpcError> {
     debug!(
         command.name = "publish_persisted",
         command.topic = topic.as_ref().map(ToString::to_string),
         "RPC request received"
     );
-    let payload = serde_json::to_vec(&payload)?;
+    let options = options.unwrap_or_default();
+    let payload = Payload::from_value(payload, options.content_type)?;
     let result = rpc
         .publish_persisted(
-            &payload,
+            &payload.to_bytes(),
             &stream_args,
             log_path.as_deref(),
             topic.as_deref(),
+            &options,
         )
         .await?;
     Ok(result)
 }
 
+/// Grant or revoke write access to a log path of a stream we own. Returns the id of the
+/// published capability operation.
+#[tauri::command]
+pub async fn set_write_access(
+    rpc: State<'_, Rpc>,
+    stream_args: StreamArgs,
+    log_path: Option<String>,
+    subject: PublicKey,
+    access: WriteAccess,
+    topic: Option<String>,
+) -> Result<Hash, RpcError> {
+    debug!(
+        command.name = "set_write_access",
+        command.subject = subject.to_hex(),
+        command.topic = topic.as_ref().map(ToString::to_string),
+        "RPC request received"
+    );
+    let operation_id = rpc
+        .set_write_access(
+            &stream_args,
+            log_path.as_deref(),
+            subject,
+            access,
+            topic.as_deref(),
+        )
+        .await?;
+    Ok(operation_id)
+}
+
 /// Publish to an ephemeral topic.
 #[tauri::command]
 pub async fn publish_ephemeral(
     rpc: State<'_, Rpc>,
     topic: String,
     payload: serde_json::Value,
+    content_type: Option<ContentType>,
 ) -> Result<(), RpcError> {
     debug!(command.name = "publish_ephemeral", "RPC request received");
-    let payload = serde_json::to_vec(&payload)?;
+    let payload = Payload::from_value(payload, content_type.unwrap_or_default())?;
     rpc.publish_ephemeral(&topic, &payload).await?;
     Ok(())
 }
 
+/// Export a backup of the node into a file chosen by the user.
+#[tauri::command]
+pub async fn export_backup(
+    rpc: State<'_, Rpc>,
+    app: AppHandle,
+    include_private_key: bool,
+) -> Result<bool, RpcError> {
+    debug!(
+        command.name = "export_backup",
+        command.include_private_key = include_private_key,
+        "RPC request received"
+    );
+    match app
+        .dialog()
+        .file()
+        .set_file_name("toolkitty.backup")
+        .blocking_save_file()
+    {
+        Some(file_path) => {
+            let file_path = file_path.into_path().expect("parseable file path");
+            rpc.export_backup(&file_path, include_private_key).await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Import a backup from a file chosen by the user. The app restarts if the backup contains a
+/// different identity.
+#[tauri::command]
+pub async fn import_backup(rpc: State<'_, Rpc>, app: AppHandle) -> Result<bool, RpcError> {
+    debug!(command.name = "import_backup", "RPC request received");
+    match app.dialog().file().blocking_pick_file() {
+        Some(file_path) => {
+            let file_path = file_path.into_path().expect("parseable file path");
+            if rpc.import_backup(&file_path).await? {
+                app.restart();
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// The private key as a mnemonic word list which can be written down as a backup.
+#[tauri::command]
+pub async fn export_mnemonic(rpc: State<'_, Rpc>) -> Result<String, RpcError> {
+    debug!(command.name = "export_mnemonic", "RPC request received");
+    let words = rpc.export_mnemonic().await?;
+    Ok(words)
+}
+
+/// Restore an identity from its mnemonic word list. The app restarts if the identity changed.
+#[tauri::command]
+pub async fn restore_mnemonic(
+    rpc: State<'_, Rpc>,
+    app: AppHandle,
+    words: String,
+) -> Result<(), RpcError> {
+    debug!(command.name = "restore_mnemonic", "RPC request received");
+    if rpc.restore_mnemonic(&words).await? {
+        app.restart();
+    }
+    Ok(())
+}
+
+/// Replace the private key with a new one and announce it as the successor of the current key.
+/// The app restarts to use the new key.
+#[tauri::command]
+pub async fn rotate_key(rpc: State<'_, Rpc>, app: AppHandle) -> Result<(), RpcError> {
+    debug!(command.name = "rotate_key", "RPC request received");
+    rpc.rotate_key().await?;
+    app.restart();
+}
+
+/// Request to act as a device of another identity. The returned request is passed to the
+/// identity, which completes it with `delegate_device`.
+#[tauri::command]
+pub async fn request_delegation(
+    rpc: State<'_, Rpc>,
+    identity: PublicKey,
+) -> Result<DelegationRequest, RpcError> {
+    debug!(
+        command.name = "request_delegation",
+        command.identity = identity.to_hex(),
+        "RPC request received"
+    );
+    let request = rpc.request_delegation(identity).await?;
+    Ok(request)
+}
+
+/// Delegate the key of another device to our identity.
+#[tauri::command]
+pub async fn delegate_device(
+    rpc: State<'_, Rpc>,
+    request: DelegationRequest,
+) -> Result<(), RpcError> {
+    debug!(
+        command.name = "delegate_device",
+        command.device = request.device.to_hex(),
+        "RPC request received"
+    );
+    rpc.delegate_device(request).await?;
+    Ok(())
+}
+
+/// Sign application data with the private key of the local node, bound to the purpose named by
+/// the tag.
+#[tauri::command]
+pub async fn sign(rpc: State<'_, Rpc>, tag: String, bytes: Vec<u8>) -> Result<Signature, RpcError> {
+    debug!(
+        command.name = "sign",
+        command.tag = tag,
+        "RPC request received"
+    );
+    let signature = rpc.sign(&tag, &bytes).await?;
+    Ok(signature)
+}
+
+/// Check that application data was signed by the public key with the given tag.
+#[tauri::command]
+pub fn verify(
+    public_key: PublicKey,
+    tag: String,
+    bytes: Vec<u8>,
+    signature: Signature,
+) -> Result<bool, RpcError> {
+    debug!(
+        command.name = "verify",
+        command.public_key = public_key.to_hex(),
+        command.tag = tag,
+        "RPC request received"
+    );
+    let valid = signing::verify(&public_key, &tag, &bytes, &signature)?;
+    Ok(valid)
+}
+
+/// Export all logs of a stream into a bundle file chosen by the user.
+#[tauri::command]
+pub async fn export_stream(
+    rpc: State<'_, Rpc>,
+    app: AppHandle,
+    stream_id: Hash,
+    include_blobs: bool,
+) -> Result<bool, RpcError> {
+    debug!(
+        command.name = "export_stream",
+        command.stream_id = stream_id.to_hex(),
+        "RPC request received"
+    );
+    match app
+        .dialog()
+        .file()
+        .set_file_name(format!("{stream_id}.bundle"))
+        .blocking_save_file()
+    {
+        Some(file_path) => {
+            let file_path = file_path.into_path().expect("parseable file path");
+            rpc.export_stream(stream_id, &file_path, include_blobs)
+                .await?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Import a stream bundle from a file chosen by the user.
+#[tauri::command]
+pub async fn import_stream(rpc: State<'_, Rpc>, app: AppHandle) -> Result<Option<Hash>, RpcError> {
+    debug!(command.name = "import_stream", "RPC request received");
+    match app.dialog().file().blocking_pick_file() {
+        Some(file_path) => {
+            let file_path = file_path.into_path().expect("parseable file path");
+            let stream_id = rpc.import_stream(&file_path).await?;
+            Ok(Some(stream_id))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Upload a file.
 #[tauri::command]
 pub async fn upload_file(rpc: State<'_, Rpc>, app: AppHandle) -> Result<Option<Hash>, RpcError> {
@@ -157,3 +402,190 @@ pub async fn upload_file(rpc: State<'_, Rpc>, app: AppHandle) -> Result<Option<H
         None => Ok(None),
     }
 }
+
+/// Get the configured storage quota.
+#[tauri::command]
+pub async fn storage_quota(rpc: State<'_, Rpc>) -> Result<StorageQuota, RpcError> {
+    debug!(command.name = "storage_quota", "RPC request received");
+    let quota = rpc.storage_quota().await?;
+    Ok(quota)
+}
+
+/// Configure the global and per-stream storage quota in bytes.
+#[tauri::command]
+pub async fn set_storage_quota(rpc: State<'_, Rpc>, quota: StorageQuota) -> Result<(), RpcError> {
+    debug!(
+        command.name = "set_storage_quota",
+        command.global = quota.global,
+        command.per_stream = quota.per_stream,
+        "RPC request received"
+    );
+
+    rpc.set_storage_quota(&quota).await?;
+    Ok(())
+}
+
+/// Get the range of supported payload schema versions.
+#[tauri::command]
+pub async fn schema_versions(rpc: State<'_, Rpc>) -> Result<SchemaVersions, RpcError> {
+    debug!(command.name = "schema_versions", "RPC request received");
+    let versions = rpc.schema_versions().await?;
+    Ok(versions)
+}
+
+/// Configure the lowest and highest supported payload schema version.
+#[tauri::command]
+pub async fn set_schema_versions(
+    rpc: State<'_, Rpc>,
+    versions: SchemaVersions,
+) -> Result<(), RpcError> {
+    debug!(
+        command.name = "set_schema_versions",
+        command.min = versions.min.map(|version| version.to_string()),
+        command.max = versions.max.map(|version| version.to_string()),
+        "RPC request received"
+    );
+
+    rpc.set_schema_versions(&versions).await?;
+    Ok(())
+}
+
+/// List all known streams with their logs, authors, operation counts and sizes.
+#[tauri::command]
+pub async fn streams(rpc: State<'_, Rpc>) -> Result<Vec<StreamInventory>, RpcError> {
+    debug!(command.name = "streams", "RPC request received");
+    let streams = rpc.streams().await?;
+    Ok(streams)
+}
+
+/// Get the logs, authors, operation counts and sizes of a single stream.
+#[tauri::command]
+pub async fn stream(
+    rpc: State<'_, Rpc>,
+    stream_id: Hash,
+) -> Result<Option<StreamInventory>, RpcError> {
+    debug!(
+        command.name = "stream",
+        command.stream_id = stream_id.to_hex(),
+        "RPC request received"
+    );
+
+    let stream = rpc.stream(stream_id).await?;
+    Ok(stream)
+}
+
+/// Run garbage collection now instead of waiting for the next periodic pass.
+#[tauri::command]
+pub async fn collect_garbage(rpc: State<'_, Rpc>) -> Result<GcReport, RpcError> {
+    debug!(command.name = "collect_garbage", "RPC request received");
+    let report = rpc.collect_garbage().await?;
+    Ok(report)
+}
+
+/// Pin a blob so it is never evicted by the garbage collector, or unpin it again.
+#[tauri::command]
+pub async fn pin_blob(rpc: State<'_, Rpc>, hash: Hash, pinned: bool) -> Result<(), RpcError> {
+    debug!(command.name = "pin_blob", "RPC request received");
+    rpc.pin_blob(hash, pinned).await?;
+    Ok(())
+}
+
+/// Operations which were created but not published yet.
+#[tauri::command]
+pub async fn outbox(rpc: State<'_, Rpc>) -> Result<Vec<OutboxEntry>, RpcError> {
+    debug!(command.name = "outbox", "RPC request received");
+    let entries = rpc.outbox().await?;
+    Ok(entries)
+}
+
+/// Returns `true` if the private key is encrypted and waits to be unlocked. Resolves as soon as
+/// the service opened the key store.
+#[tauri::command]
+pub async fn locked(unlocker: State<'_, Unlocker>) -> Result<bool, UnlockError> {
+    debug!(command.name = "locked", "RPC request received");
+    Ok(unlocker.is_locked().await)
+}
+
+/// Unlock the encrypted private key. Resolves as soon as the node was started.
+#[tauri::command]
+pub async fn unlock(unlocker: State<'_, Unlocker>, passphrase: String) -> Result<(), UnlockError> {
+    debug!(command.name = "unlock", "RPC request received");
+    unlocker.unlock(passphrase).await?;
+    Ok(())
+}
+
+/// Move the private key into another key store backend. The passphrase is only used by the
+/// encrypted file backend.
+#[tauri::command]
+pub async fn set_key_store(
+    rpc: State<'_, Rpc>,
+    backend: KeyStoreBackend,
+    passphrase: Option<String>,
+) -> Result<(), RpcError> {
+    debug!(
+        command.name = "set_key_store",
+        command.backend = ?backend,
+        "RPC request received"
+    );
+
+    rpc.set_key_store(backend, passphrase.as_deref()).await?;
+    Ok(())
+}
+
+/// All local profiles.
+#[tauri::command]
+pub fn list_profiles(profiles: State<'_, Profiles>) -> Vec<Profile> {
+    debug!(command.name = "list_profiles", "RPC request received");
+    profiles.list()
+}
+
+/// The profile the app is currently running with.
+#[tauri::command]
+pub fn active_profile(profiles: State<'_, Profiles>) -> Profile {
+    debug!(command.name = "active_profile", "RPC request received");
+    profiles.active()
+}
+
+/// Create a new profile with its own identity.
+#[tauri::command]
+pub fn create_profile(
+    profiles: State<'_, Profiles>,
+    name: String,
+) -> Result<Profile, ProfileError> {
+    debug!(
+        command.name = "create_profile",
+        command.profile_name = name,
+        "RPC request received"
+    );
+
+    let profile = profiles.create(&name)?;
+    Ok(profile)
+}
+
+/// Switch to another profile. The node is restarted in the background, a `profile_switched`
+/// event is sent as soon as it runs with the new profile. Encrypted private keys of the new
+/// profile need to be unlocked first.
+#[tauri::command]
+pub fn switch_profile(profiles: State<'_, Profiles>, id: String) -> Result<Profile, ProfileError> {
+    debug!(
+        command.name = "switch_profile",
+        command.profile_id = id,
+        "RPC request received"
+    );
+
+    let profile = profiles.switch(&id)?;
+    Ok(profile)
+}
+
+/// Delete a profile and all its data. Only inactive profiles can be deleted.
+#[tauri::command]
+pub fn delete_profile(profiles: State<'_, Profiles>, id: String) -> Result<(), ProfileError> {
+    debug!(
+        command.name = "delete_profile",
+        command.profile_id = id,
+        "RPC request received"
+    );
+
+    profiles.delete(&id)?;
+    Ok(())
+}
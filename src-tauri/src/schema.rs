@@ -0,0 +1,93 @@
+//! Supported payload schema versions.
+//!
+//! Operations carry the version of their payload format in the schema version extension. The
+//! frontend only understands a range of versions, operations outside of it are still persisted
+//! but forwarded as `unsupported_schema` events without their payload, so the processor never
+//! sees a format it can't handle. They can be replayed after an update which supports them.
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::extensions::SchemaVersion;
+use crate::store::{SqliteStore, StoreError};
+
+/// Key of the supported schema versions in the settings table.
+pub const SCHEMA_VERSIONS_SETTING: &str = "schema_versions";
+
+/// Range of supported schema versions, `None` means unbounded.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SchemaVersions {
+    /// Lowest supported version.
+    pub min: Option<SchemaVersion>,
+
+    /// Highest supported version.
+    pub max: Option<SchemaVersion>,
+}
+
+impl SchemaVersions {
+    pub fn load(store: &SqliteStore) -> Result<Self, StoreError> {
+        Ok(store.setting(SCHEMA_VERSIONS_SETTING)?.unwrap_or_default())
+    }
+
+    pub fn save(&self, store: &SqliteStore) -> Result<(), StoreError> {
+        store.set_setting(SCHEMA_VERSIONS_SETTING, self)
+    }
+
+    /// Check that the range is not empty.
+    pub fn validate(&self) -> Result<(), SchemaError> {
+        match (self.min, self.max) {
+            (Some(min), Some(max)) if min > max => Err(SchemaError::InvalidRange { min, max }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns `true` if payloads of the given version are supported.
+    pub fn supports(&self, version: SchemaVersion) -> bool {
+        !self.min.is_some_and(|min| version < min) && !self.max.is_some_and(|max| version > max)
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SchemaError {
+    #[error("lowest supported schema version {min} is higher than the highest {max}")]
+    InvalidRange {
+        min: SchemaVersion,
+        max: SchemaVersion,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::store::SqliteStore;
+
+    use super::SchemaVersions;
+
+    #[test]
+    fn supported_versions() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let versions = SchemaVersions::load(&store).unwrap();
+        assert!(versions.supports(0.into()));
+        assert!(versions.supports(u32::MAX.into()));
+
+        SchemaVersions {
+            min: Some(1.into()),
+            max: Some(2.into()),
+        }
+        .save(&store)
+        .unwrap();
+
+        let versions = SchemaVersions::load(&store).unwrap();
+        assert!(!versions.supports(0.into()));
+        assert!(versions.supports(1.into()));
+        assert!(versions.supports(2.into()));
+        assert!(!versions.supports(3.into()));
+        assert!(versions.validate().is_ok());
+
+        let versions = SchemaVersions {
+            min: Some(2.into()),
+            max: Some(1.into()),
+        };
+        assert!(versions.validate().is_err());
+    }
+}
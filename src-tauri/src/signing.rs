@@ -0,0 +1,93 @@
+//! Signatures over application data with the key of the local node.
+//!
+//! The frontend uses these to authenticate data which is not published as an operation, for
+//! example invite responses or access tokens. Every signature is bound to a tag naming the
+//! purpose of the data, so a signature produced for one purpose can't be replayed for another
+//! one. The signed message is the application context, the length-prefixed tag and the data,
+//! which can't collide with the messages signed for operations, key successions or device
+//! delegations.
+
+use p2panda_core::{PrivateKey, PublicKey, Signature};
+use thiserror::Error;
+
+/// Domain separation prefix of all messages signed for the application.
+const SIGNATURE_CONTEXT: &[u8] = b"toolkitty/app-signature/v1";
+
+/// Maximum length of a tag in bytes.
+pub const MAX_TAG_LENGTH: usize = 64;
+
+/// Sign data for the purpose named by the tag.
+pub fn sign(private_key: &PrivateKey, tag: &str, bytes: &[u8]) -> Result<Signature, SigningError> {
+    Ok(private_key.sign(&message(tag, bytes)?))
+}
+
+/// Check that the data was signed by the public key for the purpose named by the tag.
+pub fn verify(
+    public_key: &PublicKey,
+    tag: &str,
+    bytes: &[u8],
+    signature: &Signature,
+) -> Result<bool, SigningError> {
+    Ok(public_key.verify(&message(tag, bytes)?, signature))
+}
+
+fn message(tag: &str, bytes: &[u8]) -> Result<Vec<u8>, SigningError> {
+    if tag.is_empty()
+        || tag.len() > MAX_TAG_LENGTH
+        || !tag
+            .bytes()
+            .all(|byte| byte.is_ascii_alphanumeric() || b"-_./:".contains(&byte))
+    {
+        return Err(SigningError::InvalidTag(tag.to_string()));
+    }
+
+    Ok([SIGNATURE_CONTEXT, &[tag.len() as u8], tag.as_bytes(), bytes].concat())
+}
+
+#[derive(Debug, Error)]
+pub enum SigningError {
+    #[error(
+        "invalid signature tag \"{0}\", expected up to {MAX_TAG_LENGTH} letters, digits or -_./:"
+    )]
+    InvalidTag(String),
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::PrivateKey;
+
+    use super::{sign, verify, SigningError};
+
+    #[test]
+    fn sign_and_verify() {
+        let private_key = PrivateKey::new();
+        let public_key = private_key.public_key();
+
+        let signature = sign(&private_key, "invite-response", b"yes").unwrap();
+        assert!(verify(&public_key, "invite-response", b"yes", &signature).unwrap());
+        assert!(!verify(&public_key, "invite-response", b"no", &signature).unwrap());
+        assert!(!verify(
+            &PrivateKey::new().public_key(),
+            "invite-response",
+            b"yes",
+            &signature
+        )
+        .unwrap());
+
+        // Signatures are bound to their tag.
+        assert!(!verify(&public_key, "ticket", b"yes", &signature).unwrap());
+
+        // Moving bytes between the tag and the data changes the message.
+        let signature = sign(&private_key, "ticket", b"-1").unwrap();
+        assert!(!verify(&public_key, "ticket-1", b"", &signature).unwrap());
+
+        assert!(matches!(
+            sign(&private_key, "", b"yes"),
+            Err(SigningError::InvalidTag(_))
+        ));
+        assert!(matches!(
+            sign(&private_key, "no spaces", b"yes"),
+            Err(SigningError::InvalidTag(_))
+        ));
+    }
+}
@@ -0,0 +1,1943 @@
+//! Persistent storage for operations and application state.
+//!
+//! The p2panda node keeps all operations it knows about in an in-memory store which is lost as
+//! soon as the application exits. To survive restarts we write every operation we publish or
+//! receive through to a SQLite database located in the app data directory and hydrate the
+//! in-memory store from it again when the node is built.
+//!
+//! The SQLite database is the source of truth. The node is not generic over its store, it only
+//! takes a `MemoryStore`, so the store traits can't be implemented here and the node be given the
+//! database instead. Pruned logs and payloads of expired operations are deleted from both stores,
+//! so the mirror never holds more than the database.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use p2panda_core::cbor::{decode_cbor, DecodeError};
+use p2panda_core::{Body, Hash, Header, PublicKey};
+use p2panda_node::extensions::LogId;
+use p2panda_store::{MemoryStore, OperationStore};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::warn;
+
+use crate::capabilities::{CapabilityError, WriteAccess, WriteCapability};
+use crate::delegation::{DelegationError, DeviceDelegation};
+use crate::extensions::{
+    legacy_log_id, to_log_id, unix_time, ExpiresAt, Extensions, LogPath, Stream,
+};
+use crate::succession::{KeySuccession, SuccessionError};
+
+/// File name of the SQLite database inside the app data directory.
+pub const DATABASE_FILE_NAME: &str = "toolkitty.sqlite";
+
+/// Database schema migrations. The schema version of a database is the number of migrations which
+/// have been applied to it, it is tracked via SQLite's `user_version` pragma.
+///
+/// Never change a migration which was already released, always append a new one instead.
+const MIGRATIONS: &[&str] = &[
+    // Version 1: operations, topic logs, subscriptions and acknowledgements.
+    "
+        CREATE TABLE IF NOT EXISTS operations (
+            hash            TEXT    NOT NULL PRIMARY KEY,
+            public_key      TEXT    NOT NULL,
+            log_id          TEXT    NOT NULL,
+            seq_num         INTEGER NOT NULL,
+            timestamp       INTEGER NOT NULL,
+            header          BLOB    NOT NULL,
+            body            BLOB
+        );
+
+        CREATE INDEX IF NOT EXISTS operations_log_idx
+            ON operations (public_key, log_id, seq_num);
+
+        CREATE TABLE IF NOT EXISTS topic_logs (
+            topic           TEXT    NOT NULL,
+            public_key      TEXT    NOT NULL,
+            log_id          TEXT    NOT NULL,
+            PRIMARY KEY (topic, public_key, log_id)
+        );
+
+        CREATE TABLE IF NOT EXISTS subscriptions (
+            topic           TEXT    NOT NULL PRIMARY KEY
+        );
+
+        CREATE TABLE IF NOT EXISTS acks (
+            hash            TEXT    NOT NULL PRIMARY KEY
+        );
+    ",
+    // Version 2: blob accounting and settings.
+    "
+        CREATE TABLE blobs (
+            hash            TEXT    NOT NULL PRIMARY KEY,
+            size            INTEGER NOT NULL,
+            authored        INTEGER NOT NULL
+        );
+
+        CREATE TABLE settings (
+            key             TEXT    NOT NULL PRIMARY KEY,
+            value           TEXT    NOT NULL
+        );
+    ",
+    // Version 3: outbox of operations which were not published yet.
+    "
+        CREATE TABLE outbox (
+            hash            TEXT    NOT NULL PRIMARY KEY,
+            topic           TEXT,
+            header          BLOB    NOT NULL,
+            body            BLOB,
+            attempts        INTEGER NOT NULL DEFAULT 0,
+            last_error      TEXT
+        );
+    ",
+    // Version 4: key succession records linking rotated keys of the same identity.
+    "
+        CREATE TABLE successions (
+            previous        TEXT    NOT NULL PRIMARY KEY,
+            next            TEXT    NOT NULL UNIQUE,
+            record          TEXT    NOT NULL
+        );
+    ",
+    // Version 5: delegation certificates of device keys.
+    "
+        CREATE TABLE delegations (
+            device          TEXT    NOT NULL PRIMARY KEY,
+            identity        TEXT    NOT NULL,
+            record          TEXT    NOT NULL
+        );
+
+        CREATE INDEX delegations_identity ON delegations (identity);
+    ",
+    // Version 6: write capabilities of stream log paths.
+    "
+        CREATE TABLE write_capabilities (
+            stream_id       TEXT    NOT NULL,
+            log_path        TEXT    NOT NULL,
+            subject         TEXT    NOT NULL,
+            granted         INTEGER NOT NULL,
+            timestamp       INTEGER NOT NULL,
+            operation_id    TEXT    NOT NULL,
+            PRIMARY KEY (stream_id, log_path, subject)
+        );
+    ",
+    // Version 7: expiry of operations.
+    "
+        ALTER TABLE operations ADD COLUMN expires_at INTEGER;
+
+        CREATE INDEX operations_expiry_idx ON operations (expires_at);
+    ",
+    // Version 8: last access and pinning of blobs for garbage collection.
+    "
+        ALTER TABLE blobs ADD COLUMN last_accessed INTEGER NOT NULL DEFAULT 0;
+        ALTER TABLE blobs ADD COLUMN pinned INTEGER NOT NULL DEFAULT 0;
+    ",
+    // Version 9: write capabilities of the log without a path are stored with a NULL log path,
+    // operations rejected for missing write access are kept to be checked again.
+    //
+    // Earlier versions stored the log without a path as the empty string, these rows are taken
+    // for the log without a path.
+    "
+        CREATE TABLE write_capabilities_v9 (
+            stream_id       TEXT    NOT NULL,
+            log_path        TEXT,
+            subject         TEXT    NOT NULL,
+            granted         INTEGER NOT NULL,
+            timestamp       INTEGER NOT NULL,
+            operation_id    TEXT    NOT NULL
+        );
+
+        INSERT INTO write_capabilities_v9
+            SELECT stream_id, NULLIF(log_path, ''), subject, granted, timestamp, operation_id
+            FROM write_capabilities;
+
+        DROP TABLE write_capabilities;
+        ALTER TABLE write_capabilities_v9 RENAME TO write_capabilities;
+
+        CREATE INDEX write_capabilities_idx ON write_capabilities (stream_id, log_path, subject);
+
+        CREATE TABLE rejected_operations (
+            hash            TEXT    NOT NULL PRIMARY KEY,
+            stream_id       TEXT    NOT NULL,
+            public_key      TEXT    NOT NULL,
+            log_id          TEXT    NOT NULL,
+            seq_num         INTEGER NOT NULL,
+            header          BLOB    NOT NULL,
+            body            BLOB
+        );
+
+        CREATE INDEX rejected_operations_stream_idx ON rejected_operations (stream_id);
+    ",
+    // Version 10: operations held back until their dependencies were delivered and ids of
+    // pruned operations, which never hold back their dependants.
+    "
+        CREATE TABLE pending_operations (
+            hash            TEXT    NOT NULL PRIMARY KEY,
+            header          BLOB    NOT NULL,
+            body            BLOB
+        );
+
+        CREATE TABLE pruned_operations (
+            hash            TEXT    NOT NULL PRIMARY KEY
+        );
+    ",
+    // Version 11: stream of every operation, existing rows are filled in by `fill_stream_ids`.
+    "
+        ALTER TABLE operations ADD COLUMN stream_id TEXT;
+
+        CREATE INDEX operations_stream_idx ON operations (stream_id);
+    ",
+];
+
+/// Schema version which added the stream id column to the operations table.
+const STREAM_IDS_VERSION: usize = 11;
+
+/// Payload of a persisted operation, used for storage accounting.
+#[derive(Clone, Debug)]
+pub struct StoredPayload {
+    pub header: Header<Extensions>,
+    pub size: u64,
+}
+
+/// Blob in the local blob store, used for storage accounting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StoredBlob {
+    pub hash: Hash,
+    pub size: u64,
+
+    /// The blob was uploaded by us.
+    pub authored: bool,
+
+    /// Pinned blobs are never evicted by the garbage collector.
+    pub pinned: bool,
+
+    /// UNIX timestamp in seconds of the last time the blob was read or stored.
+    pub last_accessed: u64,
+}
+
+/// Operation we created which was not published yet.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboxEntry {
+    pub operation_id: Hash,
+
+    /// Persisted topic the operation is published on, `None` if it is only ingested locally.
+    pub topic: Option<String>,
+
+    /// Number of failed publish attempts.
+    pub attempts: u64,
+
+    pub last_error: Option<String>,
+
+    #[serde(skip)]
+    pub header: Header<Extensions>,
+
+    #[serde(skip)]
+    pub body: Option<Body>,
+}
+
+/// An operation as it was persisted to the database.
+pub type StoredOperation = (Header<Extensions>, Option<Body>, Vec<u8>);
+
+/// Durable store for operations and other application state which should survive restarts.
+///
+/// The store can be cheaply cloned, all clones share the same underlying database connection.
+#[derive(Clone, Debug)]
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    /// Open (or create) the database in the given app data directory.
+    pub fn open(app_data_dir: &Path) -> Result<Self, StoreError> {
+        fs::create_dir_all(app_data_dir)?;
+        let connection = Connection::open(app_data_dir.join(DATABASE_FILE_NAME))?;
+        connection.pragma_update(None, "journal_mode", "WAL")?;
+        Self::init(connection)
+    }
+
+    /// Open a database which only lives in memory, useful for tests.
+    #[cfg(test)]
+    pub fn open_in_memory() -> Result<Self, StoreError> {
+        let connection = Connection::open_in_memory()?;
+        Self::init(connection)
+    }
+
+    fn init(mut connection: Connection) -> Result<Self, StoreError> {
+        Self::migrate(&mut connection)?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    /// Bring the database schema up to date by applying all migrations which have not been
+    /// applied yet. Databases written by a newer version of the app are refused.
+    fn migrate(connection: &mut Connection) -> Result<(), StoreError> {
+        let version: i64 = connection.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        let version = version as usize;
+        if version > MIGRATIONS.len() {
+            return Err(StoreError::UnsupportedSchemaVersion {
+                found: version,
+                supported: MIGRATIONS.len(),
+            });
+        }
+
+        for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            let transaction = connection.transaction()?;
+            transaction.execute_batch(migration)?;
+            if index + 1 == STREAM_IDS_VERSION {
+                Self::fill_stream_ids(&transaction)?;
+            }
+            transaction.pragma_update(None, "user_version", (index + 1) as i64)?;
+            transaction.commit()?;
+        }
+
+        Ok(())
+    }
+
+    /// Derive the stream ids of operations persisted before they were stored in their own
+    /// column from the headers.
+    fn fill_stream_ids(connection: &Connection) -> Result<(), StoreError> {
+        let operations = {
+            let mut statement = connection.prepare("SELECT hash, header FROM operations")?;
+            let rows = statement.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        for (hash, header_bytes) in operations {
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            let stream: Stream = header.extension().expect("extract stream extension");
+            connection.execute(
+                "UPDATE operations SET stream_id = ?1 WHERE hash = ?2",
+                params![stream.id().to_hex(), hash],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Schema version of the database.
+    #[cfg(test)]
+    pub fn schema_version(&self) -> Result<usize, StoreError> {
+        let version: i64 = self
+            .connection()
+            .pragma_query_value(None, "user_version", |row| row.get(0))?;
+        Ok(version as usize)
+    }
+
+    pub(crate) fn connection(&self) -> MutexGuard<'_, Connection> {
+        self.connection.lock().expect("acquire database lock")
+    }
+
+    /// Persist an operation. Returns `false` if the operation was already stored.
+    pub fn insert_operation(
+        &self,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+        log_id: &LogId,
+    ) -> Result<bool, StoreError> {
+        Self::insert_operation_with(&self.connection(), header, body, log_id)
+    }
+
+    fn insert_operation_with(
+        connection: &Connection,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+        log_id: &LogId,
+    ) -> Result<bool, StoreError> {
+        let inserted = connection.execute(
+            "INSERT OR IGNORE INTO operations
+                (hash, public_key, log_id, seq_num, timestamp, header, body, expires_at, stream_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            params![
+                header.hash().to_hex(),
+                header.public_key.to_hex(),
+                log_id.0,
+                header.seq_num as i64,
+                header.timestamp as i64,
+                header.to_bytes(),
+                body.map(|body| body.to_bytes()),
+                header
+                    .extension::<ExpiresAt>()
+                    .map(|expires_at| i64::try_from(expires_at.0).unwrap_or(i64::MAX)),
+                header
+                    .extension::<Stream>()
+                    .map(|stream| stream.id().to_hex()),
+            ],
+        )?;
+        Ok(inserted > 0)
+    }
+
+    /// Delete all operations in a log which come before the given sequence number, also if they
+    /// are held back. Their ids are remembered so operations depending on them are not held back.
+    /// Returns the ids of the deleted operations.
+    pub fn prune_log(
+        &self,
+        public_key: &PublicKey,
+        log_id: &LogId,
+        before: u64,
+    ) -> Result<Vec<Hash>, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let deleted = {
+            let mut statement = transaction.prepare(
+                "SELECT hash FROM operations
+                 WHERE public_key = ?1 AND log_id = ?2 AND seq_num < ?3
+                 ORDER BY seq_num",
+            )?;
+            let rows = statement.query_map(
+                params![public_key.to_hex(), log_id.0, before as i64],
+                |row| row.get::<_, String>(0),
+            )?;
+            let mut deleted = Vec::new();
+            for row in rows {
+                let hash = Hash::from_str(&row?)
+                    .map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+                deleted.push(hash);
+            }
+            deleted
+        };
+        transaction.execute(
+            "INSERT OR IGNORE INTO pruned_operations (hash)
+             SELECT hash FROM operations WHERE public_key = ?1 AND log_id = ?2 AND seq_num < ?3",
+            params![public_key.to_hex(), log_id.0, before as i64],
+        )?;
+        transaction.execute(
+            "DELETE FROM pending_operations
+             WHERE hash IN (SELECT hash FROM operations
+                            WHERE public_key = ?1 AND log_id = ?2 AND seq_num < ?3)",
+            params![public_key.to_hex(), log_id.0, before as i64],
+        )?;
+        transaction.execute(
+            "DELETE FROM operations WHERE public_key = ?1 AND log_id = ?2 AND seq_num < ?3",
+            params![public_key.to_hex(), log_id.0, before as i64],
+        )?;
+        transaction.execute(
+            "DELETE FROM acks WHERE hash NOT IN (SELECT hash FROM operations)",
+            [],
+        )?;
+        transaction.commit()?;
+        Ok(deleted)
+    }
+
+    /// Delete the payloads of all operations which expired at the given UNIX timestamp in seconds,
+    /// also from their outbox entries. Headers are kept, removing them would leave gaps in the
+    /// logs or let us reuse the sequence number of our latest operation. Returns the ids of the
+    /// operations whose payload was deleted.
+    pub fn purge_expired(&self, now: u64) -> Result<Vec<Hash>, StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let expired = {
+            let mut statement = transaction.prepare(
+                "SELECT hash FROM operations WHERE expires_at <= ?1 AND body IS NOT NULL",
+            )?;
+            let rows = statement.query_map(params![now as i64], |row| row.get::<_, String>(0))?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for hash in &expired {
+            transaction.execute(
+                "UPDATE operations SET body = NULL WHERE hash = ?1",
+                params![hash],
+            )?;
+            transaction.execute(
+                "UPDATE outbox SET body = NULL WHERE hash = ?1",
+                params![hash],
+            )?;
+        }
+        transaction.commit()?;
+
+        expired
+            .iter()
+            .map(|hash| {
+                Hash::from_str(hash).map_err(|err| StoreError::InvalidValue(err.to_string()))
+            })
+            .collect()
+    }
+
+    /// Delete a single operation.
+    pub fn delete_operation(&self, operation_id: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM operations WHERE hash = ?1",
+            params![operation_id.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `true` if an operation with this hash was persisted.
+    pub fn has_operation(&self, hash: &Hash) -> Result<bool, StoreError> {
+        let count: i64 = self.connection().query_row(
+            "SELECT COUNT(*) FROM operations WHERE hash = ?1",
+            params![hash.to_hex()],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Number of persisted operations authored by the given public key.
+    pub fn count_operations_by(&self, public_key: &PublicKey) -> Result<usize, StoreError> {
+        let count: i64 = self.connection().query_row(
+            "SELECT COUNT(*) FROM operations WHERE public_key = ?1",
+            params![public_key.to_hex()],
+            |row| row.get(0),
+        )?;
+        Ok(count as usize)
+    }
+
+    /// Load all persisted operations, ordered by author, log and sequence number.
+    pub fn operations(&self) -> Result<Vec<StoredOperation>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT header, body FROM operations ORDER BY public_key, log_id, seq_num")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?))
+        })?;
+
+        let mut operations = Vec::new();
+        for row in rows {
+            let (header_bytes, body_bytes) = row?;
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            let body = body_bytes.map(|bytes| Body::new(&bytes));
+            operations.push((header, body, header_bytes));
+        }
+        Ok(operations)
+    }
+
+    /// Headers of all persisted operations, or only those in the given stream, together with the
+    /// size of header and payload in bytes.
+    pub fn operation_sizes(
+        &self,
+        stream_id: Option<&Hash>,
+    ) -> Result<Vec<(Header<Extensions>, u64)>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT header, LENGTH(header) + IFNULL(LENGTH(body), 0) FROM operations
+             WHERE ?1 IS NULL OR stream_id = ?1",
+        )?;
+        let rows = statement.query_map(params![stream_id.map(Hash::to_hex)], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut operations = Vec::new();
+        for row in rows {
+            let (header_bytes, size) = row?;
+            operations.push((decode_cbor(&header_bytes[..])?, size as u64));
+        }
+        Ok(operations)
+    }
+
+    /// Insert all persisted operations into the node's in-memory store. Operations without a log
+    /// id can't be inserted and are skipped. Returns the number of operations which were loaded.
+    pub async fn hydrate(
+        &self,
+        store: &mut MemoryStore<LogId, Extensions>,
+    ) -> Result<usize, StoreError> {
+        let mut count = 0;
+        for (header, body, header_bytes) in self.operations()? {
+            let Some(log_id) = header.extension::<LogId>() else {
+                warn!("skipping operation {} without log id", header.hash());
+                continue;
+            };
+            store
+                .insert_operation(
+                    header.hash(),
+                    &header,
+                    body.as_ref(),
+                    &header_bytes,
+                    &log_id,
+                )
+                .await
+                .map_err(|err| StoreError::Hydrate(err.to_string()))?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    /// Persist that the log of an author belongs to a topic.
+    pub fn insert_topic_log(
+        &self,
+        topic: &str,
+        public_key: &PublicKey,
+        log_id: &LogId,
+    ) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO topic_logs (topic, public_key, log_id) VALUES (?1, ?2, ?3)",
+            params![topic, public_key.to_hex(), log_id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Remove the log of an author from a topic.
+    pub fn delete_topic_log(
+        &self,
+        topic: &str,
+        public_key: &PublicKey,
+        log_id: &LogId,
+    ) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM topic_logs WHERE topic = ?1 AND public_key = ?2 AND log_id = ?3",
+            params![topic, public_key.to_hex(), log_id.0],
+        )?;
+        Ok(())
+    }
+
+    /// Load all persisted topic logs.
+    pub fn topic_logs(&self) -> Result<Vec<(String, PublicKey, LogId)>, StoreError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT topic, public_key, log_id FROM topic_logs")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+            ))
+        })?;
+
+        let mut topic_logs = Vec::new();
+        for row in rows {
+            let (topic, public_key, log_id) = row?;
+            let public_key = PublicKey::from_str(&public_key)
+                .map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+            topic_logs.push((topic, public_key, LogId(log_id)));
+        }
+        Ok(topic_logs)
+    }
+
+    /// Rewrite the log ids of persisted operations and topic logs from the ambiguous
+    /// `<stream_id>/<log_path>` format used before to the current encoding, see `to_log_id`.
+    ///
+    /// Operations carry their stream and log path in the header. Old topic log ids are hashed and
+    /// can only be mapped when we have an operation of the same log, the remaining ones are
+    /// removed and logged.
+    pub fn migrate_log_ids(&self) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+
+        let operations = {
+            let mut statement = transaction.prepare("SELECT hash, header FROM operations")?;
+            let rows = statement.query_map([], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+
+        let mut log_ids = HashMap::new();
+        for (hash, header_bytes) in operations {
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            let stream: Stream = header.extension().expect("extract stream extension");
+            let log_path: Option<LogPath> = header.extension();
+            let legacy_log_id = legacy_log_id(&stream, log_path.as_ref());
+            let log_id = to_log_id(stream, log_path);
+
+            transaction.execute(
+                "UPDATE operations SET log_id = ?1 WHERE hash = ?2",
+                params![log_id.0, hash],
+            )?;
+            log_ids.insert(legacy_log_id.0, log_id.0);
+        }
+
+        let topic_logs = {
+            let mut statement =
+                transaction.prepare("SELECT topic, public_key, log_id FROM topic_logs")?;
+            let rows = statement.query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                ))
+            })?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        for (topic, public_key, legacy_log_id) in topic_logs {
+            transaction.execute(
+                "DELETE FROM topic_logs WHERE topic = ?1 AND public_key = ?2 AND log_id = ?3",
+                params![topic, public_key, legacy_log_id],
+            )?;
+            let Some(log_id) = log_ids.get(&legacy_log_id) else {
+                warn!("removing log {legacy_log_id} without operations from topic {topic}");
+                continue;
+            };
+            transaction.execute(
+                "INSERT OR IGNORE INTO topic_logs (topic, public_key, log_id)
+                    VALUES (?1, ?2, ?3)",
+                params![topic, public_key, log_id],
+            )?;
+        }
+
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Remember that we subscribed to a persisted topic.
+    pub fn insert_subscription(&self, topic: &str) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO subscriptions (topic) VALUES (?1)",
+            params![topic],
+        )?;
+        Ok(())
+    }
+
+    /// Load all persisted topics we subscribed to.
+    pub fn subscriptions(&self) -> Result<Vec<String>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare("SELECT topic FROM subscriptions")?;
+        let topics = statement
+            .query_map([], |row| row.get::<_, String>(0))?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(topics)
+    }
+
+    /// Remember that the frontend successfully processed an operation.
+    pub fn insert_ack(&self, operation_id: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO acks (hash) VALUES (?1)",
+            params![operation_id.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `true` if the frontend already acknowledged this operation.
+    pub fn is_acked(&self, operation_id: &Hash) -> Result<bool, StoreError> {
+        let count: i64 = self.connection().query_row(
+            "SELECT COUNT(*) FROM acks WHERE hash = ?1",
+            params![operation_id.to_hex()],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Load all operations which still have a payload.
+    pub fn payloads(&self) -> Result<Vec<StoredPayload>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection
+            .prepare("SELECT header, LENGTH(body) FROM operations WHERE body IS NOT NULL")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, i64>(1)?))
+        })?;
+
+        let mut payloads = Vec::new();
+        for row in rows {
+            let (header_bytes, size) = row?;
+            payloads.push(StoredPayload {
+                header: decode_cbor(&header_bytes[..])?,
+                size: size as u64,
+            });
+        }
+        Ok(payloads)
+    }
+
+    /// Delete the payload of an operation, keeping its header.
+    pub fn delete_payload(&self, operation_id: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "UPDATE operations SET body = NULL WHERE hash = ?1",
+            params![operation_id.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Record that a blob is stored locally and was accessed just now.
+    pub fn record_blob(&self, hash: &Hash, size: u64, authored: bool) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT INTO blobs (hash, size, authored, last_accessed) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT (hash) DO UPDATE SET
+                size = excluded.size,
+                authored = MAX(authored, excluded.authored),
+                last_accessed = excluded.last_accessed",
+            params![hash.to_hex(), size as i64, authored, unix_time() as i64],
+        )?;
+        Ok(())
+    }
+
+    /// Pin or unpin a blob. Returns `false` if the blob is not stored locally.
+    pub fn pin_blob(&self, hash: &Hash, pinned: bool) -> Result<bool, StoreError> {
+        let updated = self.connection().execute(
+            "UPDATE blobs SET pinned = ?2 WHERE hash = ?1",
+            params![hash.to_hex(), pinned],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Load all blobs which are stored locally.
+    pub fn blobs(&self) -> Result<Vec<StoredBlob>, StoreError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT hash, size, authored, pinned, last_accessed FROM blobs")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, i64>(1)?,
+                row.get::<_, bool>(2)?,
+                row.get::<_, bool>(3)?,
+                row.get::<_, i64>(4)?,
+            ))
+        })?;
+
+        let mut blobs = Vec::new();
+        for row in rows {
+            let (hash, size, authored, pinned, last_accessed) = row?;
+            blobs.push(StoredBlob {
+                hash: Hash::from_str(&hash)
+                    .map_err(|err| StoreError::InvalidValue(err.to_string()))?,
+                size: size as u64,
+                authored,
+                pinned,
+                last_accessed: last_accessed as u64,
+            });
+        }
+        Ok(blobs)
+    }
+
+    /// Forget a blob which was removed from the blob store.
+    pub fn delete_blob(&self, hash: &Hash) -> Result<(), StoreError> {
+        self.connection()
+            .execute("DELETE FROM blobs WHERE hash = ?1", params![hash.to_hex()])?;
+        Ok(())
+    }
+
+    /// Total size of all operation payloads in bytes.
+    pub fn payload_usage(&self) -> Result<u64, StoreError> {
+        let size: i64 = self.connection().query_row(
+            "SELECT COALESCE(SUM(LENGTH(body)), 0) FROM operations",
+            [],
+            |row| row.get(0),
+        )?;
+        Ok(size as u64)
+    }
+
+    /// Total size of all recorded blobs in bytes.
+    pub fn blob_usage(&self) -> Result<u64, StoreError> {
+        let size: i64 =
+            self.connection()
+                .query_row("SELECT COALESCE(SUM(size), 0) FROM blobs", [], |row| {
+                    row.get(0)
+                })?;
+        Ok(size as u64)
+    }
+
+    /// Read a setting, returns `None` if it was never set.
+    pub fn setting<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>, StoreError> {
+        let value: Option<String> = self
+            .connection()
+            .query_row(
+                "SELECT value FROM settings WHERE key = ?1",
+                params![key],
+                |row| row.get(0),
+            )
+            .optional()?;
+        value
+            .map(|value| serde_json::from_str(&value))
+            .transpose()
+            .map_err(|err| StoreError::InvalidValue(err.to_string()))
+    }
+
+    /// Persist a setting.
+    pub fn set_setting<T: Serialize>(&self, key: &str, value: &T) -> Result<(), StoreError> {
+        let value = serde_json::to_string(value)
+            .map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+        self.connection().execute(
+            "INSERT INTO settings (key, value) VALUES (?1, ?2)
+             ON CONFLICT (key) DO UPDATE SET value = excluded.value",
+            params![key, value],
+        )?;
+        Ok(())
+    }
+
+    /// Persist an operation we created and add it to the outbox before it is published. Both
+    /// happen in one transaction, an operation waiting in the outbox is always part of our log
+    /// after a restart and its sequence number is never used twice.
+    pub fn insert_outbox(
+        &self,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+        log_id: &LogId,
+        topic: Option<&str>,
+    ) -> Result<(), StoreError> {
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        Self::insert_operation_with(&transaction, header, body, log_id)?;
+        transaction.execute(
+            "INSERT OR IGNORE INTO outbox (hash, topic, header, body) VALUES (?1, ?2, ?3, ?4)",
+            params![
+                header.hash().to_hex(),
+                topic,
+                header.to_bytes(),
+                body.map(|body| body.to_bytes()),
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Remove an operation from the outbox after it was published.
+    pub fn delete_outbox(&self, operation_id: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM outbox WHERE hash = ?1",
+            params![operation_id.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Record a failed attempt to publish an operation from the outbox.
+    pub fn record_outbox_failure(
+        &self,
+        operation_id: &Hash,
+        error: &str,
+    ) -> Result<(), StoreError> {
+        self.connection().execute(
+            "UPDATE outbox SET attempts = attempts + 1, last_error = ?2 WHERE hash = ?1",
+            params![operation_id.to_hex(), error],
+        )?;
+        Ok(())
+    }
+
+    /// Load all pending outbox entries in the order they were created.
+    pub fn outbox(&self) -> Result<Vec<OutboxEntry>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT topic, header, body, attempts, last_error FROM outbox ORDER BY rowid",
+        )?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, Option<String>>(0)?,
+                row.get::<_, Vec<u8>>(1)?,
+                row.get::<_, Option<Vec<u8>>>(2)?,
+                row.get::<_, i64>(3)?,
+                row.get::<_, Option<String>>(4)?,
+            ))
+        })?;
+
+        let mut entries = Vec::new();
+        for row in rows {
+            let (topic, header_bytes, body_bytes, attempts, last_error) = row?;
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            entries.push(OutboxEntry {
+                operation_id: header.hash(),
+                topic,
+                attempts: attempts as u64,
+                last_error,
+                header,
+                body: body_bytes.map(|bytes| Body::new(&bytes)),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Persist a verified key succession record.
+    ///
+    /// Records which would give a key a second successor or predecessor or close a cycle are
+    /// rejected, the first record we learned about wins. Returns `false` if the record was
+    /// already known.
+    pub fn insert_succession(&self, succession: &KeySuccession) -> Result<bool, StoreError> {
+        if self.successor(&succession.previous)? == Some(succession.next) {
+            return Ok(false);
+        }
+        if self.identity_of(&succession.previous)? == succession.next {
+            return Err(SuccessionError::Conflict.into());
+        }
+
+        let result = self.connection().execute(
+            "INSERT INTO successions (previous, next, record) VALUES (?1, ?2, ?3)",
+            params![
+                succession.previous.to_hex(),
+                succession.next.to_hex(),
+                serde_json::to_string(succession).expect("encode key succession"),
+            ],
+        );
+        match result {
+            Ok(_) => Ok(true),
+            Err(rusqlite::Error::SqliteFailure(err, _))
+                if err.code == rusqlite::ErrorCode::ConstraintViolation =>
+            {
+                Err(SuccessionError::Conflict.into())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Remove the succession record of a key.
+    pub fn delete_succession(&self, previous: &PublicKey) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM successions WHERE previous = ?1",
+            params![previous.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    fn successor(&self, public_key: &PublicKey) -> Result<Option<PublicKey>, StoreError> {
+        self.linked_key(
+            "SELECT next FROM successions WHERE previous = ?1",
+            public_key,
+        )
+    }
+
+    fn predecessor(&self, public_key: &PublicKey) -> Result<Option<PublicKey>, StoreError> {
+        self.linked_key(
+            "SELECT previous FROM successions WHERE next = ?1",
+            public_key,
+        )
+    }
+
+    fn linked_key(
+        &self,
+        query: &str,
+        public_key: &PublicKey,
+    ) -> Result<Option<PublicKey>, StoreError> {
+        let value = self
+            .connection()
+            .query_row(query, params![public_key.to_hex()], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?;
+        value
+            .map(|value| {
+                PublicKey::from_str(&value).map_err(|err| StoreError::InvalidValue(err.to_string()))
+            })
+            .transpose()
+    }
+
+    /// Persist a verified device delegation certificate.
+    ///
+    /// A device key can only be delegated once, keys which delegate devices themselves can't be
+    /// delegated and delegated keys can't delegate further devices. Returns `false` if the
+    /// certificate was already known.
+    pub fn insert_delegation(&self, delegation: &DeviceDelegation) -> Result<bool, StoreError> {
+        match self.delegator(&delegation.device)? {
+            Some(identity) if identity == delegation.identity => return Ok(false),
+            Some(_) => return Err(DelegationError::Conflict.into()),
+            None => (),
+        }
+        if self.delegator(&delegation.identity)?.is_some()
+            || !self.devices(&delegation.device)?.is_empty()
+            || self.identity_of(&delegation.identity)? == self.identity_of(&delegation.device)?
+        {
+            return Err(DelegationError::Conflict.into());
+        }
+
+        self.connection().execute(
+            "INSERT INTO delegations (device, identity, record) VALUES (?1, ?2, ?3)",
+            params![
+                delegation.device.to_hex(),
+                delegation.identity.to_hex(),
+                serde_json::to_string(delegation).expect("encode device delegation"),
+            ],
+        )?;
+        Ok(true)
+    }
+
+    fn delegator(&self, device: &PublicKey) -> Result<Option<PublicKey>, StoreError> {
+        self.linked_key("SELECT identity FROM delegations WHERE device = ?1", device)
+    }
+
+    /// Device keys delegated by the given key.
+    pub fn devices(&self, identity: &PublicKey) -> Result<Vec<PublicKey>, StoreError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT device FROM delegations WHERE identity = ?1")?;
+        let rows =
+            statement.query_map(params![identity.to_hex()], |row| row.get::<_, String>(0))?;
+
+        let mut devices = Vec::new();
+        for row in rows {
+            let device = PublicKey::from_str(&row?)
+                .map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+            devices.push(device);
+        }
+        Ok(devices)
+    }
+
+    /// The identity a key belongs to.
+    ///
+    /// This is the first key of its succession chain. For delegated device keys it is the
+    /// identity of the key which delegated the device.
+    pub fn identity_of(&self, public_key: &PublicKey) -> Result<PublicKey, StoreError> {
+        let mut identity = *public_key;
+        let mut visited = HashSet::from([identity]);
+        loop {
+            let next = match self.predecessor(&identity)? {
+                Some(previous) => Some(previous),
+                None => self.delegator(&identity)?,
+            };
+            match next {
+                Some(next) if visited.insert(next) => identity = next,
+                _ => return Ok(identity),
+            }
+        }
+    }
+
+    /// All keys of the identity the given key belongs to, including rotated keys and delegated
+    /// devices.
+    pub fn identity_keys(&self, public_key: &PublicKey) -> Result<Vec<PublicKey>, StoreError> {
+        let mut keys = vec![self.identity_of(public_key)?];
+        let mut index = 0;
+        while let Some(key) = keys.get(index).copied() {
+            let successor = self.successor(&key)?;
+            for linked_key in successor.into_iter().chain(self.devices(&key)?) {
+                if !keys.contains(&linked_key) {
+                    keys.push(linked_key);
+                }
+            }
+            index += 1;
+        }
+        Ok(keys)
+    }
+
+    /// Persist a write capability published with the given operation.
+    ///
+    /// Only keys of the stream owner's identity can issue capabilities. A capability replaces an
+    /// earlier one for the same log path and subject, the operation with the latest timestamp
+    /// wins.
+    pub fn insert_write_capability(
+        &self,
+        header: &Header<Extensions>,
+        capability: &WriteCapability,
+    ) -> Result<(), StoreError> {
+        let stream: Stream = header.extension().expect("extract stream extension");
+        if self.identity_of(&header.public_key)? != self.identity_of(&stream.owner.into())? {
+            return Err(CapabilityError::NotOwner.into());
+        }
+
+        let stream_id = stream.id().to_hex();
+        let log_path = log_path_column(capability.log_path.as_ref());
+        let subject = capability.subject.to_hex();
+        let timestamp = header.timestamp as i64;
+        let operation_id = header.hash().to_hex();
+
+        // The log without a path has a NULL log path, which never conflicts in a unique index.
+        // Earlier capabilities are therefore looked up with `IS` and replaced by hand.
+        let mut connection = self.connection();
+        let transaction = connection.transaction()?;
+        let latest: Option<(i64, String)> = transaction
+            .query_row(
+                "SELECT timestamp, operation_id FROM write_capabilities
+                 WHERE stream_id = ?1 AND log_path IS ?2 AND subject = ?3",
+                params![stream_id, log_path, subject],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        if latest.is_some_and(|latest| latest >= (timestamp, operation_id.clone())) {
+            return Ok(());
+        }
+
+        transaction.execute(
+            "DELETE FROM write_capabilities
+             WHERE stream_id = ?1 AND log_path IS ?2 AND subject = ?3",
+            params![stream_id, log_path, subject],
+        )?;
+        transaction.execute(
+            "INSERT INTO write_capabilities
+                (stream_id, log_path, subject, granted, timestamp, operation_id)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            params![
+                stream_id,
+                log_path,
+                subject,
+                capability.access == WriteAccess::Grant,
+                timestamp,
+                operation_id,
+            ],
+        )?;
+        transaction.commit()?;
+        Ok(())
+    }
+
+    /// Check that the author may write to the log path of the stream.
+    ///
+    /// Keys of the stream owner's identity may always write. Log paths without capabilities are
+    /// open to everyone, all others only to identities which were granted access.
+    pub fn check_write_access(
+        &self,
+        stream: &Stream,
+        log_path: Option<&LogPath>,
+        author: &PublicKey,
+    ) -> Result<(), StoreError> {
+        let identity = self.identity_of(author)?;
+        if identity == self.identity_of(&stream.owner.into())? {
+            return Ok(());
+        }
+
+        let capabilities = {
+            let connection = self.connection();
+            let mut statement = connection.prepare(
+                "SELECT subject, granted FROM write_capabilities
+                 WHERE stream_id = ?1 AND log_path IS ?2",
+            )?;
+            let rows = statement.query_map(
+                params![stream.id().to_hex(), log_path_column(log_path)],
+                |row| Ok((row.get::<_, String>(0)?, row.get::<_, bool>(1)?)),
+            )?;
+            rows.collect::<Result<Vec<_>, _>>()?
+        };
+        if capabilities.is_empty() {
+            return Ok(());
+        }
+
+        for (subject, granted) in capabilities {
+            let subject = PublicKey::from_str(&subject)
+                .map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+            if granted && self.identity_of(&subject)? == identity {
+                return Ok(());
+            }
+        }
+
+        Err(CapabilityError::Unauthorized {
+            author: *author,
+            stream_id: stream.id(),
+            log_path: log_path.map(ToString::to_string).unwrap_or_default(),
+        }
+        .into())
+    }
+
+    /// Keep an operation which was rejected for missing write access, so it can be checked again
+    /// when further capabilities of its stream arrive.
+    pub fn insert_rejected(
+        &self,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+    ) -> Result<(), StoreError> {
+        let stream: Stream = header.extension().expect("extract stream extension");
+        let log_id: LogId = header.extension().expect("extract log id extension");
+        self.connection().execute(
+            "INSERT OR IGNORE INTO rejected_operations
+                (hash, stream_id, public_key, log_id, seq_num, header, body)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+            params![
+                header.hash().to_hex(),
+                stream.id().to_hex(),
+                header.public_key.to_hex(),
+                log_id.0,
+                header.seq_num as i64,
+                header.to_bytes(),
+                body.map(|body| body.to_bytes()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Load all rejected operations of a stream, ordered by author, log and sequence number.
+    pub fn rejected_operations(
+        &self,
+        stream: &Stream,
+    ) -> Result<Vec<(Header<Extensions>, Option<Body>)>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT header, body FROM rejected_operations
+             WHERE stream_id = ?1
+             ORDER BY public_key, log_id, seq_num",
+        )?;
+        let rows = statement.query_map(params![stream.id().to_hex()], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?))
+        })?;
+
+        let mut operations = Vec::new();
+        for row in rows {
+            let (header_bytes, body_bytes) = row?;
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            operations.push((header, body_bytes.map(|bytes| Body::new(&bytes))));
+        }
+        Ok(operations)
+    }
+
+    /// Returns `true` if the operation was rejected and not accepted since.
+    pub fn is_rejected(&self, hash: &Hash) -> Result<bool, StoreError> {
+        let rejected = self
+            .connection()
+            .query_row(
+                "SELECT 1 FROM rejected_operations WHERE hash = ?1",
+                params![hash.to_hex()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(rejected.is_some())
+    }
+
+    pub fn delete_rejected(&self, hash: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM rejected_operations WHERE hash = ?1",
+            params![hash.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Returns `true` if the operation was deleted by a prune point.
+    pub fn is_pruned(&self, hash: &Hash) -> Result<bool, StoreError> {
+        let pruned = self
+            .connection()
+            .query_row(
+                "SELECT 1 FROM pruned_operations WHERE hash = ?1",
+                params![hash.to_hex()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(pruned.is_some())
+    }
+
+    /// Remember an operation which is held back until its dependencies were delivered, so it is
+    /// delivered after a restart as well.
+    pub fn insert_pending(
+        &self,
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+    ) -> Result<(), StoreError> {
+        self.connection().execute(
+            "INSERT OR IGNORE INTO pending_operations (hash, header, body) VALUES (?1, ?2, ?3)",
+            params![
+                header.hash().to_hex(),
+                header.to_bytes(),
+                body.map(|body| body.to_bytes()),
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Load all held back operations in the order they arrived in.
+    pub fn pending_operations(
+        &self,
+    ) -> Result<Vec<(Header<Extensions>, Option<Body>)>, StoreError> {
+        let connection = self.connection();
+        let mut statement =
+            connection.prepare("SELECT header, body FROM pending_operations ORDER BY rowid")?;
+        let rows = statement.query_map([], |row| {
+            Ok((row.get::<_, Vec<u8>>(0)?, row.get::<_, Option<Vec<u8>>>(1)?))
+        })?;
+
+        let mut operations = Vec::new();
+        for row in rows {
+            let (header_bytes, body_bytes) = row?;
+            let header: Header<Extensions> = decode_cbor(&header_bytes[..])?;
+            operations.push((header, body_bytes.map(|bytes| Body::new(&bytes))));
+        }
+        Ok(operations)
+    }
+
+    /// Returns `true` if the operation is held back.
+    pub fn is_pending(&self, hash: &Hash) -> Result<bool, StoreError> {
+        let pending = self
+            .connection()
+            .query_row(
+                "SELECT 1 FROM pending_operations WHERE hash = ?1",
+                params![hash.to_hex()],
+                |_| Ok(()),
+            )
+            .optional()?;
+        Ok(pending.is_some())
+    }
+
+    pub fn delete_pending(&self, hash: &Hash) -> Result<(), StoreError> {
+        self.connection().execute(
+            "DELETE FROM pending_operations WHERE hash = ?1",
+            params![hash.to_hex()],
+        )?;
+        Ok(())
+    }
+
+    /// Load all acknowledged operations, ordered by author, log and sequence number.
+    pub fn acks(&self) -> Result<Vec<Hash>, StoreError> {
+        let connection = self.connection();
+        let mut statement = connection.prepare(
+            "SELECT acks.hash FROM acks
+             JOIN operations ON operations.hash = acks.hash
+             ORDER BY operations.public_key, operations.log_id, operations.seq_num",
+        )?;
+        let rows = statement.query_map([], |row| row.get::<_, String>(0))?;
+
+        let mut acks = Vec::new();
+        for row in rows {
+            let hash =
+                Hash::from_str(&row?).map_err(|err| StoreError::InvalidValue(err.to_string()))?;
+            acks.push(hash);
+        }
+        Ok(acks)
+    }
+}
+
+/// Log paths are stored as strings, the log without a path as NULL.
+fn log_path_column(log_path: Option<&LogPath>) -> Option<String> {
+    log_path.map(ToString::to_string)
+}
+
+#[derive(Debug, Error)]
+pub enum StoreError {
+    #[error(transparent)]
+    Sqlite(#[from] rusqlite::Error),
+
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+
+    #[error("decoding persisted operation failed: {0}")]
+    Decode(#[from] DecodeError),
+
+    #[error("loading persisted operation into node store failed: {0}")]
+    Hydrate(String),
+
+    #[error("invalid value in database: {0}")]
+    InvalidValue(String),
+
+    #[error("database schema version {found} is newer than the supported version {supported}")]
+    UnsupportedSchemaVersion { found: usize, supported: usize },
+
+    #[error(transparent)]
+    Succession(#[from] SuccessionError),
+
+    #[error(transparent)]
+    Delegation(#[from] DelegationError),
+
+    #[error(transparent)]
+    Capability(#[from] CapabilityError),
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Hash, Header, PrivateKey};
+    use p2panda_node::extensions::LogId;
+    use p2panda_store::{LogStore, MemoryStore, OperationStore};
+    use rusqlite::{params, Connection};
+    use tempfile::tempdir;
+
+    use crate::capabilities::{CapabilityError, WriteAccess, WriteCapability};
+    use crate::delegation::{DelegationError, DelegationRequest, DeviceDelegation};
+    use crate::extensions::{Extensions, LogPath, Stream};
+    use crate::succession::{KeySuccession, SuccessionError};
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{SqliteStore, StoreError, DATABASE_FILE_NAME, MIGRATIONS};
+
+    /// Template for a header in the `messages` log of a new stream.
+    fn messages_log() -> Header<Extensions> {
+        with_extensions(Extensions {
+            log_path: Some(LogPath::try_from("messages".to_string()).unwrap()),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn operations_survive_reopen() {
+        let tmp_dir = tempdir().unwrap();
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let header = create_header(&private_key, &body, messages_log());
+        let log_id: LogId = header.extension().unwrap();
+
+        {
+            let store = SqliteStore::open(tmp_dir.path()).unwrap();
+            assert!(store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap());
+
+            // Inserting the same operation twice is a no-op.
+            assert!(!store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap());
+        }
+
+        let store = SqliteStore::open(tmp_dir.path()).unwrap();
+        assert!(store.has_operation(&header.hash()).unwrap());
+
+        let mut memory_store = MemoryStore::new();
+        assert_eq!(store.hydrate(&mut memory_store).await.unwrap(), 1);
+
+        let log = memory_store
+            .get_log(&private_key.public_key(), &log_id, None)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].0.hash(), header.hash());
+        assert_eq!(log[0].1, Some(body));
+    }
+
+    #[tokio::test]
+    async fn hydrate_operation_without_log_id() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let header = create_header(&private_key, &body, Header::default());
+        store
+            .insert_operation(&header, Some(&body), &LogId("messages".into()))
+            .unwrap();
+
+        let mut memory_store = MemoryStore::new();
+        assert_eq!(store.hydrate(&mut memory_store).await.unwrap(), 0);
+        assert!(!memory_store.has_operation(header.hash()).await.unwrap());
+    }
+
+    #[test]
+    fn prune_log() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+
+        let mut headers = Vec::new();
+        for seq_num in 0..3 {
+            let body = Body::new(format!("message {seq_num}").as_bytes());
+            let header = create_header(
+                &private_key,
+                &body,
+                Header {
+                    seq_num,
+                    backlink: headers
+                        .last()
+                        .map(|header: &Header<Extensions>| header.hash()),
+                    ..messages_log()
+                },
+            );
+            let log_id: LogId = header.extension().unwrap();
+            store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap();
+            store.insert_ack(&header.hash()).unwrap();
+            headers.push(header);
+        }
+
+        let log_id: LogId = headers[0].extension().unwrap();
+        let deleted = store
+            .prune_log(&private_key.public_key(), &log_id, 2)
+            .unwrap();
+        assert_eq!(deleted, vec![headers[0].hash(), headers[1].hash()]);
+        assert!(store.is_pruned(&headers[0].hash()).unwrap());
+        assert!(!store.is_pruned(&headers[2].hash()).unwrap());
+        assert!(!store.has_operation(&headers[0].hash()).unwrap());
+        assert!(!store.has_operation(&headers[1].hash()).unwrap());
+        assert!(store.has_operation(&headers[2].hash()).unwrap());
+        assert_eq!(store.acks().unwrap(), vec![headers[2].hash()]);
+    }
+
+    #[test]
+    fn pending_operations() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+
+        let mut hashes = Vec::new();
+        for message in ["booking accepted", "booking requested"] {
+            let body = Body::new(message.as_bytes());
+            let header = create_header(&private_key, &body, messages_log());
+            store.insert_pending(&header, Some(&body)).unwrap();
+            hashes.push(header.hash());
+        }
+        assert!(store.is_pending(&hashes[0]).unwrap());
+
+        // Held back operations are loaded in the order they arrived in.
+        let pending: Vec<_> = store
+            .pending_operations()
+            .unwrap()
+            .into_iter()
+            .map(|(header, _)| header.hash())
+            .collect();
+        assert_eq!(pending, hashes);
+
+        store.delete_pending(&hashes[0]).unwrap();
+        assert!(!store.is_pending(&hashes[0]).unwrap());
+        assert_eq!(store.pending_operations().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn purge_expired_operations() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+
+        let mut headers = Vec::new();
+        for expires_at in [Some(100), Some(200), None] {
+            let body = Body::new(b"booking requested");
+            let mut template = messages_log();
+            template.extensions.as_mut().unwrap().expires_at = expires_at.map(Into::into);
+            let header = create_header(&private_key, &body, template);
+            let log_id: LogId = header.extension().unwrap();
+            store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap();
+            store.insert_ack(&header.hash()).unwrap();
+            headers.push(header);
+        }
+
+        assert!(store.purge_expired(99).unwrap().is_empty());
+        assert_eq!(store.purge_expired(150).unwrap(), vec![headers[0].hash()]);
+        assert_eq!(store.payloads().unwrap().len(), 2);
+
+        // Headers stay in the logs, only the payloads are gone.
+        for header in &headers {
+            assert!(store.has_operation(&header.hash()).unwrap());
+        }
+
+        assert_eq!(
+            store.purge_expired(u32::MAX as u64).unwrap(),
+            vec![headers[1].hash()]
+        );
+        assert_eq!(store.payloads().unwrap().len(), 1);
+        assert_eq!(store.acks().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn acks_survive_reopen() {
+        let tmp_dir = tempdir().unwrap();
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let header = create_header(&private_key, &body, messages_log());
+        let log_id: LogId = header.extension().unwrap();
+
+        {
+            let store = SqliteStore::open(tmp_dir.path()).unwrap();
+            store
+                .insert_operation(&header, Some(&body), &log_id)
+                .unwrap();
+            assert!(!store.is_acked(&header.hash()).unwrap());
+            store.insert_ack(&header.hash()).unwrap();
+        }
+
+        let store = SqliteStore::open(tmp_dir.path()).unwrap();
+        assert!(store.is_acked(&header.hash()).unwrap());
+        assert_eq!(store.acks().unwrap(), vec![header.hash()]);
+    }
+
+    #[test]
+    fn storage_accounting() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"organize!");
+        let header = create_header(&private_key, &body, messages_log());
+        let log_id: LogId = header.extension().unwrap();
+        store
+            .insert_operation(&header, Some(&body), &log_id)
+            .unwrap();
+
+        // Recording the same blob twice only counts it once.
+        let blob_hash = Body::new(b"image").hash();
+        store.record_blob(&blob_hash, 5, false).unwrap();
+        store.record_blob(&blob_hash, 5, true).unwrap();
+        assert_eq!(store.blob_usage().unwrap(), 5);
+        assert_eq!(store.payload_usage().unwrap(), body.size());
+
+        let payloads = store.payloads().unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].size, body.size());
+
+        // Blobs can be pinned once they are stored locally.
+        assert!(store.pin_blob(&blob_hash, true).unwrap());
+        assert!(!store.pin_blob(&body.hash(), true).unwrap());
+        let blobs = store.blobs().unwrap();
+        assert_eq!(blobs.len(), 1);
+        assert!(blobs[0].authored && blobs[0].pinned);
+        assert!(blobs[0].last_accessed > 0);
+        store.delete_blob(&blob_hash).unwrap();
+        assert_eq!(store.blob_usage().unwrap(), 0);
+
+        // The header is kept when the payload gets evicted.
+        store.delete_payload(&header.hash()).unwrap();
+        assert!(store.payloads().unwrap().is_empty());
+        assert_eq!(store.payload_usage().unwrap(), 0);
+        assert!(store.has_operation(&header.hash()).unwrap());
+
+        assert_eq!(store.setting::<u64>("quota").unwrap(), None);
+        store.set_setting("quota", &1024).unwrap();
+        assert_eq!(store.setting::<u64>("quota").unwrap(), Some(1024));
+    }
+
+    #[test]
+    fn outbox() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let private_key = PrivateKey::new();
+        let body_a = Body::new(b"organize!");
+        let header_a = create_header(&private_key, &body_a, messages_log());
+        let body_b = Body::new(b"organize more!");
+        let header_b = create_header(&private_key, &body_b, messages_log());
+
+        let log_id_a: LogId = header_a.extension().unwrap();
+        let log_id_b: LogId = header_b.extension().unwrap();
+        store
+            .insert_outbox(&header_a, Some(&body_a), &log_id_a, Some("messages"))
+            .unwrap();
+        store
+            .insert_outbox(&header_b, Some(&body_b), &log_id_b, None)
+            .unwrap();
+
+        // Operations in the outbox are part of our log already.
+        assert!(store.has_operation(&header_a.hash()).unwrap());
+        assert!(store.has_operation(&header_b.hash()).unwrap());
+        store
+            .record_outbox_failure(&header_a.hash(), "no peers")
+            .unwrap();
+
+        let entries = store.outbox().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].operation_id, header_a.hash());
+        assert_eq!(entries[0].topic.as_deref(), Some("messages"));
+        assert_eq!(entries[0].attempts, 1);
+        assert_eq!(entries[0].last_error.as_deref(), Some("no peers"));
+        assert_eq!(entries[0].body, Some(body_a));
+        assert_eq!(entries[1].operation_id, header_b.hash());
+        assert_eq!(entries[1].topic, None);
+
+        store.delete_outbox(&header_a.hash()).unwrap();
+        let entries = store.outbox().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].operation_id, header_b.hash());
+    }
+
+    #[test]
+    fn key_succession_chain() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let key_a = PrivateKey::new();
+        let key_b = PrivateKey::new();
+        let key_c = PrivateKey::new();
+
+        assert!(store
+            .insert_succession(&KeySuccession::new(&key_a, &key_b))
+            .unwrap());
+        assert!(store
+            .insert_succession(&KeySuccession::new(&key_b, &key_c))
+            .unwrap());
+        assert!(!store
+            .insert_succession(&KeySuccession::new(&key_b, &key_c))
+            .unwrap());
+
+        assert_eq!(
+            store.identity_of(&key_c.public_key()).unwrap(),
+            key_a.public_key()
+        );
+        assert_eq!(
+            store.identity_keys(&key_b.public_key()).unwrap(),
+            vec![key_a.public_key(), key_b.public_key(), key_c.public_key()]
+        );
+
+        // A key can't get a second successor and chains can't be closed to a cycle.
+        assert!(matches!(
+            store.insert_succession(&KeySuccession::new(&key_a, &PrivateKey::new())),
+            Err(StoreError::Succession(SuccessionError::Conflict))
+        ));
+        assert!(matches!(
+            store.insert_succession(&KeySuccession::new(&key_c, &key_a)),
+            Err(StoreError::Succession(SuccessionError::Conflict))
+        ));
+    }
+
+    #[test]
+    fn delegated_device_keys() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let identity = PrivateKey::new();
+        let device = PrivateKey::new();
+        let rotated_identity = PrivateKey::new();
+
+        let delegation = DeviceDelegation::new(
+            &identity,
+            DelegationRequest::new(identity.public_key(), &device),
+        )
+        .unwrap();
+        assert!(store.insert_delegation(&delegation).unwrap());
+        assert!(!store.insert_delegation(&delegation).unwrap());
+        store
+            .insert_succession(&KeySuccession::new(&identity, &rotated_identity))
+            .unwrap();
+
+        assert_eq!(
+            store.identity_of(&device.public_key()).unwrap(),
+            identity.public_key()
+        );
+        assert_eq!(
+            store.identity_keys(&rotated_identity.public_key()).unwrap(),
+            vec![
+                identity.public_key(),
+                rotated_identity.public_key(),
+                device.public_key()
+            ]
+        );
+
+        // Devices can't be delegated twice or delegate further devices.
+        let other_identity = PrivateKey::new();
+        let delegation = DeviceDelegation::new(
+            &other_identity,
+            DelegationRequest::new(other_identity.public_key(), &device),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.insert_delegation(&delegation),
+            Err(StoreError::Delegation(DelegationError::Conflict))
+        ));
+        let delegation = DeviceDelegation::new(
+            &device,
+            DelegationRequest::new(device.public_key(), &other_identity),
+        )
+        .unwrap();
+        assert!(matches!(
+            store.insert_delegation(&delegation),
+            Err(StoreError::Delegation(DelegationError::Conflict))
+        ));
+    }
+
+    #[test]
+    fn write_capabilities() {
+        let store = SqliteStore::open_in_memory().unwrap();
+        let owner = PrivateKey::new();
+        let writer = PrivateKey::new();
+        let stream = Stream {
+            root_hash: Hash::new(b"calendar").into(),
+            owner: owner.public_key().into(),
+        };
+        let log_path = LogPath::try_from("calendar".to_string()).unwrap();
+
+        let capability_header = |author: &PrivateKey, log_path, access, timestamp| {
+            let capability = WriteCapability {
+                subject: writer.public_key(),
+                log_path,
+                access,
+            };
+            let body = Body::new(&capability.to_bytes());
+            let header = create_header(
+                author,
+                &body,
+                Header {
+                    timestamp,
+                    ..with_extensions(Extensions {
+                        stream_root_hash: Some(stream.root_hash),
+                        stream_owner: Some(stream.owner),
+                        ..Default::default()
+                    })
+                },
+            );
+            (header, capability)
+        };
+
+        // Log paths without capabilities are open to everyone.
+        store
+            .check_write_access(&stream, Some(&log_path), &writer.public_key())
+            .unwrap();
+
+        let (header, capability) =
+            capability_header(&writer, Some(log_path.clone()), WriteAccess::Grant, 1);
+        assert!(matches!(
+            store.insert_write_capability(&header, &capability),
+            Err(StoreError::Capability(CapabilityError::NotOwner))
+        ));
+
+        let (header, capability) =
+            capability_header(&owner, Some(log_path.clone()), WriteAccess::Revoke, 2);
+        store.insert_write_capability(&header, &capability).unwrap();
+        assert!(matches!(
+            store.check_write_access(&stream, Some(&log_path), &writer.public_key()),
+            Err(StoreError::Capability(CapabilityError::Unauthorized { .. }))
+        ));
+        store
+            .check_write_access(&stream, Some(&log_path), &owner.public_key())
+            .unwrap();
+        store
+            .check_write_access(&stream, None, &writer.public_key())
+            .unwrap();
+
+        // Older grants don't override newer revocations.
+        let (header, capability) =
+            capability_header(&owner, Some(log_path.clone()), WriteAccess::Grant, 1);
+        store.insert_write_capability(&header, &capability).unwrap();
+        assert!(store
+            .check_write_access(&stream, Some(&log_path), &writer.public_key())
+            .is_err());
+
+        let (header, capability) =
+            capability_header(&owner, Some(log_path.clone()), WriteAccess::Grant, 3);
+        store.insert_write_capability(&header, &capability).unwrap();
+        store
+            .check_write_access(&stream, Some(&log_path), &writer.public_key())
+            .unwrap();
+
+        // Capabilities of the log without a path don't apply to the empty log path.
+        let empty_log_path = LogPath::try_from(String::new()).unwrap();
+        let (header, capability) = capability_header(&owner, None, WriteAccess::Revoke, 4);
+        store.insert_write_capability(&header, &capability).unwrap();
+        assert!(store
+            .check_write_access(&stream, None, &writer.public_key())
+            .is_err());
+        store
+            .check_write_access(&stream, Some(&empty_log_path), &writer.public_key())
+            .unwrap();
+
+        let (header, capability) = capability_header(&owner, None, WriteAccess::Grant, 3);
+        store.insert_write_capability(&header, &capability).unwrap();
+        assert!(store
+            .check_write_access(&stream, None, &writer.public_key())
+            .is_err());
+
+        let (header, capability) = capability_header(&owner, None, WriteAccess::Grant, 5);
+        store.insert_write_capability(&header, &capability).unwrap();
+        store
+            .check_write_access(&stream, None, &writer.public_key())
+            .unwrap();
+    }
+
+    #[test]
+    fn migrate_older_database() {
+        let tmp_dir = tempdir().unwrap();
+
+        // A database written by a release which only knew the first migration.
+        let private_key = PrivateKey::new();
+        let body = Body::new(b"{}");
+        let header = create_header(&private_key, &body, messages_log());
+        {
+            let connection = Connection::open(tmp_dir.path().join(DATABASE_FILE_NAME)).unwrap();
+            connection.execute_batch(MIGRATIONS[0]).unwrap();
+            connection.pragma_update(None, "user_version", 1).unwrap();
+            connection
+                .execute(
+                    "INSERT INTO subscriptions (topic) VALUES ('calendar/123')",
+                    [],
+                )
+                .unwrap();
+            connection
+                .execute(
+                    "INSERT INTO operations
+                        (hash, public_key, log_id, seq_num, timestamp, header, body)
+                     VALUES (?1, ?2, 'messages', 0, 0, ?3, ?4)",
+                    params![
+                        header.hash().to_hex(),
+                        private_key.public_key().to_hex(),
+                        header.to_bytes(),
+                        body.to_bytes(),
+                    ],
+                )
+                .unwrap();
+        }
+
+        let store = SqliteStore::open(tmp_dir.path()).unwrap();
+        assert_eq!(store.schema_version().unwrap(), MIGRATIONS.len());
+        assert_eq!(store.subscriptions().unwrap(), vec!["calendar/123"]);
+
+        // Stream ids of existing operations are filled in.
+        let stream: Stream = header.extension().unwrap();
+        assert_eq!(store.operation_sizes(Some(&stream.id())).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn refuse_newer_database() {
+        let tmp_dir = tempdir().unwrap();
+
+        {
+            let connection = Connection::open(tmp_dir.path().join(DATABASE_FILE_NAME)).unwrap();
+            connection
+                .pragma_update(None, "user_version", (MIGRATIONS.len() + 1) as i64)
+                .unwrap();
+        }
+
+        let result = SqliteStore::open(tmp_dir.path());
+        assert!(matches!(
+            result,
+            Err(StoreError::UnsupportedSchemaVersion { .. })
+        ));
+    }
+
+    #[test]
+    fn subscriptions_survive_reopen() {
+        let tmp_dir = tempdir().unwrap();
+
+        {
+            let store = SqliteStore::open(tmp_dir.path()).unwrap();
+            store.insert_subscription("calendar/123").unwrap();
+            store.insert_subscription("calendar/123").unwrap();
+            store.insert_subscription("calendar_inbox/123").unwrap();
+        }
+
+        let store = SqliteStore::open(tmp_dir.path()).unwrap();
+        let mut subscriptions = store.subscriptions().unwrap();
+        subscriptions.sort();
+        assert_eq!(subscriptions, vec!["calendar/123", "calendar_inbox/123"]);
+    }
+}
@@ -0,0 +1,174 @@
+//! Rotation of the identity key.
+//!
+//! A compromised key is replaced by publishing a key succession record. It names the previous and
+//! the next public key and is signed by both keys, so nobody can claim to be the successor of a
+//! key they don't control and nobody can attach a successor to a key without its consent. The
+//! record is published as an operation authored by the previous key in its identity log, with a
+//! JSON payload of type `key_succession`.
+//!
+//! Keys connected by succession records form a chain and all keys in a chain belong to the same
+//! identity. An identity is represented by the first key of its chain, which is also the owner of
+//! all streams created before the first rotation. Every key has at most one successor and one
+//! predecessor, the first record we learn about wins.
+
+use p2panda_core::{Body, Header, PrivateKey, PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::extensions::{is_identity_record, Extensions};
+
+/// Domain separation prefix of the signed succession message.
+const SUCCESSION_CONTEXT: &[u8] = b"toolkitty/key-succession/v1";
+
+/// Record stating that `next` replaces `previous`, signed by both keys.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename = "key_succession", rename_all = "camelCase")]
+pub struct KeySuccession {
+    pub previous: PublicKey,
+    pub next: PublicKey,
+    pub previous_signature: Signature,
+    pub next_signature: Signature,
+}
+
+impl KeySuccession {
+    pub fn new(previous: &PrivateKey, next: &PrivateKey) -> Self {
+        let message = Self::message(&previous.public_key(), &next.public_key());
+        Self {
+            previous: previous.public_key(),
+            next: next.public_key(),
+            previous_signature: previous.sign(&message),
+            next_signature: next.sign(&message),
+        }
+    }
+
+    fn message(previous: &PublicKey, next: &PublicKey) -> Vec<u8> {
+        [
+            SUCCESSION_CONTEXT,
+            previous.as_bytes().as_slice(),
+            next.as_bytes().as_slice(),
+        ]
+        .concat()
+    }
+
+    /// Check that both keys signed the record.
+    pub fn verify(&self) -> Result<(), SuccessionError> {
+        if self.previous == self.next {
+            return Err(SuccessionError::SameKey);
+        }
+
+        let message = Self::message(&self.previous, &self.next);
+        if !self.previous.verify(&message, &self.previous_signature)
+            || !self.next.verify(&message, &self.next_signature)
+        {
+            return Err(SuccessionError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+
+    /// Extract and verify the succession record of an operation. Returns `None` if the operation
+    /// does not contain one or was not published in an identity log.
+    pub fn from_operation(
+        header: &Header<Extensions>,
+        body: Option<&Body>,
+    ) -> Result<Option<Self>, SuccessionError> {
+        let Some(body) = body.filter(|_| is_identity_record(header)) else {
+            return Ok(None);
+        };
+        let Ok(succession) = serde_json::from_slice::<Self>(&body.to_bytes()) else {
+            return Ok(None);
+        };
+
+        if header.public_key != succession.previous {
+            return Err(SuccessionError::AuthorMismatch);
+        }
+        succession.verify()?;
+
+        Ok(Some(succession))
+    }
+
+    /// Encode the record as an operation payload.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        serde_json::to_vec(self).expect("encode key succession")
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum SuccessionError {
+    #[error("key succession is not signed by both keys")]
+    InvalidSignature,
+
+    #[error("key succession was not published by the previous key")]
+    AuthorMismatch,
+
+    #[error("key succession must name two different keys")]
+    SameKey,
+
+    #[error("key succession conflicts with an earlier one")]
+    Conflict,
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::{Body, Header, PrivateKey};
+
+    use crate::extensions::Extensions;
+    use crate::test_utils::{create_header, with_extensions};
+
+    use super::{KeySuccession, SuccessionError};
+
+    fn create_identity_record(private_key: &PrivateKey, body: &Body) -> Header<Extensions> {
+        create_header(
+            private_key,
+            body,
+            with_extensions(Extensions::identity(private_key.public_key())),
+        )
+    }
+
+    #[test]
+    fn verify_succession() {
+        let previous = PrivateKey::new();
+        let next = PrivateKey::new();
+        let succession = KeySuccession::new(&previous, &next);
+        assert!(succession.verify().is_ok());
+
+        let body = Body::new(&succession.to_bytes());
+        let header = create_identity_record(&previous, &body);
+        assert_eq!(
+            KeySuccession::from_operation(&header, Some(&body)).unwrap(),
+            Some(succession.clone())
+        );
+
+        // Only the previous key can publish the record.
+        let header = create_identity_record(&next, &body);
+        assert!(matches!(
+            KeySuccession::from_operation(&header, Some(&body)),
+            Err(SuccessionError::AuthorMismatch)
+        ));
+
+        // A successor needs to prove that it controls its key.
+        let forged = KeySuccession {
+            next: PrivateKey::new().public_key(),
+            ..succession
+        };
+        assert!(matches!(
+            forged.verify(),
+            Err(SuccessionError::InvalidSignature)
+        ));
+
+        // Records outside of the identity log are application data.
+        let header = create_header(&previous, &body, with_extensions(Extensions::default()));
+        assert_eq!(
+            KeySuccession::from_operation(&header, Some(&body)).unwrap(),
+            None
+        );
+
+        // Other payloads are not mistaken for successions.
+        let body = Body::new(br#"{"type":"event","name":"Assembly"}"#);
+        let header = create_identity_record(&previous, &body);
+        assert_eq!(
+            KeySuccession::from_operation(&header, Some(&body)).unwrap(),
+            None
+        );
+    }
+}
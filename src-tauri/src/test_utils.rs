@@ -0,0 +1,30 @@
+//! Fixtures shared by the tests of several modules.
+
+use p2panda_core::{Body, Header, PrivateKey};
+
+use crate::extensions::Extensions;
+
+/// Create a header for the given body and sign it. Fields which don't depend on key and body, like
+/// the sequence number or the extensions, are taken from `template`.
+pub fn create_header(
+    private_key: &PrivateKey,
+    body: &Body,
+    template: Header<Extensions>,
+) -> Header<Extensions> {
+    let mut header = Header {
+        public_key: private_key.public_key(),
+        payload_size: body.size(),
+        payload_hash: Some(body.hash()),
+        ..template
+    };
+    header.sign(private_key);
+    header
+}
+
+/// Template for a header with the given extensions.
+pub fn with_extensions(extensions: Extensions) -> Header<Extensions> {
+    Header {
+        extensions: Some(extensions),
+        ..Default::default()
+    }
+}
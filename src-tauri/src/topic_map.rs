@@ -0,0 +1,156 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use p2panda_core::PublicKey;
+use p2panda_node::extensions::LogId;
+use p2panda_node::topic::Topic;
+use p2panda_sync::log_sync::TopicLogMap;
+use tokio::sync::RwLock;
+
+use crate::store::{SqliteStore, StoreError};
+
+type Logs = HashMap<PublicKey, Vec<LogId>>;
+
+/// Sync protocol topic map which is written through to the persistent store.
+///
+/// Maps persisted topics to the logs of all authors which should be synced when subscribing to
+/// that topic. All entries are kept in memory for fast lookups during sync and reloaded from the
+/// store when the app starts.
+#[derive(Clone, Debug)]
+pub struct TopicMap {
+    inner: Arc<RwLock<HashMap<Topic, Logs>>>,
+    store: SqliteStore,
+}
+
+impl TopicMap {
+    /// Load all persisted topic logs from the store.
+    pub fn load(store: SqliteStore) -> Result<Self, StoreError> {
+        let mut inner: HashMap<Topic, Logs> = HashMap::new();
+        for (topic, public_key, log_id) in store.topic_logs()? {
+            let log_ids = inner
+                .entry(Topic::Persisted(topic))
+                .or_default()
+                .entry(public_key)
+                .or_default();
+            if !log_ids.contains(&log_id) {
+                log_ids.push(log_id);
+            }
+        }
+
+        Ok(Self {
+            inner: Arc::new(RwLock::new(inner)),
+            store,
+        })
+    }
+
+    /// Add the log of an author to a persisted topic.
+    pub async fn add_log(
+        &self,
+        topic: &str,
+        public_key: &PublicKey,
+        log_id: &LogId,
+    ) -> Result<(), StoreError> {
+        let mut inner = self.inner.write().await;
+        self.store.insert_topic_log(topic, public_key, log_id)?;
+
+        let log_ids = inner
+            .entry(Topic::Persisted(topic.to_string()))
+            .or_default()
+            .entry(*public_key)
+            .or_default();
+        if !log_ids.contains(log_id) {
+            log_ids.push(log_id.clone());
+        }
+
+        Ok(())
+    }
+
+    /// Remove the log of an author from a persisted topic.
+    pub async fn remove_log(
+        &self,
+        topic: &str,
+        public_key: &PublicKey,
+        log_id: &LogId,
+    ) -> Result<(), StoreError> {
+        let mut inner = self.inner.write().await;
+        self.store.delete_topic_log(topic, public_key, log_id)?;
+
+        let topic = Topic::Persisted(topic.to_string());
+        if let Some(logs) = inner.get_mut(&topic) {
+            if let Some(log_ids) = logs.get_mut(public_key) {
+                log_ids.retain(|id| id != log_id);
+                if log_ids.is_empty() {
+                    logs.remove(public_key);
+                }
+            }
+            if logs.is_empty() {
+                inner.remove(&topic);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl TopicLogMap<Topic, LogId> for TopicMap {
+    async fn get(&self, topic: &Topic) -> Option<Logs> {
+        self.inner.read().await.get(topic).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use p2panda_core::PrivateKey;
+    use p2panda_node::extensions::LogId;
+    use p2panda_node::topic::Topic;
+    use p2panda_sync::log_sync::TopicLogMap;
+    use tempfile::tempdir;
+
+    use crate::store::SqliteStore;
+
+    use super::TopicMap;
+
+    #[tokio::test]
+    async fn add_and_remove_logs_across_restarts() {
+        let tmp_dir = tempdir().unwrap();
+        let public_key_a = PrivateKey::new().public_key();
+        let public_key_b = PrivateKey::new().public_key();
+        let topic = "calendar/123";
+        let log_id = LogId("123/calendar".to_string());
+
+        {
+            let topic_map = TopicMap::load(SqliteStore::open(tmp_dir.path()).unwrap()).unwrap();
+            topic_map
+                .add_log(topic, &public_key_a, &log_id)
+                .await
+                .unwrap();
+            topic_map
+                .add_log(topic, &public_key_b, &log_id)
+                .await
+                .unwrap();
+            topic_map
+                .remove_log(topic, &public_key_b, &log_id)
+                .await
+                .unwrap();
+        }
+
+        let topic_map = TopicMap::load(SqliteStore::open(tmp_dir.path()).unwrap()).unwrap();
+        let logs = topic_map
+            .get(&Topic::Persisted(topic.to_string()))
+            .await
+            .unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs.get(&public_key_a), Some(&vec![log_id.clone()]));
+
+        topic_map
+            .remove_log(topic, &public_key_a, &log_id)
+            .await
+            .unwrap();
+        assert!(topic_map
+            .get(&Topic::Persisted(topic.to_string()))
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,214 @@
+//! Unlocking of a passphrase-encrypted private key when the app starts.
+//!
+//! The node can't be started before the private key is known. If it is stored encrypted the
+//! service waits for the frontend to send a passphrase via the `unlock` command. Wrong passphrases
+//! are reported back and the service keeps waiting until the key could be decrypted.
+
+use std::path::Path;
+use std::sync::Mutex;
+
+use serde::Serialize;
+use thiserror::Error;
+use tokio::sync::{mpsc, oneshot, watch};
+
+use crate::keystore::{KeyStore, KeyStoreBackend, KeyStoreError};
+
+type UnlockRequest = (String, oneshot::Sender<Result<(), UnlockError>>);
+
+/// Gate between the frontend and the service task while the private key is locked.
+pub struct Unlocker {
+    /// Channel to send passphrases to the waiting service, only present while the key is locked.
+    requests_tx: Mutex<Option<mpsc::Sender<UnlockRequest>>>,
+
+    /// Whether the private key is locked, `None` while the service did not open the key store
+    /// yet.
+    locked_tx: watch::Sender<Option<bool>>,
+
+    /// Set to `true` as soon as the service was started and all other commands are available.
+    ready_tx: watch::Sender<bool>,
+}
+
+impl Default for Unlocker {
+    fn default() -> Self {
+        Self {
+            requests_tx: Mutex::new(None),
+            locked_tx: watch::Sender::new(None),
+            ready_tx: watch::Sender::new(false),
+        }
+    }
+}
+
+impl Unlocker {
+    /// Returns `true` if the service waits for a passphrase. Waits until the service opened the
+    /// key store, before that it is not known yet if the key is locked.
+    pub async fn is_locked(&self) -> bool {
+        let locked = self
+            .locked_tx
+            .subscribe()
+            .wait_for(Option::is_some)
+            .await
+            .map(|locked| *locked);
+        matches!(locked, Ok(Some(true)))
+    }
+
+    /// Signal that the private key of the service is not encrypted and no passphrase is needed.
+    pub fn unlocked(&self) {
+        self.locked_tx.send_replace(Some(false));
+    }
+
+    /// Wait until the frontend sends the passphrase which decrypts the encrypted key file in the
+    /// app data directory. If there is no key file yet the first passphrase is accepted and will
+    /// be used to encrypt a new key.
+    pub async fn wait_for_passphrase(
+        &self,
+        app_data_dir: &Path,
+    ) -> anyhow::Result<Box<dyn KeyStore>> {
+        let (requests_tx, mut requests_rx) = mpsc::channel(1);
+        *self
+            .requests_tx
+            .lock()
+            .expect("acquire unlock requests lock") = Some(requests_tx);
+        self.locked_tx.send_replace(Some(true));
+
+        while let Some((passphrase, reply_tx)) = requests_rx.recv().await {
+            let result = KeyStoreBackend::EncryptedFile
+                .open(app_data_dir, Some(&passphrase))
+                .and_then(|key_store| match key_store.load() {
+                    Ok(_) | Err(KeyStoreError::NotFound) => Ok(key_store),
+                    Err(err) => Err(err),
+                });
+            match result {
+                Ok(key_store) => {
+                    self.requests_tx
+                        .lock()
+                        .expect("acquire unlock requests lock")
+                        .take();
+                    self.unlocked();
+                    let _ = reply_tx.send(Ok(()));
+                    return Ok(key_store);
+                }
+                Err(err) => {
+                    let _ = reply_tx.send(Err(UnlockError::InvalidPassphrase(err.to_string())));
+                }
+            }
+        }
+
+        Err(anyhow::anyhow!("unlock requests channel closed"))
+    }
+
+    /// Signal that the service was started.
+    pub fn ready(&self) {
+        self.ready_tx.send_replace(true);
+    }
+
+    /// Signal that the service is restarting, for example because another profile was chosen.
+    pub fn reset(&self) {
+        self.locked_tx.send_replace(None);
+        self.ready_tx.send_replace(false);
+    }
+
+    /// Unlock the private key with the given passphrase and wait until the service was started.
+    pub async fn unlock(&self, passphrase: String) -> Result<(), UnlockError> {
+        let requests_tx = self
+            .requests_tx
+            .lock()
+            .expect("acquire unlock requests lock")
+            .clone();
+
+        if let Some(requests_tx) = requests_tx {
+            let (reply_tx, reply_rx) = oneshot::channel();
+            requests_tx
+                .send((passphrase, reply_tx))
+                .await
+                .map_err(|_| UnlockError::Closed)?;
+            reply_rx.await.map_err(|_| UnlockError::Closed)??;
+        }
+
+        self.ready_tx
+            .subscribe()
+            .wait_for(|ready| *ready)
+            .await
+            .map_err(|_| UnlockError::Closed)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum UnlockError {
+    #[error("unlocking private key failed: {0}")]
+    InvalidPassphrase(String),
+
+    #[error("service stopped before the private key was unlocked")]
+    Closed,
+}
+
+impl Serialize for UnlockError {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::ser::Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use p2panda_core::PrivateKey;
+    use tempfile::tempdir;
+
+    use crate::keystore::{EncryptedFileKeyStore, KeyStore};
+    use crate::migrations::ENCRYPTED_PRIVATE_KEY_FILE_NAME;
+
+    use super::{UnlockError, Unlocker};
+
+    #[tokio::test]
+    async fn unlock_with_passphrase() {
+        let tmp_dir = tempdir().unwrap();
+        let app_data_dir = tmp_dir.path().to_path_buf();
+        let private_key = PrivateKey::new();
+        EncryptedFileKeyStore::new(
+            app_data_dir.join(ENCRYPTED_PRIVATE_KEY_FILE_NAME),
+            "organize!",
+        )
+        .save(&private_key)
+        .unwrap();
+
+        let unlocker = Arc::new(Unlocker::default());
+        let service = tokio::spawn({
+            let unlocker = unlocker.clone();
+            async move {
+                let key_store = unlocker.wait_for_passphrase(&app_data_dir).await.unwrap();
+                unlocker.ready();
+                key_store.load().unwrap()
+            }
+        });
+
+        // The lock state is only reported once the service opened the key store.
+        assert!(unlocker.is_locked().await);
+
+        assert!(matches!(
+            unlocker.unlock("wrong".to_string()).await,
+            Err(UnlockError::InvalidPassphrase(_))
+        ));
+        assert!(unlocker.is_locked().await);
+
+        unlocker.unlock("organize!".to_string()).await.unwrap();
+        assert!(!unlocker.is_locked().await);
+        assert_eq!(service.await.unwrap().as_bytes(), private_key.as_bytes());
+    }
+
+    #[tokio::test]
+    async fn report_unencrypted_key() {
+        let unlocker = Arc::new(Unlocker::default());
+        let locked = tokio::spawn({
+            let unlocker = unlocker.clone();
+            async move { unlocker.is_locked().await }
+        });
+
+        unlocker.unlocked();
+        assert!(!locked.await.unwrap());
+    }
+}
@@ -77,7 +77,11 @@ type Image = string;
  *
  * Read more here: https://v2.tauri.app/develop/calling-frontend/#channels
  */
-type ChannelMessage = StreamMessage | EphemeralMessage | SystemMessage;
+type ChannelMessage =
+  | StreamMessage
+  | EphemeralMessage
+  | InvalidEphemeralMessage
+  | SystemMessage;
 
 /**
  * ଘ(˵╹-╹)━☆•.,¸.•*
@@ -244,6 +248,15 @@ type EphemeralMessage = {
   data: ResolveInviteCodeRequest | ResolveInviteCodeResponse;
 };
 
+/**
+ * We've received an ephemeral message from the network which could not be
+ * decoded, the data is the reason.
+ */
+type InvalidEphemeralMessage = {
+  event: "invalid_ephemeral";
+  data: string;
+};
+
 /**
  * Message requesting to resolve an invite code to all calendar data the peer
  * needs to join.